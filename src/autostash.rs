@@ -0,0 +1,62 @@
+use git2::{DiffOptions, Repository};
+use anyhow::{Result, anyhow};
+use std::path::PathBuf;
+use std::process::Command;
+use crate::{output, sparse};
+
+// --autostash (or rebase.autoStash): stash local changes in each repo before the run and put them
+// back once it's done, whichever way it ends
+
+// Whether a repo has changes of its own, not counting submodules that moved or have changes inside
+pub fn has_changes(repo: &Repository) -> Result<bool> {
+    let mut opts = DiffOptions::new();
+    opts.ignore_submodules(true);
+    Ok(sparse::changed_files(repo, Some(&mut opts))? != 0)
+}
+
+// Stashes to pop when this goes out of scope, innermost repo first
+pub struct AutoStash {
+    stashed: Vec<(String, PathBuf)>,
+}
+
+impl AutoStash {
+    pub fn new() -> AutoStash {
+        AutoStash { stashed: vec![] }
+    }
+
+    pub fn stash(&mut self, repo: &Repository, named_path: &str, run_id: &str) -> Result<()> {
+        let workdir = repo.workdir().expect("Has workdir").to_path_buf();
+        let status = Command::new("git")
+            .arg("stash")
+            .arg("push")
+            .arg("--message")
+            .arg(format!("sub-rebase autostash (run {})", run_id))
+            .current_dir(&workdir)
+            .stdout(output::child_stdout())
+            .status()?;
+        if !status.success() {
+            return Err(anyhow!("[{}] Autostash failed: {:?}", named_path, status.code()));
+        }
+        say!("[{}] Stashed local changes", named_path);
+        self.stashed.push((named_path.to_string(), workdir));
+        Ok(())
+    }
+}
+
+impl Drop for AutoStash {
+    fn drop(&mut self) {
+        for (named_path, workdir) in self.stashed.drain(..) {
+            let status = Command::new("git")
+                .arg("stash")
+                .arg("pop")
+                .current_dir(&workdir)
+                .stdout(output::child_stdout())
+                .status();
+            match status {
+                Ok(status) if status.success() => say!("[{}] Applied autostash", named_path),
+                // git leaves the stash alone if it doesn't apply cleanly
+                _ => warn!("[{}] Applying autostash failed, your changes are still in git stash list", named_path),
+            }
+        }
+    }
+}
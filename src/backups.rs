@@ -0,0 +1,339 @@
+use git2::{Repository, Oid, BranchType};
+use anyhow::{Result, anyhow};
+use structopt::StructOpt;
+use std::collections::{BTreeMap, HashMap};
+use std::process::Command;
+use crate::{Config, Failure, SubWalk, recurse_subs_in, sub_path_to_string, detach_head, reset_hard, output, prompter, sparse};
+use crate::resolver::GitlinkResolver;
+
+#[derive(StructOpt)]
+pub enum BackupsCommand {
+    /// List the backup branches of every run, across the whole submodule tree
+    List,
+    /// Restore every repo in the submodule tree to its backups from a run
+    Restore {
+        #[structopt(name="run-id")]
+        run_id: String,
+    },
+    /// Delete the backup branches of some runs, or of all but the newest few
+    Prune {
+        /// Keep this many of the newest runs and prune the rest
+        #[structopt(long)]
+        keep: Option<usize>,
+        #[structopt(name="run-id")]
+        run_ids: Vec<String>,
+    },
+}
+
+// A backup branch in one repo of the tree
+struct Backup {
+    path: Vec<String>,
+    branch: String,
+    run_id: String,
+    // Full ref name of what was backed up, or HEAD if it was detached
+    protects: String,
+    commit: Oid,
+    // When the backup was made, going by its reflog, or else when its commit was made
+    created: i64,
+}
+
+// Name of the backup branch protecting `protects` for this run, from --backup-name
+fn backup_branch_name(config: &Config, protects: &str) -> String {
+    let short_name = protects.rsplit('/').next().expect("Split should have results");
+    config.backup_name.replace("{branch}", short_name).replace("{run}", &config.run_id)
+}
+
+// Make a backup branch because aaa my data. None with --no-backup
+pub fn make_backup(config: &Config, repo: &Repository, protects: &str, commit: Oid) -> Result<Option<String>> {
+    if config.no_backup {
+        return Ok(None);
+    }
+    // Never overwrite an older backup, count up instead
+    let mut branch_name = backup_branch_name(config, protects);
+    let mut counter = 1;
+    while repo.find_branch(&branch_name, BranchType::Local).is_ok() {
+        counter += 1;
+        branch_name = format!("{}-{}", backup_branch_name(config, protects), counter);
+    }
+    repo.branch(&branch_name, &repo.find_commit(commit)?, false)?;
+    repo.config()?.set_str(&format!("branch.{}.subRebaseProtects", branch_name), protects)?;
+    repo.config()?.set_str(&format!("branch.{}.subRebaseRun", branch_name), &config.run_id)?;
+    Ok(Some(branch_name))
+}
+
+// Backups are recorded in the repo's config, or from before that, named backup/<branch>_<run id>
+pub fn is_backup(repo: &Repository, branch_name: &str) -> bool {
+    branch_name.starts_with("backup/") || repo.config().and_then(|c| c.get_string(&format!("branch.{}.subRebaseRun", branch_name))).is_ok()
+}
+
+// What a backup from before we recorded it protects, going by its name: the last component of the
+// branch name, or HEAD for detached ones, which were named backup/HEAD_<time>
+fn legacy_protects(name: &str, run_id: &str) -> Option<String> {
+    let short_name = name.strip_prefix("backup/")?.strip_suffix(run_id)?.strip_suffix('_')?;
+    Some(if short_name == "HEAD" { "HEAD".to_string() } else { format!("refs/heads/{}", short_name) })
+}
+
+fn find_backups(repo: &Repository, path: &[String]) -> Result<Vec<Backup>> {
+    let config = repo.config()?;
+    let mut backups = vec![];
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _type) = branch?;
+        let name = match branch.name()? {
+            Some(name) if is_backup(repo, name) => name.to_string(),
+            _ => continue,
+        };
+        let run_id = match (config.get_string(&format!("branch.{}.subRebaseRun", name)), name.rfind('_')) {
+            (Ok(run_id), _) => run_id,
+            (Err(_), Some(index)) => name[index + 1..].to_string(),
+            (Err(_), None) => continue,
+        };
+        let protects = match config.get_string(&format!("branch.{}.subRebaseProtects", name)) {
+            Ok(protects) => protects,
+            Err(_) => match legacy_protects(&name, &run_id) {
+                Some(protects) => protects,
+                None => continue,
+            },
+        };
+        let commit = branch.get().peel_to_commit()?;
+        let created = repo.reflog(&format!("refs/heads/{}", name)).ok()
+            .and_then(|reflog| reflog.iter().next_back().map(|entry| entry.committer().when().seconds()))
+            .unwrap_or(commit.time().seconds());
+        backups.push(Backup {
+            path: path.to_vec(),
+            branch: name,
+            run_id,
+            protects,
+            commit: commit.id(),
+            created,
+        });
+    }
+    Ok(backups)
+}
+
+// Collect backups from every repo in the tree, grouped by run
+fn backup_sets(repo: &Repository) -> Result<BTreeMap<String, Vec<Backup>>> {
+    let head = repo.head()?.peel_to_commit()?;
+    let backups = recurse_subs_in(SubWalk::ReadOnly, repo, &head, &GitlinkResolver, &|repo: &Repository, _submodule, _target, path, child_results: HashMap<String, Vec<Backup>>| -> Result<Vec<Backup>> {
+        let mut results = find_backups(repo, path)?;
+        for (_child, result) in child_results {
+            results.extend(result);
+        }
+        Ok(results)
+    })?;
+
+    let mut sets = BTreeMap::new();
+    for backup in backups {
+        sets.entry(backup.run_id.clone()).or_insert_with(Vec::new).push(backup);
+    }
+    for (_run_id, set) in sets.iter_mut() {
+        set.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+    Ok(sets)
+}
+
+// Describe how far the protected ref has moved on from the backup
+fn divergence(repo: &Repository, backup: &Backup) -> Result<String> {
+    let current = match repo.revparse_single(&backup.protects).and_then(|o| o.peel_to_commit()) {
+        Ok(commit) => commit.id(),
+        Err(_) => return Ok(format!("{} no longer exists", backup.protects)),
+    };
+    if current == backup.commit {
+        return Ok("unchanged".to_string());
+    }
+    let (ahead, behind) = repo.graph_ahead_behind(current, backup.commit)?;
+    Ok(format!("current is {} ahead, {} behind", ahead, behind))
+}
+
+fn open_at_path(repo: &Repository, path: &Vec<String>) -> Result<Repository> {
+    let mut current = Repository::open(repo.path())?;
+    for name in path {
+        let sub_repo = current.find_submodule(name)?.open()?;
+        current = sub_repo;
+    }
+    Ok(current)
+}
+
+fn list(repo: &Repository) -> Result<()> {
+    let sets = backup_sets(repo)?;
+    if sets.is_empty() {
+        say!("{}", tr!("No backups found"));
+        return Ok(());
+    }
+
+    for (run_id, set) in &sets {
+        say!("Run {}:", run_id);
+        let max_sub_len = set.iter().map(|b| sub_path_to_string(&b.path).len()).max().unwrap_or(0);
+        for backup in set {
+            let sub_repo = open_at_path(repo, &backup.path)?;
+            say!("  {}:{} {} protects {} ({})", sub_path_to_string(&backup.path), output::pad(sub_path_to_string(&backup.path).len(), max_sub_len), backup.branch, backup.protects, divergence(&sub_repo, backup)?);
+        }
+    }
+    Ok(())
+}
+
+fn restore(repo: &Repository, run_id: &str) -> Result<()> {
+    let mut sets = backup_sets(repo)?;
+    let set = sets.remove(run_id).ok_or(anyhow!("No backups found for run {}", run_id))?;
+
+    say!("{}", tr!("Restoring run {}:", run_id));
+    for backup in &set {
+        let sub_repo = open_at_path(repo, &backup.path)?;
+        say!("[{}] {} {} {} ({})", sub_path_to_string(&backup.path), backup.protects, output::arrow(), backup.commit, divergence(&sub_repo, backup)?);
+        if sparse::changed_files(&sub_repo, None)? != 0 {
+            warn!("{}", tr!("[{}] Cannot restore with a dirty working copy! Please stash first.", sub_path_to_string(&backup.path)));
+            return Err(Failure::DirtyWorkingCopy.into());
+        }
+    }
+
+    say!("{}", tr!("Press ENTER to restore..."));
+    prompter::wait_for_resolution("restore")?;
+
+    for backup in &set {
+        let named_path = sub_path_to_string(&backup.path);
+        let sub_repo = open_at_path(repo, &backup.path)?;
+        let head = sub_repo.head()?;
+        let commit = sub_repo.find_object(backup.commit, None)?;
+        let message = format!("sub-rebase: restore backup {}", backup.branch);
+        if backup.protects == "HEAD" {
+            say!("[{}] Set HEAD to {}", named_path, backup.commit);
+            detach_head(&sub_repo, backup.commit, &message)?;
+            reset_hard(&sub_repo, &commit, &message)?;
+        } else {
+            say!("[{}] Set {} to {}", named_path, backup.protects, backup.commit);
+            sub_repo.reference(&backup.protects, backup.commit, true, &message)?;
+            if head.name() == Some(backup.protects.as_str()) {
+                say!("[{}] Reset HEAD (hard) to {}", named_path, backup.commit);
+                reset_hard(&sub_repo, &commit, &message)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Run id of the newest run with backups
+fn newest_run(sets: &BTreeMap<String, Vec<Backup>>) -> Option<String> {
+    sets.iter()
+        .max_by_key(|(_run_id, set)| set.iter().map(|b| b.created).max().unwrap_or(0))
+        .map(|(run_id, _set)| run_id.clone())
+}
+
+// Restore the newest run's backups
+pub fn undo(repo: &Repository) -> Result<()> {
+    let newest = newest_run(&backup_sets(repo)?).ok_or(anyhow!("No backups to undo a run with"))?;
+    restore(repo, &newest)
+}
+
+// git range-diff between each backup of a run (the newest by default) and what it protects now, to
+// review what changed in the commits before force-pushing them
+pub fn range_diff(repo: &Repository, run_id: Option<&str>) -> Result<()> {
+    let mut sets = backup_sets(repo)?;
+    let run_id = match run_id {
+        Some(run_id) => run_id.to_string(),
+        None => newest_run(&sets).ok_or(anyhow!("{}", tr!("No backups found")))?,
+    };
+    let set = sets.remove(&run_id).ok_or(anyhow!("No backups found for run {}", run_id))?;
+
+    for backup in &set {
+        let named_path = sub_path_to_string(&backup.path);
+        let sub_repo = open_at_path(repo, &backup.path)?;
+        let current = match sub_repo.revparse_single(&backup.protects).and_then(|o| o.peel_to_commit()) {
+            Ok(commit) => commit.id(),
+            Err(_) => {
+                say!("[{}] {} no longer exists", named_path, backup.protects);
+                continue;
+            }
+        };
+        if current == backup.commit {
+            say!("[{}] {} unchanged", named_path, backup.protects);
+            continue;
+        }
+        say!("[{}] {} {} {}", named_path, backup.commit, output::arrow(), current);
+        let status = Command::new("git")
+            .arg("range-diff")
+            .arg(format!("{}...{}", backup.commit, current))
+            .current_dir(sub_repo.workdir().expect("Has workdir"))
+            .stdout(output::child_stdout())
+            .status()?;
+        if !status.success() {
+            return Err(anyhow!("[{}] git range-diff failed: {:?}", named_path, status.code()));
+        }
+    }
+    Ok(())
+}
+
+// The ref a backup of this commit was protecting, so a crashed run's repo can be put back on its branch
+pub fn protected_ref(repo: &Repository, commit: Oid) -> Result<Option<String>> {
+    Ok(find_backups(repo, &[])?.into_iter()
+        .filter(|backup| backup.commit == commit && backup.protects != "HEAD")
+        .max_by_key(|backup| backup.created)
+        .map(|backup| backup.protects))
+}
+
+fn prune(repo: &Repository, keep: Option<usize>, run_ids: &[String]) -> Result<()> {
+    let sets = backup_sets(repo)?;
+    let mut pruned = vec![];
+    for run_id in run_ids {
+        match sets.get(run_id) {
+            Some(set) => pruned.push((run_id, set)),
+            None => return Err(anyhow!("No backups found for run {}", run_id)),
+        }
+    }
+    if let Some(keep) = keep {
+        let mut by_age = sets.iter().collect::<Vec<_>>();
+        by_age.sort_by_key(|(_run_id, set)| std::cmp::Reverse(set.iter().map(|b| b.created).max().unwrap_or(0)));
+        for (run_id, set) in by_age.into_iter().skip(keep) {
+            if !pruned.iter().any(|(pruned_id, _)| *pruned_id == run_id) {
+                pruned.push((run_id, set));
+            }
+        }
+    } else if run_ids.is_empty() {
+        return Err(anyhow!("Say which runs to prune, or how many to --keep"));
+    }
+    if pruned.is_empty() {
+        say!("{}", tr!("No backups found"));
+        return Ok(());
+    }
+
+    for (run_id, set) in &pruned {
+        say!("Run {}:", run_id);
+        for backup in set.iter() {
+            say!("  {}: {}", sub_path_to_string(&backup.path), backup.branch);
+        }
+    }
+    say!("{}", tr!("Press ENTER to prune..."));
+    prompter::wait_for_resolution("prune")?;
+
+    for (_run_id, set) in &pruned {
+        for backup in set.iter() {
+            let sub_repo = open_at_path(repo, &backup.path)?;
+            sub_repo.find_branch(&backup.branch, BranchType::Local)?.delete()?;
+            // Older backups don't have these
+            let _ = sub_repo.config()?.remove(&format!("branch.{}.subRebaseProtects", backup.branch));
+            let _ = sub_repo.config()?.remove(&format!("branch.{}.subRebaseRun", backup.branch));
+            say!("[{}] Deleted {}", sub_path_to_string(&backup.path), backup.branch);
+        }
+    }
+    Ok(())
+}
+
+pub fn run(repo: &Repository, command: &BackupsCommand) -> Result<()> {
+    match command {
+        BackupsCommand::List => list(repo),
+        BackupsCommand::Restore { run_id } => restore(repo, run_id),
+        BackupsCommand::Prune { keep, run_ids } => prune(repo, *keep, run_ids),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_backup_names() {
+        assert_eq!(legacy_protects("backup/main_1650000000", "1650000000").as_deref(), Some("refs/heads/main"));
+        assert_eq!(legacy_protects("backup/feature_x_1650000000", "1650000000").as_deref(), Some("refs/heads/feature_x"));
+        assert_eq!(legacy_protects("backup/HEAD_1650000000", "1650000000").as_deref(), Some("HEAD"));
+        assert_eq!(legacy_protects("backup/main_1650000000", "1700000000"), None);
+        assert_eq!(legacy_protects("main_1650000000", "1650000000"), None);
+    }
+}
@@ -0,0 +1,72 @@
+use git2::Repository;
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use crate::watchdog;
+
+// A bare repo (e.g. a CI mirror) has no working copy to rebase in, so runs in one happen in a
+// temporary worktree of the branch HEAD names, with its submodules cloned. Worktrees share refs with
+// the repo they belong to, so rebasing the branch there updates it in the bare repo too
+
+pub struct BareWorktree {
+    bare: PathBuf,
+    path: PathBuf,
+    // --keep-worktree, for getting at the submodules' rewritten commits, which only the worktree's
+    // clones of them have
+    keep: bool,
+}
+
+fn git(named_path: &str, dir: &Path, operation: &str, args: &[&str]) -> Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.args(args).current_dir(dir);
+    match watchdog::run_command(named_path, operation, &mut cmd)? {
+        Some(output) if output.status.success() => Ok(()),
+        Some(output) => Err(anyhow!("{} failed: {}", operation, String::from_utf8_lossy(&output.stderr).trim())),
+        None => Err(anyhow!("{} was skipped, can't run without it", operation)),
+    }
+}
+
+impl BareWorktree {
+    pub fn create(bare: &Repository, run_id: &str, keep: bool) -> Result<BareWorktree> {
+        let head = bare.find_reference("HEAD")?;
+        let branch = head.symbolic_target()
+            .and_then(|target| target.strip_prefix("refs/heads/"))
+            .ok_or_else(|| anyhow!("HEAD of the bare repo doesn't name a branch to rebase"))?
+            .to_string();
+        if bare.find_branch(&branch, git2::BranchType::Local).is_err() {
+            return Err(anyhow!("Branch {} named by HEAD of the bare repo doesn't exist", branch));
+        }
+
+        let path = std::env::temp_dir().join(format!("git-sub-rebase-{}-{}", std::process::id(), run_id));
+        let path_str = path.to_string_lossy().to_string();
+        say!("Bare repo, rebasing {} in a temporary worktree at {}", branch, path.display());
+        git("*root*", bare.path(), "git worktree add", &["worktree", "add", &path_str, &branch])?;
+        // From here on it gets removed again, even if the submodules don't clone
+        let worktree = BareWorktree { bare: bare.path().to_path_buf(), path, keep };
+        let repo = worktree.open()?;
+        for mut submodule in repo.submodules()? {
+            let name = submodule.name().expect("Submodule should have name").to_string();
+            crate::update_submodule(&repo, &mut submodule, &name)?;
+        }
+        Ok(worktree)
+    }
+
+    pub fn open(&self) -> Result<Repository> {
+        Ok(Repository::open(&self.path)?)
+    }
+}
+
+impl Drop for BareWorktree {
+    fn drop(&mut self) {
+        if self.keep {
+            say!("Kept the temporary worktree at {}, remove it with `git worktree remove --force` when done", self.path.display());
+            return;
+        }
+        let path = self.path.to_string_lossy().to_string();
+        // Forced, since it has submodules
+        match git("*root*", &self.bare, "git worktree remove", &["worktree", "remove", "--force", "--force", &path]) {
+            Ok(()) => say!("Removed the temporary worktree at {}", self.path.display()),
+            Err(e) => warn!("Removing the temporary worktree at {} failed, `git worktree prune` once it's gone: {}", self.path.display(), e),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use git2::{Repository, Oid, Submodule};
+use anyhow::Result;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Merge bases and commit summaries get looked up over and over for the same commits (checking,
+// planning, selecting, linting and then rebasing all start from the merge base). Keep them for the
+// run, keyed by repo and commit. Nothing here holds git2 objects, so it's fine to share across threads
+
+// Past this many entries the cache starts over, so huge trees don't grow it forever
+const MAX_ENTRIES: usize = 100_000;
+
+#[derive(Default)]
+struct Cache {
+    merge_bases: HashMap<(PathBuf, Oid, Oid), Oid>,
+    summaries: HashMap<(PathBuf, Oid), String>,
+}
+
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+// Same for open_submodule, kept apart so they don't skew the lookups' hit rate
+static REPO_HITS: AtomicU64 = AtomicU64::new(0);
+static REPO_MISSES: AtomicU64 = AtomicU64::new(0);
+
+fn with_cache<T>(f: impl FnOnce(&mut Cache) -> T) -> T {
+    let mut cache = CACHE.lock().expect("Cache lock poisoned");
+    let cache = cache.get_or_insert_with(Cache::default);
+    if cache.merge_bases.len() + cache.summaries.len() > MAX_ENTRIES {
+        *cache = Cache::default();
+    }
+    f(cache)
+}
+
+fn lookup<T: Clone>(cached: Option<T>) -> Option<T> {
+    count(cached, &HITS, &MISSES)
+}
+
+fn count<T>(cached: Option<T>, hits: &AtomicU64, misses: &AtomicU64) -> Option<T> {
+    match cached {
+        Some(value) => {
+            hits.fetch_add(1, Ordering::Relaxed);
+            Some(value)
+        }
+        None => {
+            misses.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+pub fn merge_base(repo: &Repository, a: Oid, b: Oid) -> Result<Oid> {
+    let key = (repo.path().to_path_buf(), a, b);
+    if let Some(base) = lookup(with_cache(|cache| cache.merge_bases.get(&key).copied())) {
+        return Ok(base);
+    }
+    let base = repo.merge_base(a, b)?;
+    with_cache(|cache| cache.merge_bases.insert(key, base));
+    Ok(base)
+}
+
+pub fn summary(repo: &Repository, id: Oid) -> Result<String> {
+    let key = (repo.path().to_path_buf(), id);
+    if let Some(summary) = lookup(with_cache(|cache| cache.summaries.get(&key).cloned())) {
+        return Ok(summary);
+    }
+    let summary = repo.find_commit(id)?.summary().unwrap_or("").to_string();
+    with_cache(|cache| cache.summaries.insert(key, summary.clone()));
+    Ok(summary)
+}
+
+// Hits and misses so far
+pub fn stats() -> (u64, u64) {
+    (HITS.load(Ordering::Relaxed), MISSES.load(Ordering::Relaxed))
+}
+
+// Submodule repos reused and opened so far
+pub fn repo_stats() -> (u64, u64) {
+    (REPO_HITS.load(Ordering::Relaxed), REPO_MISSES.load(Ordering::Relaxed))
+}
+
+// Opened submodule repos, keyed by working copy. Every pass over the tree (checks, updates, branch
+// names, the rebase, finishing up) used to open each one again, which reads its config and HEAD off
+// disk; slow on network filesystems. Handles can't be shared between threads, so each has its own
+thread_local! {
+    static REPOS: RefCell<HashMap<PathBuf, Rc<Repository>>> = RefCell::new(HashMap::new());
+}
+
+// submodule.open(), for the same submodule it's the same handle every time. Failures aren't kept,
+// so one that gets updated opens once it's there
+pub fn open_submodule(parent: &Repository, submodule: &Submodule) -> Result<Rc<Repository>, git2::Error> {
+    let key = match parent.workdir() {
+        Some(workdir) => workdir.join(submodule.path()),
+        None => return submodule.open().map(Rc::new),
+    };
+    if let Some(repo) = count(REPOS.with(|repos| repos.borrow().get(&key).cloned()), &REPO_HITS, &REPO_MISSES) {
+        return Ok(repo);
+    }
+    let repo = Rc::new(submodule.open()?);
+    REPOS.with(|repos| repos.borrow_mut().insert(key, repo.clone()));
+    Ok(repo)
+}
@@ -0,0 +1,83 @@
+use git2::{Commit, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use git2::build::CheckoutBuilder;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The hard resets at the end of a run check out every repo in the tree, which on a big superproject is
+// most of the run. libgit2 writes one file at a time and runs every file through the attribute
+// filters. So filters are left out in repos with nothing for them to do, and --checkout-workers
+// hands the resets to `git reset --hard` with git's parallel checkout instead
+
+// 0 for libgit2's checkout
+static WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_workers(workers: Option<usize>) {
+    let workers = match workers {
+        Some(0) => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        Some(workers) => workers,
+        None => 0,
+    };
+    WORKERS.store(workers, Ordering::SeqCst);
+}
+
+// Whether checking out `tree` in this repo could change what gets written: line endings, ident
+// expansion or filter drivers, from attributes anywhere or from core.autocrlf and core.eol
+fn needs_filters(repo: &Repository, tree: &Tree) -> bool {
+    let config = match repo.config() {
+        Ok(config) => config,
+        Err(_) => return true,
+    };
+    if config.get_string("core.autocrlf").is_ok_and(|value| !value.eq_ignore_ascii_case("false"))
+        || config.get_string("core.eol").is_ok()
+        || config.get_path("core.attributesFile").is_ok() {
+        return true;
+    }
+    let global_attributes = std::env::var_os("XDG_CONFIG_HOME").map(std::path::PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| std::path::PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("git").join("attributes"));
+    if global_attributes.is_some_and(|path| path.exists()) || repo.path().join("info").join("attributes").exists() {
+        return true;
+    }
+    // The .gitattributes that count are the ones being checked out, not what's in the index now
+    let mut found = false;
+    let walked = tree.walk(TreeWalkMode::PreOrder, |_dir, entry| {
+        if entry.name_bytes() == b".gitattributes" {
+            found = true;
+            return TreeWalkResult::Abort;
+        }
+        TreeWalkResult::Ok
+    });
+    found || walked.is_err()
+}
+
+// Options for a forced checkout of a whole commit's tree
+pub fn builder(repo: &Repository, tree: &Tree) -> CheckoutBuilder<'static> {
+    let mut builder = CheckoutBuilder::new();
+    builder.force();
+    if !needs_filters(repo, tree) {
+        builder.disable_filters(true);
+    }
+    builder
+}
+
+// `git reset --hard` with --checkout-workers set, None to use libgit2. It moves HEAD (or the branch it's
+// on), writes the index and knows about sparse checkouts, which is everything reset_hard does
+pub fn git_reset_hard(repo: &Repository, commit: &Commit, message: &str) -> Option<Result<(), git2::Error>> {
+    let workers = WORKERS.load(Ordering::SeqCst);
+    if workers == 0 {
+        return None;
+    }
+    let workdir = repo.workdir()?;
+    let output = Command::new("git")
+        .arg("-c").arg(format!("checkout.workers={}", workers))
+        .args(["reset", "--hard", "--quiet", "--no-recurse-submodules"])
+        .arg(commit.id().to_string())
+        .env("GIT_REFLOG_ACTION", message)
+        .current_dir(workdir)
+        .output();
+    Some(match output {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(git2::Error::from_str(&format!("git reset --hard failed: {}", String::from_utf8_lossy(&output.stderr).trim()))),
+        Err(e) => Err(git2::Error::from_str(&format!("git reset --hard failed: {}", e))),
+    })
+}
@@ -0,0 +1,40 @@
+use git2::Repository;
+use std::path::PathBuf;
+use std::process::Command;
+use crate::watchdog;
+
+// On histories of hundreds of thousands of commits, merge bases and revwalks spend their time parsing
+// commit objects. With a commit-graph file libgit2 reads parents, dates and generation numbers from it
+// instead, but git only writes one on gc. So repos without one get it written before the run starts
+// looking at their history. It's only ever a speedup, so failing to write one isn't an error
+
+// Where the repo keeps its objects, which for a worktree is in the repo it belongs to
+fn objects_dir(repo: &Repository) -> PathBuf {
+    let common = std::fs::read_to_string(repo.path().join("commondir"))
+        .map(|dir| repo.path().join(dir.trim()))
+        .unwrap_or_else(|_| repo.path().to_path_buf());
+    common.join("objects")
+}
+
+pub fn has_graph(repo: &Repository) -> bool {
+    let info = objects_dir(repo).join("info");
+    info.join("commit-graph").exists() || info.join("commit-graphs").join("commit-graph-chain").exists()
+}
+
+// Write a commit-graph for everything reachable, unless there is one or it's turned off. git doesn't
+// use them in shallow clones, and they'd go stale as soon as the history gets deepened
+pub fn ensure(named_path: &str, repo: &Repository) {
+    let enabled = repo.config().and_then(|config| config.get_bool("core.commitGraph")).unwrap_or(true);
+    if !enabled || repo.is_shallow() || has_graph(repo) {
+        return;
+    }
+    say!("[{}] Writing a commit-graph, so history lookups are faster", named_path);
+    let mut cmd = Command::new("git");
+    cmd.args(["commit-graph", "write", "--reachable"]).current_dir(repo.path());
+    match watchdog::run_command(named_path, "git commit-graph write", &mut cmd) {
+        Ok(Some(output)) if output.status.success() => {}
+        Ok(Some(output)) => warn!("[{}] Writing a commit-graph failed, carrying on without: {}", named_path, String::from_utf8_lossy(&output.stderr).trim()),
+        Ok(None) => {}
+        Err(e) => warn!("[{}] Writing a commit-graph failed, carrying on without: {}", named_path, e),
+    }
+}
@@ -0,0 +1,247 @@
+use git2::{Repository, Oid, Signature, ObjectType, BranchType};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use crate::backups;
+
+// Old commit -> rewritten commit for every repo in the tree, keyed by submodule path (empty for the
+// superproject)
+pub type CommitMaps = BTreeMap<Vec<String>, HashMap<Oid, Oid>>;
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// {"<path>": {"<old>": "<new>", ...}, ...} with everything sorted so runs can be diffed
+pub fn to_json(maps: &CommitMaps) -> String {
+    let mut json = String::from("{\n");
+    for (i, (path, map)) in maps.iter().enumerate() {
+        json.push_str(&format!("  {}: {{", json_string(&path.join("/"))));
+        let mut entries = map.iter().map(|(old, new)| (old.to_string(), new.to_string())).collect::<Vec<_>>();
+        entries.sort();
+        for (j, (old, new)) in entries.iter().enumerate() {
+            json.push_str(&format!("{}\n    \"{}\": \"{}\"", if j == 0 { "" } else { "," }, old, new));
+        }
+        json.push_str(if entries.is_empty() { "}" } else { "\n  }" });
+        json.push_str(if i + 1 == maps.len() { "\n" } else { ",\n" });
+    }
+    json.push_str("}\n");
+    json
+}
+
+// Write the maps to a file, or stdout for -
+pub fn write_json(maps: &CommitMaps, out: &Path) -> Result<()> {
+    let json = to_json(maps);
+    if out == Path::new("-") {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        stdout.write_all(json.as_bytes())?;
+        stdout.flush()?;
+    } else {
+        std::fs::write(out, json)?;
+    }
+    Ok(())
+}
+
+pub const NOTES_REF: &str = "refs/notes/sub-rebase";
+
+// Entries that are real rewrites, oldest first. Commits that are still in their rewrite's history (like
+// the merge base, which maps to the target) weren't rewritten
+pub fn rewrites(repo: &Repository, map: &HashMap<Oid, Oid>) -> Result<Vec<(Oid, Oid)>> {
+    let mut rewrites = vec![];
+    for (old, new) in map {
+        if old != new && !repo.graph_descendant_of(*new, *old)? {
+            rewrites.push((*old, *new));
+        }
+    }
+    rewrites.sort_by_key(|(old, _)| repo.find_commit(*old).map(|c| c.time().seconds()).unwrap_or(0));
+    Ok(rewrites)
+}
+
+// Leave a note on each rewritten commit saying what it became, so `git notes --ref sub-rebase show <old>`
+// answers it later
+pub fn write_notes(repo: &Repository, map: &HashMap<Oid, Oid>, signature: &Signature, run_id: &str) -> Result<usize> {
+    let rewrites = rewrites(repo, map)?;
+    for (old, new) in &rewrites {
+        repo.note(signature, signature, Some(NOTES_REF), *old, &format!("rewritten-to {}\nrun {}\n", new, run_id), true)?;
+    }
+    Ok(rewrites.len())
+}
+
+// Make refs/replace/<old> point at each rewritten commit, so anything still holding an old id sees the
+// new one (unless it runs with --no-replace-objects)
+pub fn write_replace_refs(repo: &Repository, map: &HashMap<Oid, Oid>, run_id: &str) -> Result<usize> {
+    let rewrites = rewrites(repo, map)?;
+    for (old, new) in &rewrites {
+        repo.reference(&format!("refs/replace/{}", old), *new, true, &format!("sub-rebase: replace rewritten commit (run {})", run_id))?;
+    }
+    Ok(rewrites.len())
+}
+
+// Notes refs to carry over to rewritten commits, like git rebase: GIT_NOTES_REWRITE_REF or else
+// notes.rewriteRef, as globs. Nothing unless one of those is set, or if notes.rewrite.rebase is off
+fn notes_rewrite_refs(repo: &Repository) -> Result<Vec<String>> {
+    let config = repo.config()?;
+    if !config.get_bool("notes.rewrite.rebase").unwrap_or(true) {
+        return Ok(vec![]);
+    }
+    let patterns = match std::env::var("GIT_NOTES_REWRITE_REF") {
+        Ok(patterns) => patterns.split(':').filter(|p| !p.is_empty()).map(|p| p.to_string()).collect(),
+        Err(_) => {
+            let mut patterns = vec![];
+            if let Ok(entries) = config.multivar("notes.rewriteRef", None) {
+                for entry in &entries {
+                    if let Some(value) = entry?.value() {
+                        patterns.push(value.to_string());
+                    }
+                }
+            }
+            patterns
+        }
+    };
+
+    let mut refs = vec![];
+    for pattern in patterns {
+        for reference in repo.references_glob(&pattern)? {
+            if let Some(name) = reference?.name() {
+                // Our own notes say what the old commit became, they don't belong on the new one
+                if name != NOTES_REF && !refs.iter().any(|r| r == name) {
+                    refs.push(name.to_string());
+                }
+            }
+        }
+    }
+    Ok(refs)
+}
+
+// Copy notes from old commits to their rewrites, honoring notes.rewriteMode
+pub fn copy_notes(repo: &Repository, map: &HashMap<Oid, Oid>, signature: &Signature) -> Result<usize> {
+    let notes_refs = notes_rewrite_refs(repo)?;
+    if notes_refs.is_empty() {
+        return Ok(0);
+    }
+    let mode = repo.config()?.get_string("notes.rewriteMode").unwrap_or_else(|_| "concatenate".to_string());
+
+    let mut copied = 0;
+    for (old, new) in rewrites(repo, map)? {
+        for notes_ref in &notes_refs {
+            let old_note = match repo.find_note(Some(notes_ref), old) {
+                Ok(note) => note.message().unwrap_or("").to_string(),
+                Err(_) => continue,
+            };
+            let message = match (repo.find_note(Some(notes_ref), new), mode.as_str()) {
+                (Err(_), _) | (Ok(_), "overwrite") => old_note,
+                (Ok(_), "ignore") => continue,
+                (Ok(existing), _) => format!("{}\n\n{}", existing.message().unwrap_or("").trim_end(), old_note),
+            };
+            repo.note(signature, signature, Some(notes_ref), new, &message, true)?;
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+// What a commit was rewritten to, according to its note
+pub fn read_note(repo: &Repository, old: Oid) -> Option<Oid> {
+    let note = repo.find_note(Some(NOTES_REF), old).ok()?;
+    let line = note.message()?.lines().find(|line| line.starts_with("rewritten-to "))?;
+    Oid::from_str(line["rewritten-to ".len()..].trim()).ok()
+}
+
+// Move other local branches (or just these ones) that point at rewritten commits along with them,
+// like git rebase --update-refs. Leaves alone the checked out branch, our own state branches and backups
+pub fn update_branches(repo: &Repository, map: &HashMap<Oid, Oid>, only: Option<&[String]>, run_id: &str) -> Result<Vec<(String, Oid, Oid)>> {
+    let rewrites = rewrites(repo, map)?.into_iter().collect::<HashMap<_, _>>();
+    let mut moved = vec![];
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _type) = branch?;
+        let name = match branch.name()? {
+            Some(name) if !backups::is_backup(repo, name) && !name.starts_with("multi_rebase_") => name.to_string(),
+            _ => continue,
+        };
+        if branch.is_head() || only.is_some_and(|only| !only.contains(&name)) {
+            continue;
+        }
+        let old = branch.get().peel_to_commit()?.id();
+        if let Some(new) = rewrites.get(&old) {
+            repo.reference(&format!("refs/heads/{}", name), *new, true, &format!("sub-rebase: update ref (run {})", run_id))?;
+            moved.push((name, old, *new));
+        }
+    }
+    moved.sort();
+    Ok(moved)
+}
+
+// Tags pointing at rewritten commits, as (name, old, new)
+pub fn tags_to_move(repo: &Repository, map: &HashMap<Oid, Oid>) -> Result<Vec<(String, Oid, Oid)>> {
+    let rewrites = rewrites(repo, map)?.into_iter().collect::<HashMap<_, _>>();
+    let mut tags = vec![];
+    for name in repo.tag_names(None)?.iter().flatten() {
+        let commit = match repo.revparse_single(&format!("refs/tags/{}", name)).and_then(|o| o.peel_to_commit()) {
+            Ok(commit) => commit.id(),
+            Err(_) => continue,
+        };
+        if let Some(new) = rewrites.get(&commit) {
+            tags.push((name.to_string(), commit, *new));
+        }
+    }
+    tags.sort();
+    Ok(tags)
+}
+
+// Point a tag at a rewritten commit. Annotated tags are recreated with the same tagger and message,
+// and signed ones get signed again with the user's key, since the old signature covers the old commit
+pub fn move_tag(repo: &Repository, name: &str, new: Oid) -> Result<()> {
+    let reference = repo.find_reference(&format!("refs/tags/{}", name))?;
+    let tag = match reference.peel(ObjectType::Tag) {
+        Ok(tag) => tag.into_tag().expect("Peeled to a tag"),
+        Err(_) => {
+            repo.reference(&format!("refs/tags/{}", name), new, true, "sub-rebase: move tag to rewritten commit")?;
+            return Ok(());
+        }
+    };
+
+    let message = tag.message().unwrap_or("");
+    match message.find("-----BEGIN PGP SIGNATURE-----").or(message.find("-----BEGIN SSH SIGNATURE-----")) {
+        Some(signature_start) => {
+            let status = Command::new("git")
+                .arg("tag")
+                .arg("--force")
+                .arg("--sign")
+                .arg("--cleanup=verbatim")
+                .arg("--message")
+                .arg(&message[..signature_start])
+                .arg(name)
+                .arg(new.to_string())
+                .current_dir(repo.workdir().unwrap_or(repo.path()))
+                .stdout(crate::output::child_stdout())
+                .status()?;
+            if !status.success() {
+                return Err(anyhow::anyhow!("Re-signing tag {} failed: {:?}", name, status.code()));
+            }
+        }
+        None => {
+            let tagger = match tag.tagger() {
+                Some(tagger) => tagger.to_owned(),
+                None => repo.signature()?,
+            };
+            repo.tag(name, &repo.find_object(new, None)?, &tagger, message, true)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use git2::Repository;
+use anyhow::{Result, anyhow};
+use structopt::StructOpt;
+use structopt::clap::Shell;
+use crate::Config;
+use crate::settings::{SETTINGS, Kind};
+
+// Completion scripts are clap's, with targets and submodule paths filled in by calling back into
+// `completions --list`, since those depend on the repo being completed in
+
+const BIN_NAME: &str = "git-sub-rebase";
+const SUBCOMMANDS: &[&str] = &["backups", "config", "conflicts", "undo", "range-diff", "help", "completions"];
+
+#[derive(StructOpt)]
+pub struct CompletionsCommand {
+    /// Shell to write a completion script for: bash, zsh or fish
+    #[structopt(required_unless="list")]
+    shell: Option<String>,
+    /// What the scripts complete from the repo they're in: refs or submodules
+    #[structopt(long, hidden=true)]
+    list: Option<String>,
+}
+
+// Every ref name a target could be given as, shortest form
+fn list_refs(repo: &Repository) -> Result<()> {
+    let mut names = vec![];
+    for reference in repo.references()? {
+        let reference = reference?;
+        if let Some(name) = reference.shorthand() {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    names.dedup();
+    for name in names {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+// Submodule paths from the superproject, the way --exclude-submodule takes them
+fn list_submodules(repo: &Repository, prefix: &str) -> Result<()> {
+    for submodule in repo.submodules()? {
+        let path = format!("{}{}", prefix, submodule.path().to_string_lossy());
+        println!("{}", path);
+        if let Ok(sub_repo) = submodule.open() {
+            list_submodules(&sub_repo, &format!("{}/", path))?;
+        }
+    }
+    Ok(())
+}
+
+// Options whose next word is their value, not a target
+fn value_options(separator: &str) -> String {
+    SETTINGS.iter()
+        .filter(|setting| setting.kind == Kind::Value || setting.kind == Kind::Multi)
+        .map(|setting| format!("--{}", setting.long))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn bash() -> String {
+    format!(r#"
+_git_sub_rebase_dynamic() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}" word
+    case "${{prev}}" in
+        --exclude-submodule)
+            COMPREPLY=( $(compgen -W "$({bin} completions --list submodules 2>/dev/null)" -- "${{cur}}") )
+            return 0 ;;
+        {value_options})
+            _{bin} "$@"
+            return ;;
+    esac
+    if [[ "${{cur}}" != -* ]]; then
+        for word in "${{COMP_WORDS[@]:1:COMP_CWORD-1}}"; do
+            case "${{word}}" in
+                {subcommands})
+                    _{bin} "$@"
+                    return ;;
+            esac
+        done
+        COMPREPLY=( $(compgen -W "$({bin} completions --list refs 2>/dev/null) {subcommand_words}" -- "${{cur}}") )
+        return 0
+    fi
+    _{bin} "$@"
+}}
+complete -F _git_sub_rebase_dynamic -o bashdefault -o default {bin}
+
+# For `git sub-rebase`, with git's own completion loaded
+_git_sub_rebase() {{
+    case "${{prev}}" in
+        --exclude-submodule)
+            __gitcomp_nl "$({bin} completions --list submodules 2>/dev/null)"
+            return ;;
+    esac
+    case "${{cur}}" in
+        -*) __gitcomp "{options}" ;;
+        *) __git_complete_refs ;;
+    esac
+}}
+"#, bin = BIN_NAME, value_options = value_options("|"), subcommands = SUBCOMMANDS.join("|"), subcommand_words = SUBCOMMANDS.join(" "),
+        options = SETTINGS.iter().map(|setting| format!("--{}", setting.long)).collect::<Vec<_>>().join(" "))
+}
+
+fn zsh() -> String {
+    format!(r#"
+_git_sub_rebase_dynamic() {{
+    local prev=${{words[CURRENT-1]}} word
+    local -a found subcommands
+    subcommands=({subcommands})
+    if [[ $prev == --exclude-submodule ]]; then
+        found=(${{(f)"$({bin} completions --list submodules 2>/dev/null)"}})
+        compadd -a found
+        return
+    fi
+    if [[ $PREFIX != -* && " {value_options} " != *" $prev "* ]]; then
+        for word in ${{words[2,CURRENT-1]}}; do
+            if (( ${{subcommands[(Ie)$word]}} )); then
+                _{bin} "$@"
+                return
+            fi
+        done
+        found=(${{(f)"$({bin} completions --list refs 2>/dev/null)"}})
+        compadd -a found
+    fi
+    _{bin} "$@"
+}}
+_git_sub_rebase_dynamic "$@"
+"#, bin = BIN_NAME, value_options = value_options(" "), subcommands = SUBCOMMANDS.join(" "))
+}
+
+fn fish() -> String {
+    format!(r#"
+complete -c {bin} -n "not __fish_seen_subcommand_from {subcommands}" -f -a "({bin} completions --list refs 2>/dev/null)"
+complete -c {bin} -l exclude-submodule -r -f -a "({bin} completions --list submodules 2>/dev/null)"
+"#, bin = BIN_NAME, subcommands = SUBCOMMANDS.join(" "))
+}
+
+pub fn run(repo: Option<&Repository>, command: &CompletionsCommand) -> Result<()> {
+    if let Some(list) = &command.list {
+        // Called from inside a completion, so nothing to say outside a repo
+        let repo = match repo {
+            Some(repo) => repo,
+            None => return Ok(()),
+        };
+        return match list.as_str() {
+            "refs" => list_refs(repo),
+            "submodules" => list_submodules(repo, ""),
+            _ => Err(anyhow!("Unknown list {}, expected refs or submodules", list)),
+        };
+    }
+
+    let name = command.shell.as_deref().expect("Required unless --list");
+    let (shell, dynamic) = match name {
+        "bash" => (Shell::Bash, bash()),
+        "zsh" => (Shell::Zsh, zsh()),
+        "fish" => (Shell::Fish, fish()),
+        _ => return Err(anyhow!("Unknown shell {}, expected bash, zsh or fish", name)),
+    };
+    let mut script = vec![];
+    Config::clap().gen_completions_to(BIN_NAME, shell, &mut script);
+    let mut script = String::from_utf8(script)?;
+    // zsh's calls its function at the end, which has to be ours instead
+    if name == "zsh" {
+        let call = format!("_{} \"$@\"", BIN_NAME);
+        if script.trim_end().ends_with(&call) {
+            let at = script.trim_end().len() - call.len();
+            script.truncate(at);
+        }
+    }
+    print!("{}{}", script, dynamic);
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use git2::{Repository, BranchType};
+use anyhow::Result;
+use std::collections::HashMap;
+use crate::{SubWalk, recurse_subs_in, sub_path_to_string, sub_workdir_path, conflicted_files, output};
+use crate::resolver::GitlinkResolver;
+
+// `conflicts`, for looking at a paused run from another terminal
+
+// A repo stopped at a conflict
+struct PausedRepo {
+    path: Vec<String>,
+    // The commit being picked and its summary, if libgit2's rebase state says
+    picking: Option<String>,
+    files: Vec<(String, &'static str)>,
+}
+
+fn paused_repo(repo: &Repository, path: &[String]) -> Result<Option<PausedRepo>> {
+    // Only repos a run is working on, other conflicts aren't ours
+    if repo.find_branch("multi_rebase_old", BranchType::Local).is_err() {
+        return Ok(None);
+    }
+    let files = conflicted_files(repo)?;
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let picking = repo.open_rebase(None).ok().and_then(|mut rebase| {
+        let current = rebase.operation_current()?;
+        let op = rebase.nth(current)?;
+        let commit = repo.find_commit(op.id()).ok()?;
+        Some(format!("{} {}", op.id(), commit.summary().unwrap_or("")))
+    });
+    Ok(Some(PausedRepo { path: path.to_vec(), picking, files }))
+}
+
+// What someone would usually do about a file in this state
+fn suggestion(status: &str) -> &'static str {
+    match status {
+        "deleted by them" | "deleted by us" => "keep it with git add, or drop it with git rm",
+        "both deleted" => "git rm it",
+        _ => "fix the conflict markers, then git add",
+    }
+}
+
+pub fn run(repo: &Repository) -> Result<()> {
+    let head = repo.head()?.peel_to_commit()?;
+    let paused = recurse_subs_in(SubWalk::ReadOnly, repo, &head, &GitlinkResolver, &|repo: &Repository, _submodule, _target, path, child_results: HashMap<String, Vec<PausedRepo>>| -> Result<Vec<PausedRepo>> {
+        let mut results = paused_repo(repo, path)?.into_iter().collect::<Vec<_>>();
+        for (_child, result) in child_results {
+            results.extend(result);
+        }
+        Ok(results)
+    })?;
+
+    if paused.is_empty() {
+        say!("No conflicts in any repo");
+        return Ok(());
+    }
+    for repo in &paused {
+        let named_path = sub_path_to_string(&repo.path);
+        match &repo.picking {
+            Some(picking) => say!("{}", output::label(format!("[{}] Stopped picking {}", named_path, picking))),
+            None => say!("{}", output::label(format!("[{}] Stopped at a conflict", named_path))),
+        }
+        let max_len = repo.files.iter().map(|(file, _)| file.len()).max().unwrap_or(0);
+        for (file, status) in &repo.files {
+            let full_path = format!("{}/{}", sub_workdir_path(&repo.path), file);
+            if repo.path.is_empty() {
+                say!("  {}:{} {} ({})", status, output::pad(status.len(), 15), file, suggestion(status));
+            } else {
+                say!("  {}:{} {}{} {} ({})", status, output::pad(status.len(), 15), file, output::pad(file.len(), max_len), full_path, suggestion(status));
+            }
+        }
+    }
+    say!("Then answer the waiting git-sub-rebase: c to continue, m for your mergetool, s to skip the commit or a to abort");
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use git2::{Cred, CredentialType, FetchOptions, RemoteCallbacks, Repository, SubmoduleUpdateOptions};
+use std::path::PathBuf;
+use crate::proxy;
+
+// Credentials for libgit2's fetches and clones, found where git would look: the ssh agent, then the
+// usual key files in ~/.ssh, then git's credential helpers for HTTPS. libgit2 asks again after each
+// one that gets turned down, so each is tried once before giving up
+
+// After the agent, in the order ssh tries them
+const KEY_FILES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+fn key_files() -> Vec<PathBuf> {
+    let ssh_dir = match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".ssh"),
+        None => return vec![],
+    };
+    KEY_FILES.iter().map(|name| ssh_dir.join(name)).filter(|path| path.exists()).collect()
+}
+
+pub fn callbacks(repo: &Repository) -> RemoteCallbacks<'static> {
+    let config = repo.config().ok();
+    let mut tried_agent = false;
+    let mut key_files = key_files().into_iter();
+    let mut tried_helper = false;
+    let mut tried_default = false;
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        let user = username.unwrap_or("git");
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(user);
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            if !tried_agent {
+                tried_agent = true;
+                debug!("Trying the ssh agent for {}", url);
+                if let Ok(cred) = Cred::ssh_key_from_agent(user) {
+                    return Ok(cred);
+                }
+            }
+            if let Some(key) = key_files.next() {
+                debug!("Trying {} for {}", key.display(), url);
+                return Cred::ssh_key(user, None, &key, None);
+            }
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && !tried_helper {
+            tried_helper = true;
+            if let Some(config) = &config {
+                debug!("Trying git's credential helpers for {}", url);
+                if let Ok(cred) = Cred::credential_helper(config, url, username) {
+                    return Ok(cred);
+                }
+            }
+        }
+        if allowed.contains(CredentialType::DEFAULT) && !tried_default {
+            tried_default = true;
+            return Cred::default();
+        }
+        Err(git2::Error::from_str(&format!("No credentials for {} worked (tried the ssh agent, ~/.ssh keys and git's credential helpers)", url)))
+    });
+    callbacks
+}
+
+// `remote` is the name of the remote being fetched, if it's a configured one, for its proxy setting
+pub fn fetch_options(repo: &Repository, remote: Option<&str>, url: &str) -> FetchOptions<'static> {
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks(repo));
+    options.proxy_options(proxy::options(repo, remote, url));
+    options
+}
+
+pub fn submodule_update_options(repo: &Repository, url: &str) -> SubmoduleUpdateOptions<'static> {
+    let mut options = SubmoduleUpdateOptions::new();
+    options.fetch(fetch_options(repo, None, url));
+    options
+}
@@ -0,0 +1,193 @@
+use git2::{BranchType, Oid, Repository, Submodule};
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use crate::watchdog;
+
+// The same repo is sometimes a submodule in more than one place (a library two components each pull
+// in). Rebasing the same commits onto the same target twice gives the same result, but with new ids
+// and every conflict asked about twice. So the first copy gets rebased, and the others fetch its
+// objects and take its commit map
+
+// Same remote, same commits and same target, with the submodules inside rewritten the same way
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct Key {
+    url: String,
+    head: Oid,
+    target: Oid,
+    children: Vec<(String, Vec<(Oid, Oid)>)>,
+}
+
+struct Rebased {
+    named_path: String,
+    git_dir: PathBuf,
+    commit_map: HashMap<Oid, Oid>,
+}
+
+static REBASED: Mutex<Option<HashMap<Key, Rebased>>> = Mutex::new(None);
+
+// Where the submodule comes from, without the differences that don't matter
+fn normalized_url(repo: &Repository, submodule: &Submodule) -> Option<String> {
+    let url = repo.find_remote("origin").ok()
+        .and_then(|remote| remote.url().map(|url| url.to_string()))
+        .or_else(|| submodule.url().map(|url| url.to_string()))?;
+    let url = url.trim_end_matches('/');
+    Some(url.strip_suffix(".git").unwrap_or(url).to_string())
+}
+
+// Only submodules get one, the superproject is never mounted twice
+pub fn key(repo: &Repository, submodule: Option<&Submodule>, target: Oid, child_results: &HashMap<String, HashMap<Oid, Oid>>) -> Option<Key> {
+    let url = normalized_url(repo, submodule?)?;
+    let head = repo.head().ok()?.peel_to_commit().ok()?.id();
+    let mut children = child_results.iter().map(|(path, map)| {
+        let mut map = map.iter().map(|(old, new)| (*old, *new)).collect::<Vec<_>>();
+        map.sort();
+        (path.clone(), map)
+    }).collect::<Vec<_>>();
+    children.sort();
+    Some(Key { url, head, target, children })
+}
+
+// Remember a rebased copy, if it got as far as having rewritten commits
+pub fn record(key: Key, repo: &Repository, named_path: &str, commit_map: &HashMap<Oid, Oid>) {
+    if repo.find_branch("multi_rebase_new", BranchType::Local).is_err() {
+        return;
+    }
+    let mut rebased = REBASED.lock().expect("Dedupe lock poisoned");
+    rebased.get_or_insert_with(HashMap::new).entry(key).or_insert_with(|| Rebased {
+        named_path: named_path.to_string(),
+        git_dir: repo.path().to_path_buf(),
+        commit_map: commit_map.clone(),
+    });
+}
+
+// Copy what another copy was rebased to into this one. Fetching from its git dir only brings over the
+// objects this one is missing, and leaves nothing pointing back at it, so that copy can be deleted or
+// gc'd afterwards without taking this one's commits along (which an alternate would)
+fn fetch_objects(repo: &Repository, named_path: &str, other_git_dir: &Path, other_head: Oid) -> Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.arg("fetch")
+        .arg("--quiet")
+        .arg("--no-tags")
+        .arg(other_git_dir)
+        .arg("refs/heads/multi_rebase_new")
+        .current_dir(repo.workdir().unwrap_or(repo.path()));
+    match watchdog::run_command(named_path, "git fetch", &mut cmd)? {
+        Some(output) if output.status.success() => {}
+        Some(output) => return Err(anyhow!("[{}] Fetching from {} failed: {}", named_path, other_git_dir.display(), String::from_utf8_lossy(&output.stderr).trim())),
+        None => return Err(anyhow!("[{}] Fetching from {} was skipped", named_path, other_git_dir.display())),
+    }
+    repo.find_commit(other_head)?;
+    Ok(())
+}
+
+// A git dir that lists `git_dir`'s objects in its objects/info/alternates, among the git dirs of the
+// superproject `git_dir` belongs to. Earlier versions left those behind, and deleting `git_dir` would
+// take the other one's objects with it
+pub fn borrowed_by(git_dir: &Path) -> Option<PathBuf> {
+    let objects = git_dir.join("objects").canonicalize().ok()?;
+    // The superproject's git dir is the one the outermost modules/ is in
+    let root = git_dir.ancestors().filter(|dir| dir.file_name() == Some("modules".as_ref())).last()?.parent()?;
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        if dir != git_dir {
+            let alternates = fs::read_to_string(dir.join("objects").join("info").join("alternates")).unwrap_or_default();
+            if alternates.lines().any(|line| dir.join("objects").join(line.trim()).canonicalize().ok().as_ref() == Some(&objects)) {
+                return Some(dir);
+            }
+        }
+        // Submodule names can have slashes in them, so git dirs can be anywhere under modules/
+        let children = if dir == root { vec![dir.join("modules")] } else {
+            fs::read_dir(&dir).map(|entries| entries.flatten().map(|entry| entry.path()).collect()).unwrap_or_default()
+        };
+        dirs.extend(children.into_iter().filter(|child| child.is_dir() && child.file_name() != Some("objects".as_ref())));
+    }
+    None
+}
+
+// If another copy was rebased already, copy its objects over and point multi_rebase_new at what it
+// was rebased to, and hand back its commit map. The state branches have to be there already, like for
+// any rebase
+pub fn reuse(key: &Key, repo: &Repository, named_path: &str) -> Result<Option<HashMap<Oid, Oid>>> {
+    let (other, git_dir, commit_map) = {
+        let rebased = REBASED.lock().expect("Dedupe lock poisoned");
+        match rebased.as_ref().and_then(|rebased| rebased.get(key)) {
+            Some(done) => (done.named_path.clone(), done.git_dir.clone(), done.commit_map.clone()),
+            None => return Ok(None),
+        }
+    };
+    if git_dir == repo.path() {
+        return Ok(None);
+    }
+    let other_head = Repository::open(&git_dir)?.find_branch("multi_rebase_new", BranchType::Local)?.get().peel_to_commit()?.id();
+    if commit_map.get(&key.head) != Some(&other_head) {
+        // Something moved it since, so rebase this copy after all
+        warn!("[{}] {} is at {} now, not where its rebase took {}. Rebasing this copy on its own", named_path, other, other_head, key.head);
+        return Ok(None);
+    }
+    say!("[{}] Same commits and target as {}, taking what it was rebased to", named_path, other);
+    fetch_objects(repo, named_path, &git_dir, other_head)?;
+    repo.reference("refs/heads/multi_rebase_new", other_head, true, &format!("sub-rebase: same as {}", other))?;
+    Ok(Some(commit_map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Signature;
+
+    fn commit(repo: &Repository, parents: &[&git2::Commit], contents: &str) -> Oid {
+        let signature = Signature::now("Test", "test@example.com").unwrap();
+        let mut tree = repo.treebuilder(None).unwrap();
+        tree.insert("file", repo.blob(contents.as_bytes()).unwrap(), 0o100644).unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        repo.commit(None, &signature, &signature, contents, &tree, parents).unwrap()
+    }
+
+    #[test]
+    fn reused_copy_outlives_source() {
+        let dir = std::env::temp_dir().join(format!("sub-rebase-dedupe-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let source = Repository::init(dir.join("source")).unwrap();
+        let copy = Repository::init(dir.join("copy")).unwrap();
+
+        let head = commit(&source, &[], "old");
+        let rebased = commit(&source, &[&source.find_commit(head).unwrap()], "rebased");
+        source.reference("refs/heads/multi_rebase_new", rebased, true, "test").unwrap();
+        let key = Key { url: "https://example.com/dedupe-test".to_string(), head, target: rebased, children: vec![] };
+        let commit_map = HashMap::from([(head, rebased)]);
+        record(key.clone(), &source, "source", &commit_map);
+
+        assert_eq!(reuse(&key, &copy, "copy").unwrap(), Some(commit_map));
+        copy.set_head("refs/heads/multi_rebase_new").unwrap();
+        assert!(!copy.path().join("objects").join("info").join("alternates").exists());
+
+        drop(source);
+        fs::remove_dir_all(dir.join("source")).unwrap();
+        let copy = Repository::open(dir.join("copy")).unwrap();
+        assert_eq!(copy.head().unwrap().peel_to_commit().unwrap().id(), rebased);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn finds_borrowers() {
+        let dir = std::env::temp_dir().join(format!("sub-rebase-borrowers-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let lender = dir.join(".git").join("modules").join("libs").join("foo");
+        let borrower = dir.join(".git").join("modules").join("bar");
+        for git_dir in [&lender, &borrower] {
+            fs::create_dir_all(git_dir.join("objects").join("info")).unwrap();
+        }
+        assert_eq!(borrowed_by(&lender), None);
+
+        fs::write(borrower.join("objects").join("info").join("alternates"), "../../libs/foo/objects\n").unwrap();
+        assert_eq!(borrowed_by(&lender), Some(borrower.clone()));
+        assert_eq!(borrowed_by(&borrower), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,41 @@
+use anyhow::{Result, anyhow};
+
+// Portable builds (see Cargo.toml) leave out libgit2's HTTPS and SSH transports. Check for them
+// before libgit2 needs them, so a missing one gets explained instead of failing somewhere deep inside
+
+fn has_feature(feature: libgit2_sys::git_feature_t) -> bool {
+    let features = unsafe { libgit2_sys::git_libgit2_features() };
+    features as u32 & feature != 0
+}
+
+pub fn https() -> bool {
+    has_feature(libgit2_sys::GIT_FEATURE_HTTPS)
+}
+
+pub fn ssh() -> bool {
+    has_feature(libgit2_sys::GIT_FEATURE_SSH)
+}
+
+// Transports this build has, for showing alongside the version
+pub fn transports() -> String {
+    let mut transports = vec!["file", "git", "http"];
+    if https() {
+        transports.push("https");
+    }
+    if ssh() {
+        transports.push("ssh");
+    }
+    transports.join(", ")
+}
+
+// Make sure libgit2 can talk to a remote at this url
+pub fn check_url(url: &str) -> Result<()> {
+    let scp_like = !url.contains("://") && url.find(':').is_some_and(|colon| !url[..colon].contains('/'));
+    if url.starts_with("https://") && !https() {
+        return Err(anyhow!("Can't fetch {}: this build has no HTTPS support. Use a build with the https feature, or update the submodule with git first", url));
+    }
+    if (url.starts_with("ssh://") || url.starts_with("git+ssh://") || scp_like) && !ssh() {
+        return Err(anyhow!("Can't fetch {}: this build has no SSH support. Use a build with the ssh feature, or update the submodule with git first", url));
+    }
+    Ok(())
+}
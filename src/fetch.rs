@@ -0,0 +1,123 @@
+use git2::Repository;
+use anyhow::{Result, anyhow};
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use crate::{credentials, features, proxy, temp_clones, watchdog};
+
+// --fetch: bring the target's remote up to date before anything is worked out from it, in the
+// superproject and every submodule, so origin/main means what it is now and not at the last fetch
+
+// The remote to fetch in a repo: the one asked for if the repo has it, else origin, else whichever
+// it has
+pub fn default_remote(repo: &Repository, preferred: Option<&str>) -> Result<Option<String>> {
+    let remotes = repo.remotes()?;
+    let names = remotes.iter().flatten().collect::<Vec<_>>();
+    Ok(preferred.into_iter().chain(Some("origin"))
+        .find(|name| names.contains(name))
+        .or_else(|| names.first().copied())
+        .map(|name| name.to_string()))
+}
+
+// Remote of a target like origin/main, if it's a remote-tracking branch
+pub fn target_remote(repo: &Repository, ref_: &str) -> Option<String> {
+    let reference = repo.resolve_reference_from_short_name(ref_).ok()?;
+    let name = reference.name()?;
+    if !name.starts_with("refs/remotes/") {
+        return None;
+    }
+    let remote = repo.branch_remote_name(name).ok()?;
+    remote.as_str().map(|remote| remote.to_string())
+}
+
+// Fetch a remote's configured refspecs
+pub fn fetch_remote(named_path: &str, repo: &Repository, name: &str) -> Result<()> {
+    let mut remote = repo.find_remote(name)?;
+    let url = remote.url().unwrap_or("").to_string();
+    say!("[{}] Fetching {}", named_path, name);
+    if proxy::needs_git(repo, Some(name), &url) {
+        return git(named_path, repo, "git fetch", &["fetch".to_string(), name.to_string()]);
+    }
+    features::check_url(&url)?;
+    let mut options = credentials::fetch_options(repo, Some(name), &url);
+    watchdog::watch(named_path, "fetch", || remote.fetch(&[] as &[&str], Some(&mut options), None))?;
+    Ok(())
+}
+
+// The superproject from `remote`, then every checked out submodule from its remote of the same name
+// (or its default), outermost first so the gitlinks fetched above are there to find below
+pub fn fetch_tree(repo: &Repository, remote: &str) -> Result<()> {
+    fn recurse(repo: &Repository, path: &mut Vec<String>, remote: &str) -> Result<()> {
+        let named_path = crate::sub_path_to_string(path);
+        match default_remote(repo, Some(remote))? {
+            Some(name) => fetch_remote(&named_path, repo, &name)?,
+            None => say!("[{}] No remote to fetch", named_path),
+        }
+        for submodule in repo.submodules()? {
+            let sub_repo = match submodule.open() {
+                Ok(sub_repo) => sub_repo,
+                // Gets cloned fresh later on anyway
+                Err(_) => continue,
+            };
+            path.push(submodule.name().ok_or_else(|| anyhow!("Submodule needs name"))?.to_string());
+            recurse(&sub_repo, path, remote)?;
+            path.pop();
+        }
+        Ok(())
+    }
+    recurse(repo, &mut vec![], remote)
+}
+
+fn git(named_path: &str, repo: &Repository, operation: &str, args: &[String]) -> Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.args(args).current_dir(repo.workdir().expect("Has workdir"));
+    match watchdog::run_command(named_path, operation, &mut cmd)? {
+        Some(output) if output.status.success() => Ok(()),
+        Some(output) => Err(anyhow!("[{}] {} failed: {}", named_path, operation, String::from_utf8_lossy(&output.stderr).trim())),
+        None => Err(anyhow!("[{}] {} was skipped", named_path, operation)),
+    }
+}
+
+// --fetch-all: clone every submodule that isn't there yet and fetch every remote of every repo, `jobs`
+// at a time, so nothing has to come over the network halfway through
+pub fn fetch_all(repo: &Repository, jobs: usize) -> Result<()> {
+    // Submodules that aren't there yet, as (parent's workdir, name, path from the superproject)
+    fn find_missing(repo: &Repository, path: &mut Vec<String>, missing: &mut Vec<(PathBuf, String, String)>) -> Result<()> {
+        for submodule in repo.submodules()? {
+            let name = submodule.name().ok_or_else(|| anyhow!("Submodule needs name"))?.to_string();
+            path.push(name.clone());
+            match submodule.open() {
+                Ok(sub_repo) => find_missing(&sub_repo, path, missing)?,
+                Err(_) => {
+                    temp_clones::record(repo, &name, &submodule.path().to_string_lossy());
+                    missing.push((repo.workdir().expect("Has workdir").to_path_buf(), name, crate::sub_path_to_string(path)));
+                }
+            }
+            path.pop();
+        }
+        Ok(())
+    }
+
+    let mut missing = vec![];
+    find_missing(repo, &mut vec![], &mut missing)?;
+    if !missing.is_empty() {
+        say!("Cloning {} submodules, {} at a time", missing.len(), jobs);
+    }
+    for batch in missing.chunks(jobs.max(1)) {
+        // Each clone opens its own handles, they can't be shared between threads
+        thread::scope(|scope| {
+            let clones = batch.iter().map(|(parent, name, named_path)| scope.spawn(move || -> Result<()> {
+                let parent = Repository::open(parent)?;
+                let mut submodule = parent.find_submodule(name)?;
+                crate::update_submodule(&parent, &mut submodule, named_path)
+            })).collect::<Vec<_>>();
+            clones.into_iter()
+                .map(|clone| clone.join().unwrap_or_else(|_| Err(anyhow!("Cloning a submodule panicked"))))
+                .collect::<Result<Vec<_>>>()
+        })?;
+    }
+
+    say!("Fetching every repo, {} at a time", jobs);
+    let args = ["fetch", "--all", "--recurse-submodules=yes"].iter().map(|arg| arg.to_string()).chain(Some(format!("--jobs={}", jobs))).collect::<Vec<_>>();
+    git("*root*", repo, "git fetch", &args)
+}
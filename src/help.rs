@@ -0,0 +1,190 @@
+use anyhow::{Result, anyhow};
+use structopt::StructOpt;
+use crate::{Config, Failure, UnresolvedHeadPolicy, OnConflictPolicy, PreRebasePolicy, MissingCommitsCheck, StrategyOption, Choices, STATE_BRANCHES, CONFLICT_ACTIONS};
+use crate::settings::{SETTINGS, Kind, config_file_path};
+use crate::output::EVENTS;
+
+// Topics are built from the same tables the rebase itself uses, so they stay true as those change
+
+#[derive(StructOpt)]
+pub struct HelpCommand {
+    /// Topic to show, or leave out to list them
+    topic: Option<String>,
+}
+
+// (name, summary, text)
+type Topic = (&'static str, &'static str, fn() -> String);
+
+const TOPICS: &[Topic] = &[
+    ("state", "Branches a rebase leaves in every repo while it runs", state),
+    ("recovery", "Getting back to where you were after a run goes wrong", recovery),
+    ("conflicts", "How conflicts get resolved, and the choices at the prompt", conflicts),
+    ("submodule-policies", "Options deciding how submodules are treated", submodule_policies),
+    ("settings", "Every setting and where else it can be set", settings),
+    ("exit-codes", "What each exit code means", exit_codes),
+    ("porcelain", "Lines printed by --porcelain", porcelain),
+    ("serve", "The JSON-RPC protocol spoken by --serve", serve),
+];
+
+fn choices<T>(choices: Choices<T>) -> String {
+    let max_name_len = choices.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+    choices.iter()
+        .map(|(name, _, description)| format!("    {:width$}  {}\n", name, description, width = max_name_len))
+        .collect()
+}
+
+fn state() -> String {
+    let mut text = String::from("While rebasing, every repo in the tree gets these branches:\n");
+    for (name, description) in STATE_BRANCHES {
+        text.push_str(&format!("  {:18}  {}\n", name, description));
+    }
+    text.push_str("\nThe repo's HEAD points at multi_rebase_new until the run finishes. Once every repo is done,\n");
+    text.push_str("the original branches are reset to multi_rebase_new and all of these are deleted. If the\n");
+    text.push_str("rebase fails, the original branches are reset to multi_rebase_old instead.\n");
+    text
+}
+
+fn recovery() -> String {
+    let mut text = String::new();
+    text.push_str("Before rebasing a repo, its branch is backed up as backup/<branch>_<run id> (see --backup-name\n");
+    text.push_str("and --no-backup). Every repo in\n");
+    text.push_str("one run shares the run id, so they can be restored together:\n");
+    text.push_str("  git-sub-rebase backups list\n");
+    text.push_str("  git-sub-rebase backups restore <run id>\n");
+    text.push_str("  git-sub-rebase undo             (restores the last run)\n");
+    text.push_str("Each rebased repo also gets ORIG_HEAD set to where it was, and everything the run does to\n");
+    text.push_str("HEAD and branches shows up in the reflog as sub-rebase: <what it was doing>.\n");
+    text.push_str("Old ones pile up, prune them by run or keep only the newest few:\n");
+    text.push_str("  git-sub-rebase backups prune <run id>\n");
+    text.push_str("  git-sub-rebase backups prune --keep <count>\n\n");
+    text.push_str(&format!("If a run dies partway through, any of {} left behind\n", STATE_BRANCHES.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")));
+    text.push_str("are detected at the start of the next run, which offers to delete them and start over.\n");
+    text.push_str("Repos it left checked out on multi_rebase_new get asked about first: restore the commit from\n");
+    text.push_str("before the run, or adopt what was rebased so far, back on the branch its backup protected.\n");
+    text.push_str("\nEvery run appends a timestamped transcript of what it said, asked and was told to\n");
+    text.push_str(".git/sub-rebase/log, for working out what happened to which repo.\n");
+    text
+}
+
+fn conflicts() -> String {
+    let mut text = String::new();
+    text.push_str("When a pick conflicts, these are tried in order until one resolves it:\n");
+    text.push_str("  1. git rerere, if rerere.enabled is set and it resolves every file\n");
+    text.push_str("  2. --exec-on-conflict, if it exits successfully\n");
+    text.push_str("  3. --on-conflict:\n");
+    text.push_str(&choices(OnConflictPolicy::CHOICES));
+    text.push_str("  4. --mergetool, or else the prompt:\n");
+    for (key, name, description) in CONFLICT_ACTIONS {
+        text.push_str(&format!("    {}  {:20}  {}\n", key, name, description));
+    }
+    text.push_str("\nThe mergetool counts as resolving the conflict if mergetool.<tool>.trustExitCode (or\n");
+    text.push_str("--trust-exit-codes) is set and it exits successfully, or otherwise if nothing is left conflicted\n");
+    text.push_str("in the index. If it didn't, the prompt comes back.\n");
+    text.push_str("Skipping a commit drops it, which --missing-commits-check (or rebase.missingCommitsCheck) can\n");
+    text.push_str("warn about or refuse:\n");
+    text.push_str(&choices(MissingCommitsCheck::CHOICES));
+    text.push_str("`git-sub-rebase conflicts` lists what's conflicted in every repo of a paused run.\n");
+    text.push_str("Commands run at a conflict get SUB_REBASE_REPO, SUB_REBASE_PATH, SUB_REBASE_COMMIT,\n");
+    text.push_str("SUB_REBASE_OLD and SUB_REBASE_NEW in their environment.\n");
+    text.push_str("Picks can be steered with -X/--strategy-option:\n");
+    text.push_str(&choices(StrategyOption::CHOICES));
+    text
+}
+
+fn submodule_policies() -> String {
+    let mut text = String::new();
+    text.push_str("--unresolved-head, when a submodule's HEAD can't be resolved mid-rebase:\n");
+    text.push_str(&choices(UnresolvedHeadPolicy::CHOICES));
+    text.push_str("\n--pre-rebase-reject, when a repo's pre-rebase hook rejects the rebase:\n");
+    text.push_str(&choices(PreRebasePolicy::CHOICES));
+    text.push_str("\n--exclude-submodule <path> leaves a submodule and everything inside it on its old commits.\n");
+    text.push_str("--select-submodules asks which submodules to rebase before starting.\n");
+    text.push_str("--edit-plan opens the plan in your sequence editor, where repos can be excluded the same way.\n");
+    text.push_str("--lockfile <path> takes submodule targets from a lockfile instead of the gitlinks.\n");
+    text.push_str("--gitlink-only rewrites the superproject's gitlinks without checking anything out.\n");
+    text
+}
+
+fn settings() -> String {
+    let mut text = String::from("Settings come from the command line, then the environment, then git config, then ");
+    text.push_str(&match config_file_path() {
+        Some(path) => format!("{}.\n", path.display()),
+        None => "the user config file.\n".to_string(),
+    });
+    text.push_str("Repeatable settings take comma-separated lists in the environment and multivars in git config.\n\n");
+    let max_long_len = SETTINGS.iter().map(|s| s.long.len()).max().unwrap_or(0) + 2;
+    let max_key_len = SETTINGS.iter().map(|s| s.git_key().len()).max().unwrap_or(0);
+    for setting in SETTINGS {
+        let kind = match setting.kind {
+            Kind::Flag => "",
+            Kind::Value => " (value)",
+            Kind::Multi => " (repeatable)",
+            Kind::Count => " (count)",
+        };
+        text.push_str(&format!("  {:long_width$}  {:key_width$}  {}{}\n", format!("--{}", setting.long), setting.git_key(), setting.env_var(), kind, long_width = max_long_len, key_width = max_key_len));
+    }
+    text.push_str("\nSubmodules can also be excluded one by one in git config, by path:\n");
+    text.push_str("  [subRebase \"libs/foo\"]\n      exclude = true\n");
+    text.push_str("\nSave one in the repo's git config (or the user config file, with --user):\n");
+    text.push_str("  git-sub-rebase config set on-conflict abort\n  git-sub-rebase config set --add exclude-submodule libs/foo\n");
+    text.push_str("  git-sub-rebase config get on-conflict\n  git-sub-rebase config unset on-conflict\n  git-sub-rebase config list\n");
+    text.push_str("\nSee the effective values with: git-sub-rebase config --show-effective\n");
+    text
+}
+
+fn exit_codes() -> String {
+    let mut text = String::from("  0  Success\n  1  Any other error\n");
+    for failure in Failure::all() {
+        text.push_str(&format!("  {}  {}\n", failure.exit_code(), failure));
+    }
+    text
+}
+
+fn porcelain() -> String {
+    let mut text = String::from("With --porcelain, stdout only gets these lines and everything else goes to stderr:\n");
+    let max_event_len = EVENTS.iter().map(|(event, _)| event.len()).max().unwrap_or(0);
+    for (event, description) in EVENTS {
+        text.push_str(&format!("  {:width$}  {}\n", event, description, width = max_event_len));
+    }
+    text.push_str("\nPaths are submodule paths, *root* for the superproject. Fields are separated by spaces; one that's\n");
+    text.push_str("empty or has spaces, quotes, backslashes or control characters in it is written in double quotes with\n");
+    text.push_str("C-style escapes, e.g. \"libs/my lib\". Output from git and hooks goes to stderr too. Answer prompts on stdin.\n");
+    text
+}
+
+fn serve() -> String {
+    let mut text = String::from("With --serve, stdin and stdout carry JSON-RPC 2.0 messages, one per line.\n\n");
+    text.push_str("Client to server:\n");
+    text.push_str("  {\"id\":1,\"method\":\"rebase\",\"params\":{\"args\":[\"origin/main\"],\"cwd\":\"/path/to/repo\"}}\n");
+    text.push_str("      Start a run with these arguments. Answered with {\"exit_code\":<code>} when it ends\n");
+    text.push_str("  {\"id\":2,\"method\":\"shutdown\"}\n");
+    text.push_str("      Stop once the current run (if any) ends\n");
+    text.push_str("Server to client:\n");
+    text.push_str("  {\"method\":\"event\",\"params\":{\"name\":\"rewrite\",\"fields\":[<path>, <old>, <new>]}}\n");
+    text.push_str("      Each --porcelain event, see `help porcelain`\n");
+    text.push_str("  {\"method\":\"message\",\"params\":{\"text\":\"...\"}}\n");
+    text.push_str("      Everything printed for people\n");
+    text.push_str("  {\"id\":<n>,\"method\":\"prompt\",\"params\":{\"kind\":\"conflict\",\"path\":\"libs/foo\"}}\n");
+    text.push_str("      A question, answered with {\"id\":<n>,\"result\":\"<what you'd type>\"}\n");
+    text.push_str("\nOne run at a time. If the client goes away, a running rebase fails at its next prompt.\n");
+    text
+}
+
+pub fn run(command: &HelpCommand) -> Result<()> {
+    match &command.topic {
+        None => {
+            Config::clap().print_long_help()?;
+            println!("\n\nHelp topics (git-sub-rebase help <topic>):");
+            let max_name_len = TOPICS.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+            for (name, summary, _) in TOPICS {
+                println!("  {:width$}  {}", name, summary, width = max_name_len);
+            }
+        }
+        Some(topic) => {
+            let (_, _, render) = TOPICS.iter().find(|(name, _, _)| name == topic)
+                .ok_or_else(|| anyhow!("Unknown help topic {}, expected one of {}", topic, TOPICS.iter().map(|(name, _, _)| *name).collect::<Vec<_>>().join(", ")))?;
+            print!("{}", render());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use git2::Repository;
+use anyhow::Result;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+
+// libgit2 doesn't run hooks, so anything git would run during a rebase is run from here
+
+// Where a repo keeps a hook, if it has it and it's runnable. core.hooksPath wins over .git/hooks
+fn hook_path(repo: &Repository, name: &str) -> Result<Option<PathBuf>> {
+    let dir = match repo.config()?.get_path("core.hooksPath") {
+        Ok(dir) if dir.is_absolute() => dir,
+        // Relative hooksPath is relative to where hooks run
+        Ok(dir) => repo.workdir().unwrap_or(repo.path()).join(dir),
+        Err(_) => repo.path().join("hooks"),
+    };
+    let path = dir.join(name);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if path.metadata()?.permissions().mode() & 0o111 == 0 {
+            return Ok(None);
+        }
+    }
+    Ok(Some(path))
+}
+
+pub fn has_hook(repo: &Repository, name: &str) -> bool {
+    hook_path(repo, name).ok().flatten().is_some()
+}
+
+// Run a hook like git does, from the top of the working copy with its stdout sent to stderr. None if
+// the repo doesn't have it
+pub fn run_hook(repo: &Repository, name: &str, args: &[&str], input: Option<&str>) -> Result<Option<ExitStatus>> {
+    let path = match hook_path(repo, name)? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let mut child = Command::new(path)
+        .args(args)
+        .current_dir(repo.workdir().unwrap_or(repo.path()))
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .spawn()?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        // A hook that doesn't read its input can close the pipe early, that's fine
+        let _ = stdin.write_all(input.as_bytes());
+    }
+    let output = child.wait_with_output()?;
+    eprint!("{}", String::from_utf8_lossy(&output.stdout));
+    Ok(Some(output.status))
+}
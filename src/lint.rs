@@ -0,0 +1,87 @@
+use git2::{Repository, Oid, Commit, Delta, FileMode, Pathspec, PathspecFlags, Sort};
+use anyhow::Result;
+use crate::Config;
+
+// --lint: check the commits about to be rewritten, so the rebase doubles as a history cleanup gate.
+// Every commit gets every check, and the run stops before anything is touched if any of them fail
+
+// Problems with one commit, as messages for people
+fn lint_commit(config: &Config, repo: &Repository, commit: &Commit, forbidden: Option<&Pathspec>) -> Result<Vec<String>> {
+    let mut problems = vec![];
+
+    let message = commit.message().unwrap_or("");
+    let mut lines = message.lines();
+    let subject = lines.next().unwrap_or("");
+    if subject.trim().is_empty() {
+        problems.push("Empty subject".to_string());
+    } else if subject.chars().count() > config.lint_subject_length {
+        problems.push(format!("Subject is longer than {} characters", config.lint_subject_length));
+    }
+    if let Some(second) = lines.next() {
+        if !second.trim().is_empty() {
+            problems.push("No blank line after the subject".to_string());
+        }
+    }
+    if config.lint_signoff {
+        let committer = commit.committer();
+        let signoff = format!("Signed-off-by: {} <{}>", committer.name().unwrap_or(""), committer.email().unwrap_or(""));
+        if !message.lines().any(|line| line.trim() == signoff) {
+            problems.push("Missing Signed-off-by from the committer".to_string());
+        }
+    }
+
+    if forbidden.is_none() && config.lint_max_blob.is_none() {
+        return Ok(problems);
+    }
+    // Against the first parent, so merges only answer for what they bring in
+    let parent_tree = match commit.parents().next() {
+        Some(parent) => Some(parent.tree()?),
+        None => None,
+    };
+    let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+    for delta in diff.deltas() {
+        if delta.status() != Delta::Added && delta.status() != Delta::Modified {
+            continue;
+        }
+        let file = delta.new_file();
+        // Gitlinks get their own commits linted in the submodule
+        if file.mode() == FileMode::Commit {
+            continue;
+        }
+        let path = match file.path() {
+            Some(path) => path,
+            None => continue,
+        };
+        if let Some(forbidden) = forbidden {
+            if forbidden.matches_path(path, PathspecFlags::DEFAULT) {
+                problems.push(format!("Forbidden file {}", path.display()));
+            }
+        }
+        if let Some(max_blob) = config.lint_max_blob {
+            let size = repo.find_blob(file.id())?.size() as u64;
+            if size > max_blob {
+                problems.push(format!("{} is {} bytes, more than {}", path.display(), size, max_blob));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+// Lint the commits in base..head, oldest first, giving (commit, summary, problems) for each one with problems
+pub fn lint_range(config: &Config, repo: &Repository, base: Oid, head: Oid) -> Result<Vec<(Oid, String, Vec<String>)>> {
+    let forbidden = if config.lint_forbid.is_empty() { None } else { Some(Pathspec::new(config.lint_forbid.iter())?) };
+
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    walk.push(head)?;
+    walk.hide(base)?;
+    let mut report = vec![];
+    for id in walk {
+        let commit = repo.find_commit(id?)?;
+        let problems = lint_commit(config, repo, &commit, forbidden.as_ref())?;
+        if !problems.is_empty() {
+            report.push((commit.id(), commit.summary().unwrap_or("").to_string(), problems));
+        }
+    }
+    Ok(report)
+}
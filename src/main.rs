@@ -1,38 +1,845 @@
-use git2::{Repository, Commit, TreeWalkMode, TreeWalkResult, ObjectType, Submodule, Tree, Oid, RebaseOptions, ResetType, BranchType, Delta, Sort, Signature};
+use git2::{Repository, Commit, Object, ObjectType, Submodule, Tree, Oid, RebaseOptions, BranchType, Delta, Sort, Signature, FileMode, IndexEntry, IndexTime, MergeOptions, FileFavor};
 use anyhow::{Error, Result, anyhow};
 use structopt::StructOpt;
-use std::ffi::OsStr;
 use std::borrow::{BorrowMut};
+use std::cell::RefCell;
 use std::collections::{BTreeMap, HashMap};
-use git2::build::CheckoutBuilder;
+use git2::build::{CheckoutBuilder, TreeUpdateBuilder};
 use chrono::Local;
-use std::io::stdin;
 use std::path::PathBuf;
 use std::process::Command;
 use std::str::FromStr;
-use std::sync::{atomic};
+use std::sync::{atomic, Mutex};
 use std::sync::atomic::AtomicBool;
 use git2::ErrorCode::{Applied, Conflict, NotFound};
 use git2::ErrorClass::{Os, Rebase};
+use structopt::clap::AppSettings;
+
+#[macro_use]
+mod messages;
+#[macro_use]
+mod output;
+mod autostash;
+mod backups;
+mod bare_worktree;
+mod cache;
+mod checkout;
+mod commit_graph;
+mod commit_map;
+mod completions;
+mod conflicts;
+mod dedupe;
+mod credentials;
+mod features;
+mod fetch;
+mod help;
+mod hooks;
+mod lint;
+mod plan;
+mod pointers;
+mod prompter;
+mod promisor;
+mod proxy;
+mod push;
+mod resolver;
+mod serve;
+mod settings;
+mod shallow;
+mod sparse;
+mod temp_clones;
+mod todo;
+mod watchdog;
+
+use resolver::TargetResolver;
 
 // TODO: Continue/abort after a crash
 // TODO: Interactive mode where you can pick/edit/squash/fixup/drop
 
 #[derive(StructOpt)]
+#[structopt(setting=AppSettings::SubcommandsNegateReqs, setting=AppSettings::DisableHelpSubcommand)]
 struct Config {
-    #[structopt(name="ref")]
-    ref_: String,
+    #[structopt(name="ref", required_unless_one=&["stdin-refs", "serve"])]
+    ref_: Option<String>,
+    /// Run as if started in this directory, like `git -C`. A submodule given this way gets rebased on its own
+    #[structopt(short="C", long="repo", parse(from_os_str))]
+    repo: Option<PathBuf>,
+    /// Read targets from stdin, one per line until a blank line or the end: a ref for the superproject,
+    /// or a submodule path (from the superproject, e.g. libs/foo/deps/bar) and a ref to rebase it onto
+    #[structopt(long)]
+    stdin_refs: bool,
+    /// Answer every prompt with its default, for runs nobody is watching. Conflicts and submodules
+    /// without a HEAD abort the run instead of waiting
+    #[structopt(short, long)]
+    yes: bool,
+    /// Answer prompts from this file, one `<kind> [<path>] = <answer>` per line (kinds as in `help porcelain`),
+    /// e.g. `pick-branch libs/foo = 2` or `conflict * = a`. Anything it doesn't answer gets asked as usual
+    #[structopt(long, parse(from_os_str))]
+    answers: Option<PathBuf>,
+    /// Speak JSON-RPC on stdin and stdout instead, for editor plugins to run rebases. See `help serve`
+    #[structopt(long)]
+    serve: bool,
+    // Targets for submodules from --stdin-refs, by absolute working copy path
+    #[structopt(skip)]
+    stdin_pins: Vec<(PathBuf, String)>,
+    #[structopt(subcommand)]
+    command: Option<SubCommand>,
+    /// Rebase a stack of dependent branches, bottom first, e.g. --stack A --stack B --stack C with C
+    /// checked out. C gets rebased and A and B move to their rewritten commits, so gitlinks stay
+    /// consistent across the whole stack
+    #[structopt(long, number_of_values=1)]
+    stack: Vec<String>,
+    // Shared by everything created during this run, so backups can be grouped back together
+    #[structopt(skip)]
+    run_id: String,
+    /// What to do when a submodule's HEAD can't be resolved mid-rebase: prompt, detach, branch or abort
+    #[structopt(long, default_value="prompt")]
+    unresolved_head: UnresolvedHeadPolicy,
+    /// Use the original author date as the committer date of rewritten commits
+    #[structopt(long)]
+    committer_date_is_author_date: bool,
+    /// Keep the original committer name, email and date of rewritten commits
+    #[structopt(long, conflicts_with="committer-date-is-author-date")]
+    preserve_committer: bool,
+    /// Shell command to run when a conflict stops the rebase, before asking for manual resolution
+    #[structopt(long)]
+    exec_on_conflict: Option<String>,
+    /// Make the current user the author of every rewritten commit
+    #[structopt(long)]
+    reset_author: bool,
+    /// Use the current time as the author date of rewritten commits
+    #[structopt(long)]
+    ignore_date: bool,
+    /// Author to use for every rewritten commit, as "Name <email>"
+    #[structopt(long)]
+    author: Option<Identity>,
+    /// Committer to use for every rewritten commit, as "Name <email>"
+    #[structopt(long, conflicts_with="preserve-committer")]
+    committer: Option<Identity>,
+    /// Branch to check out in a submodule that has none checked out, instead of asking, e.g.
+    /// --checkout libs/foo=main. Gets reset to the submodule's HEAD like a branch picked at the prompt
+    #[structopt(long="checkout", number_of_values=1)]
+    checkouts: Vec<CheckoutSpec>,
+    /// Rewrite the superproject's trees directly from the submodule commit maps, without checkouts.
+    /// Only works if the superproject's own files never conflict
+    #[structopt(long)]
+    gitlink_only: bool,
+    /// Rewrite every repo in the object database with in-memory merges, only touching working copies
+    /// once the rebase is finished. Conflicts abort the rebase since there is nowhere to resolve them
+    #[structopt(long)]
+    no_worktree_rebase: bool,
+    /// Pick every commit in the working copy, checking each one out. Otherwise commits get picked in
+    /// memory until one conflicts, and only the rest go through the working copy
+    #[structopt(long)]
+    worktree_picks: bool,
+    /// Merge option to pass to every pick: ours, theirs, union, ignore-space-change, ignore-all-space,
+    /// ignore-space-at-eol, patience or minimal. Like git rebase, "ours" is the upstream side
+    #[structopt(short="X", long="strategy-option", number_of_values=1)]
+    strategy_options: Vec<StrategyOption>,
+    /// Always launch git mergetool on conflicted files instead of asking
+    #[structopt(long)]
+    mergetool: bool,
+    /// Take the mergetool's exit code as whether it resolved the conflict, for tools without
+    /// mergetool.<tool>.trustExitCode set
+    #[structopt(long)]
+    trust_exit_codes: bool,
+    /// Take each submodule's target from this lockfile ("<path> <sha>" per line) in the target commit,
+    /// instead of from the gitlinks
+    #[structopt(long, parse(from_os_str))]
+    lockfile: Option<PathBuf>,
+    /// Leave this submodule (and everything inside it) on its old commits, e.g. libs/foo
+    #[structopt(long="exclude-submodule", number_of_values=1)]
+    exclude_submodules: Vec<String>,
+    /// Pick which submodules to rebase before starting
+    #[structopt(long)]
+    select_submodules: bool,
+    /// Open the plan in your sequence editor before starting, to exclude repos from the run.
+    /// See `help submodule-policies`
+    #[structopt(long)]
+    edit_plan: bool,
+    /// Print the conflict hunks of each conflicted file, not just the file names
+    #[structopt(long)]
+    conflict_hunks: bool,
+    /// What to do when a conflict needs resolving: prompt, or abort to roll everything back and exit with code 3
+    #[structopt(long, default_value="prompt")]
+    on_conflict: OnConflictPolicy,
+    /// Print stable, machine-readable event lines on stdout and move everything else to stderr.
+    /// See `help porcelain`
+    #[structopt(long)]
+    porcelain: bool,
+    /// Screen reader friendly output: no column alignment or arrows, labelled lines, one prompt choice per line
+    #[structopt(long)]
+    plain: bool,
+    /// Say more about what's happening: -v for every reset and HEAD move, -vv for every pick too
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+    /// Only print errors and what's needed to answer prompts
+    #[structopt(short, long)]
+    quiet: bool,
+    /// When to color output: auto, always or never
+    #[structopt(long, default_value="auto")]
+    color: ColorWhen,
+    /// What to do when a repo's pre-rebase hook rejects the rebase: abort the whole run, or skip that repo
+    #[structopt(long, default_value="abort")]
+    pre_rebase_reject: PreRebasePolicy,
+    /// Run the pre-commit and commit-msg hooks of each repo on the commits it rewrites
+    #[structopt(long)]
+    verify: bool,
+    /// Don't run the pre-rebase, pre-commit or commit-msg hooks, even with --verify set in config
+    #[structopt(long)]
+    no_verify: bool,
+    /// Language for prompts and messages, e.g. de. Defaults to the locale from LC_ALL, LC_MESSAGES or LANG
+    #[structopt(long)]
+    lang: Option<String>,
+    /// Ring the terminal bell whenever the run stops to wait for input
+    #[structopt(long)]
+    bell: bool,
+    /// Shell command to run whenever the run stops to wait for input, with the kind of prompt in
+    /// SUB_REBASE_PROMPT (e.g. a desktop notification)
+    #[structopt(long)]
+    notify_command: Option<String>,
+    /// Record what each commit was rewritten to as a note in refs/notes/sub-rebase of its repo, and follow
+    /// those notes when a gitlink points at a commit an earlier run rewrote
+    #[structopt(long)]
+    map_notes: bool,
+    /// Make git replace refs from each rewritten commit to its new version, so old ids in build caches,
+    /// CI and scripts keep resolving (to the new commits)
+    #[structopt(long)]
+    replace_refs: bool,
+    /// Check the commits about to be rewritten in every repo first, and stop if any have problems
+    #[structopt(long)]
+    lint: bool,
+    /// Longest subject --lint allows
+    #[structopt(long, default_value="72")]
+    lint_subject_length: usize,
+    /// Make --lint require a Signed-off-by line from each commit's committer
+    #[structopt(long)]
+    lint_signoff: bool,
+    /// Files --lint doesn't allow commits to add or change, as pathspecs like *.pem
+    #[structopt(long, number_of_values=1)]
+    lint_forbid: Vec<String>,
+    /// Largest file (in bytes) --lint allows commits to add or change
+    #[structopt(long)]
+    lint_max_blob: Option<u64>,
+    /// Stop before a rewritten commit brings in a file over this many bytes that wasn't in the
+    /// original commit, usually from a bad conflict resolution
+    #[structopt(long)]
+    max_blob: Option<u64>,
+    /// Name for the backup branch made in each repo, with {branch} and {run} filled in
+    #[structopt(long, default_value="backup/{branch}_{run}")]
+    backup_name: String,
+    /// Put backup branches under this prefix instead of backup/, e.g. wip/backup/. --backup-name wins
+    /// if both are set
+    #[structopt(long)]
+    backup_prefix: Option<String>,
+    /// Don't make backup branches, e.g. in a throwaway clone
+    #[structopt(long)]
+    no_backup: bool,
+    /// Offer to move tags that point at rewritten commits to their new versions
+    #[structopt(long)]
+    retag: bool,
+    /// Move other local branches that point at rewritten commits to the new commits, for stacked
+    /// branches. Defaults to each repo's rebase.updateRefs
+    #[structopt(long)]
+    update_refs: bool,
+    #[structopt(long)]
+    no_update_refs: bool,
+    /// Stash local changes in each repo before the run and apply them again afterwards. Defaults to
+    /// each repo's rebase.autoStash
+    #[structopt(long)]
+    autostash: bool,
+    #[structopt(long)]
+    no_autostash: bool,
+    /// Warn about fixup!/squash! commits, which get picked as they are. Defaults to each repo's
+    /// rebase.autoSquash
+    #[structopt(long)]
+    autosquash: bool,
+    #[structopt(long)]
+    no_autosquash: bool,
+    /// What to do when a commit gets dropped (skipped at a conflict): ignore, warn, or error to refuse.
+    /// Defaults to each repo's rebase.missingCommitsCheck
+    #[structopt(long)]
+    missing_commits_check: Option<MissingCommitsCheck>,
+    /// After a successful run, write every repo's old -> new commit map as JSON to this file (- for stdout).
+    /// {run} in the file name is replaced with the run id
+    #[structopt(long, parse(from_os_str))]
+    map_out: Option<PathBuf>,
+    /// Label for this run, e.g. onto-1.48-rc2. It starts the run id, so backups, notes and map files
+    /// can be found by it later
+    #[structopt(long)]
+    name: Option<String>,
+    /// Fetch the target's remote first, in the superproject and every submodule, so the target is
+    /// where it is on the remote now
+    #[structopt(long)]
+    fetch: bool,
+    /// Clone every submodule that isn't yet and fetch every remote of every repo first, several at once,
+    /// so the run doesn't wait on the network halfway through
+    #[structopt(long)]
+    fetch_all: bool,
+    /// How many repos --fetch-all works on at once (the number of CPUs by default)
+    #[structopt(long)]
+    jobs: Option<usize>,
+    /// Once the run succeeds, push every branch with rewritten commits to its upstream with
+    /// --force-with-lease, after showing what would be pushed
+    #[structopt(long)]
+    push: bool,
+    /// In a shallow clone, fetch more history without asking when the merge base is further back than it goes
+    #[structopt(long)]
+    deepen: bool,
+    /// In a bare repo, keep the temporary worktree the run happens in instead of removing it, e.g. to
+    /// push the submodules' rewritten commits from it
+    #[structopt(long)]
+    keep_worktree: bool,
+    /// Check out with `git reset --hard` and this many of git's parallel checkout workers (0 for one per
+    /// CPU) instead of libgit2, which writes one file at a time
+    #[structopt(long)]
+    checkout_workers: Option<usize>,
+    /// Seconds a checkout, submodule update or git command can run before it's reported as stuck, 0 to never
+    #[structopt(long, default_value="120")]
+    stall_timeout: u64,
+}
+
+#[derive(StructOpt)]
+enum SubCommand {
+    /// Manage the backup branches made by previous runs
+    Backups(backups::BackupsCommand),
+    /// Inspect the settings a run would use
+    Config(settings::ConfigCommand),
+    /// List the conflicts in every repo of a paused run, and what to do about them
+    Conflicts,
+    /// Put every repo back the way it was before the last run, from its backups
+    Undo,
+    /// Compare every repo's commits from before a run (the last one by default) with what they are now,
+    /// with git range-diff
+    RangeDiff {
+        #[structopt(name="run-id")]
+        run_id: Option<String>,
+    },
+    /// Print a completion script for bash, zsh or fish, e.g. `source <(git-sub-rebase completions bash)`
+    Completions(completions::CompletionsCommand),
+    /// Show help on a topic: state, recovery, conflicts, submodule-policies, settings, exit-codes or porcelain
+    Help(help::HelpCommand),
+}
+
+// Named choices for an option, as (name, value, description). Shared by parsing and the help topics
+// so they can't drift apart
+type Choices<T> = &'static [(&'static str, T, &'static str)];
+
+fn parse_choice<T: Copy>(choices: Choices<T>, what: &str, s: &str) -> Result<T> {
+    choices.iter().find(|(name, _, _)| *name == s).map(|(_, value, _)| *value).ok_or_else(|| {
+        anyhow!("Unknown {} {}, expected one of {}", what, s, choices.iter().map(|(name, _, _)| *name).collect::<Vec<_>>().join(", "))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnresolvedHeadPolicy {
+    Prompt,
+    Detach,
+    Branch,
+    Abort,
+}
+
+impl UnresolvedHeadPolicy {
+    const CHOICES: Choices<Self> = &[
+        ("prompt", UnresolvedHeadPolicy::Prompt, "Ask you to check out a branch in the submodule and press ENTER"),
+        ("detach", UnresolvedHeadPolicy::Detach, "Detach the submodule HEAD at the commit the parent expects"),
+        ("branch", UnresolvedHeadPolicy::Branch, "Create a recovered/<name>_<time> branch at the commit the parent expects and check it out"),
+        ("abort", UnresolvedHeadPolicy::Abort, "Give up and revert the whole rebase"),
+    ];
+}
+
+impl FromStr for UnresolvedHeadPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_choice(Self::CHOICES, "policy", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StrategyOption {
+    Ours,
+    Theirs,
+    Union,
+    IgnoreSpaceChange,
+    IgnoreAllSpace,
+    IgnoreSpaceAtEol,
+    Patience,
+    Minimal,
+}
+
+impl StrategyOption {
+    const CHOICES: Choices<Self> = &[
+        ("ours", StrategyOption::Ours, "Resolve conflicting hunks in favor of the upstream side"),
+        ("theirs", StrategyOption::Theirs, "Resolve conflicting hunks in favor of the commit being picked"),
+        ("union", StrategyOption::Union, "Keep both sides of conflicting hunks"),
+        ("ignore-space-change", StrategyOption::IgnoreSpaceChange, "Ignore changes in amount of whitespace"),
+        ("ignore-all-space", StrategyOption::IgnoreAllSpace, "Ignore all whitespace"),
+        ("ignore-space-at-eol", StrategyOption::IgnoreSpaceAtEol, "Ignore whitespace at the end of lines"),
+        ("patience", StrategyOption::Patience, "Use the patience diff algorithm"),
+        ("minimal", StrategyOption::Minimal, "Spend extra time finding the smallest diff"),
+    ];
+}
+
+impl FromStr for StrategyOption {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_choice(Self::CHOICES, "strategy option", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OnConflictPolicy {
+    Prompt,
+    Abort,
+}
+
+impl OnConflictPolicy {
+    const CHOICES: Choices<Self> = &[
+        ("prompt", OnConflictPolicy::Prompt, "Stop and ask what to do"),
+        ("abort", OnConflictPolicy::Abort, "Roll every repo back and exit with code 3"),
+    ];
+}
+
+impl FromStr for OnConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_choice(Self::CHOICES, "policy", s)
+    }
+}
+
+// Branches made in every repo to keep track of a rebase in progress. Any of these lying around means
+// a previous run died partway through
+const STATE_BRANCHES: &[(&str, &str)] = &[
+    ("multi_rebase_old", "the previous head commit, in case of failure"),
+    ("multi_rebase_cur", "the rebase-head with all rebased commits so far"),
+    ("multi_rebase_track", "the commit on the pre-rebase branch that we are rebasing next"),
+    ("multi_rebase_new", "the head branch used during the rebase"),
+];
+
+// Choices at the conflict prompt as (key, name, description). Names start with their key
+const CONFLICT_ACTIONS: &[(char, &str, &str)] = &[
+    ('c', "continue", "Commit what's in the index and carry on (the default)"),
+    ('s', "skip this commit", "Drop the conflicting commit and carry on with the next"),
+    ('a', "abort whole rebase", "Revert every repo to where it was before the run"),
+    ('m', "mergetool", "Run your configured mergetool on the conflicted files, then continue"),
+    ('o', "open shell", "Open $SHELL in the conflicted repo, returning to the prompt when it exits"),
+    ('d', "diff", "Run git diff in the conflicted repo"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PreRebasePolicy {
+    Abort,
+    Skip,
+}
+
+impl PreRebasePolicy {
+    const CHOICES: Choices<Self> = &[
+        ("abort", PreRebasePolicy::Abort, "Revert the whole run"),
+        ("skip", PreRebasePolicy::Skip, "Leave that repo on its old commits, like --exclude-submodule"),
+    ];
+}
+
+impl FromStr for PreRebasePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_choice(Self::CHOICES, "policy", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MissingCommitsCheck {
+    Ignore,
+    Warn,
+    Error,
+}
+
+impl MissingCommitsCheck {
+    const CHOICES: Choices<Self> = &[
+        ("ignore", MissingCommitsCheck::Ignore, "Drop commits without comment"),
+        ("warn", MissingCommitsCheck::Warn, "Drop commits, but warn about each one"),
+        ("error", MissingCommitsCheck::Error, "Refuse to drop commits, asking again instead"),
+    ];
+
+    // --missing-commits-check, or else the repo's rebase.missingCommitsCheck
+    fn for_repo(config: &Config, repo: &Repository) -> MissingCommitsCheck {
+        config.missing_commits_check.unwrap_or_else(|| {
+            repo.config().and_then(|c| c.get_string("rebase.missingCommitsCheck")).ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(MissingCommitsCheck::Ignore)
+        })
+    }
+}
+
+impl FromStr for MissingCommitsCheck {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_choice(Self::CHOICES, "check", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColorWhen {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorWhen {
+    const CHOICES: Choices<Self> = &[
+        ("auto", ColorWhen::Auto, "Color output going to a terminal, unless NO_COLOR is set or --plain is on"),
+        ("always", ColorWhen::Always, "Color output wherever it goes"),
+        ("never", ColorWhen::Never, "No color"),
+    ];
+}
+
+impl FromStr for ColorWhen {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_choice(Self::CHOICES, "color setting", s)
+    }
+}
+
+// Take targets from stdin for --stdin-refs. Stops at a blank line, so prompts can still be answered
+// on stdin after the targets
+fn read_stdin_refs(config: &mut Config, repo: &Repository) -> Result<()> {
+    let workdir = repo.workdir().expect("Has workdir").to_path_buf();
+    let stdin = std::io::stdin();
+    loop {
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(ref_), None, None) => {
+                if let Some(given) = &config.ref_ {
+                    return Err(anyhow!("Two refs given for the superproject, {} and {}", given, ref_));
+                }
+                config.ref_ = Some(ref_.to_string());
+            }
+            (Some(path), Some(ref_), None) => config.stdin_pins.push((workdir.join(path.trim_end_matches('/')), ref_.to_string())),
+            _ => return Err(anyhow!("Bad target line, expected [<submodule path>] <ref>: {}", line.trim())),
+        }
+    }
+    Ok(())
+}
+
+// Say what's about to happen in each repo: where it branched off, where it's going, and which commits
+// get rewritten. Gives the number of commits to pick in all of them
+fn show_plan(config: &Config, repo: &Repository, target: &Commit, resolver: &dyn TargetResolver) -> Result<usize> {
+    say!("{}", tr!("Plan:"));
+    recurse_subs(repo, target, resolver, &|repo: &Repository, _submodule, target: &Commit, path, child_results: HashMap<String, usize>| -> Result<usize> {
+        let named_path = sub_path_to_string(path);
+        let child_total = child_results.values().sum::<usize>();
+        if is_excluded(config, path) {
+            say!("[{}] Excluded, stays where it is", named_path);
+            return Ok(child_total);
+        }
+        let head = repo.head()?.peel_to_commit()?.id();
+        let base = cache::merge_base(repo, head, target.id())?;
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        walk.push(head)?;
+        walk.hide(base)?;
+        let commits = walk.collect::<Result<Vec<_>, _>>()?;
+        output::event(&["plan", &named_path, &base.to_string(), &target.id().to_string(), &commits.len().to_string()]);
+
+        if base == target.id() {
+            say!("[{}] Already on top of {}, {} commits kept as they are", named_path, target.id(), commits.len());
+            return Ok(child_total);
+        }
+        say!("[{}] {} commits from {} {} {}", named_path, commits.len(), base, output::arrow(), target.id());
+        for id in commits.iter().take(PLAN_COMMITS_SHOWN) {
+            say!("[{}]   {} {}", named_path, id, cache::summary(repo, *id)?);
+        }
+        if commits.len() > PLAN_COMMITS_SHOWN {
+            say!("[{}]   ...and {} more", named_path, commits.len() - PLAN_COMMITS_SHOWN);
+        }
+        Ok(child_total + commits.len())
+    })
+}
+
+// Make sure --stack names a chain of branches, each on top of the one before, ending at HEAD
+fn check_stack(repo: &Repository, stack: &[String]) -> Result<()> {
+    let mut below: Option<(&String, Oid)> = None;
+    for name in stack {
+        let commit = repo.find_branch(name, BranchType::Local)
+            .map_err(|e| anyhow!("Cannot find stacked branch {}: {}", name, e))?
+            .get().peel_to_commit()?.id();
+        if let Some((below_name, below_commit)) = below {
+            if below_commit != commit && !repo.graph_descendant_of(commit, below_commit)? {
+                return Err(anyhow!("Stacked branch {} isn't on top of {}", name, below_name));
+            }
+        }
+        below = Some((name, commit));
+    }
+    let top = stack.last().expect("Stack isn't empty");
+    if repo.head()?.shorthand() != Some(top.as_str()) {
+        return Err(anyhow!("Check out the top of the stack ({}) first", top));
+    }
+    Ok(())
+}
+
+// A boolean rebase.* setting from a repo's git config, unless --<option> or --no-<option> says otherwise
+fn rebase_config_flag(repo: &Repository, key: &str, on: bool, off: bool) -> bool {
+    if off {
+        return false;
+    }
+    on || repo.config().and_then(|c| c.get_bool(key)).unwrap_or(false)
+}
+
+// Failures that get their own exit code, so wrappers don't have to parse output
+// Anything else is a generic error and exits with 1
+#[derive(Debug)]
+enum Failure {
+    // Every repo already contains its target
+    NothingToRebase,
+    // The rebase was given up on because of a conflict, in the repo at this path
+    ConflictAborted(String),
+    DirtyWorkingCopy,
+    Interrupted,
+    // --lint found problems in commits that were about to be rewritten
+    LintFailed,
+    // A prompt came up with nobody to answer it, this kind of prompt
+    NoInput(String),
+}
+
+impl Failure {
+    // One of each, for listing exit codes
+    fn all() -> Vec<Failure> {
+        vec![Failure::NothingToRebase, Failure::ConflictAborted("<path>".to_string()), Failure::DirtyWorkingCopy, Failure::Interrupted, Failure::LintFailed, Failure::NoInput("<prompt>".to_string())]
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::NothingToRebase => 2,
+            Failure::ConflictAborted(_) => 3,
+            Failure::DirtyWorkingCopy => 4,
+            Failure::Interrupted => 5,
+            Failure::LintFailed => 6,
+            Failure::NoInput(_) => 7,
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::NothingToRebase => write!(f, "Nothing to rebase"),
+            Failure::ConflictAborted(named_path) => write!(f, "[{}] Rebase aborted due to conflict", named_path),
+            Failure::DirtyWorkingCopy => write!(f, "Dirty working copy"),
+            Failure::Interrupted => write!(f, "Interrupted"),
+            Failure::LintFailed => write!(f, "Lint failed"),
+            Failure::NoInput(kind) => write!(f, "Nothing to answer prompt {} (stdin isn't a terminal or was closed), pass --yes to take the defaults", kind),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+// Name and email given on the command line, like git's --author
+#[derive(Debug, Clone)]
+struct Identity {
+    name: String,
+    email: String,
+}
+
+impl FromStr for Identity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let open = s.find('<').ok_or(anyhow!("Expected \"Name <email>\", got {}", s))?;
+        let close = s.rfind('>').filter(|close| *close > open).ok_or(anyhow!("Expected \"Name <email>\", got {}", s))?;
+        Ok(Identity {
+            name: s[..open].trim().to_string(),
+            email: s[open + 1..close].trim().to_string(),
+        })
+    }
+}
+
+// --checkout <path>=<branch>, the branch to check out in a submodule with no branch checked out
+#[derive(Debug, Clone)]
+struct CheckoutSpec {
+    // As printed in [brackets], *root* for the superproject
+    path: String,
+    branch: String,
+}
+
+impl FromStr for CheckoutSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let equals = s.find('=').ok_or(anyhow!("Expected <path>=<branch>, got {}", s))?;
+        Ok(CheckoutSpec {
+            path: s[..equals].trim().trim_end_matches('/').to_string(),
+            branch: s[equals + 1..].trim().to_string(),
+        })
+    }
+}
+
+// (local, remote) names for checking out a branch named with --checkout: a local branch, a remote
+// branch like origin/foo, or foo on any remote
+fn checkout_spec_branch(repo: &Repository, branch: &String) -> Result<(String, String)> {
+    if repo.find_branch(branch, BranchType::Local).is_ok() {
+        return Ok((branch.clone(), branch.clone()));
+    }
+    if repo.find_branch(branch, BranchType::Remote).is_ok() {
+        return Ok((branch_name_to_canonical(repo, branch)?, branch.clone()));
+    }
+    for remote in repo.remotes()?.iter().flatten() {
+        let remote_branch = format!("{}/{}", remote, branch);
+        if repo.find_branch(&remote_branch, BranchType::Remote).is_ok() {
+            return Ok((branch.clone(), remote_branch));
+        }
+    }
+    Err(anyhow!("No branch {} to check out", branch))
 }
-static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-fn read_stdin() -> Result<String> {
-    INTERRUPTED.store(false, atomic::Ordering::SeqCst);
-    let mut choice = String::new();
-    stdin().read_line(&mut choice)?;
-    match INTERRUPTED.load(atomic::Ordering::SeqCst) {
-        false => Ok(choice),
-        _ => Err(anyhow!("Interrupted"))
+// Give a branch the run checked out an upstream, so git status and git push work on it afterwards like
+// they would after `git checkout`. One made from a remote branch tracks it, a local one picked by name
+// tracks the branch of the same name if just one remote has it. An upstream it already had stays
+fn track_upstream(repo: &Repository, named_path: &str, local: &str, remote: &str) -> Result<()> {
+    let mut branch = repo.find_branch(local, BranchType::Local)?;
+    if branch.upstream().is_ok() {
+        return Ok(());
+    }
+    let upstream = if local != remote {
+        Some(remote.to_string()).filter(|remote| repo.find_branch(remote, BranchType::Remote).is_ok())
+    } else {
+        let candidates = repo.remotes()?.iter().flatten()
+            .map(|name| format!("{}/{}", name, local))
+            .filter(|remote| repo.find_branch(remote, BranchType::Remote).is_ok())
+            .collect::<Vec<_>>();
+        if candidates.len() == 1 { candidates.into_iter().next() } else { None }
+    };
+    if let Some(upstream) = upstream {
+        branch.set_upstream(Some(&upstream))?;
+        say!("[{}] {} now tracks {}", named_path, local, upstream);
     }
+    Ok(())
+}
+
+// Exactly what HEAD was, so it can be put back byte-for-byte. Going through set_head() would detach
+// a HEAD that points at something other than a branch, and can't point at an unborn branch
+#[derive(Debug, Clone, PartialEq)]
+enum HeadState {
+    // Symbolic ref to this, which might not exist yet
+    Symbolic(String),
+    Detached(Oid),
+}
+
+impl HeadState {
+    fn capture(repo: &Repository) -> Result<HeadState> {
+        let head = repo.find_reference("HEAD")?;
+        match head.symbolic_target() {
+            Some(target) => Ok(HeadState::Symbolic(target.to_string())),
+            None => Ok(HeadState::Detached(head.target().ok_or(anyhow!("HEAD is neither symbolic nor direct"))?)),
+        }
+    }
+
+    fn restore(&self, repo: &Repository) -> Result<()> {
+        match self {
+            HeadState::Symbolic(target) => {
+                repo.reference_symbolic("HEAD", target, true, "sub-rebase: restore HEAD")?;
+            }
+            HeadState::Detached(id) => detach_head(repo, *id, "sub-rebase: restore HEAD")?,
+        }
+        Ok(())
+    }
+}
+
+// set_head() and reset() write libgit2's own reflog messages, which all look alike. These say what
+// the run was doing, so the reflog is some use for recovering by hand
+
+fn set_head(repo: &Repository, refname: &str, message: &str) -> Result<(), git2::Error> {
+    debug!("{}: set HEAD to {} ({})", repo.path().display(), refname, message);
+    repo.reference_symbolic("HEAD", refname, true, message)?;
+    Ok(())
+}
+
+fn detach_head(repo: &Repository, id: Oid, message: &str) -> Result<(), git2::Error> {
+    debug!("{}: detach HEAD at {} ({})", repo.path().display(), id, message);
+    repo.reference("HEAD", id, true, message)?;
+    Ok(())
+}
+
+// reset --hard, the same steps as libgit2's
+fn reset_hard(repo: &Repository, target: &Object, message: &str) -> Result<(), git2::Error> {
+    let commit = target.peel_to_commit()?;
+    let head = repo.find_reference("HEAD")?;
+    if is_checked_out(repo, &commit) {
+        // Nothing to check out, which on a big working copy is most of the time saved
+        debug!("{}: reset --hard to {}, already checked out ({})", repo.path().display(), commit.id(), message);
+        match head.symbolic_target() {
+            Some(branch) => repo.reference(branch, commit.id(), true, message)?,
+            None => repo.reference("HEAD", commit.id(), true, message)?,
+        };
+        return Ok(());
+    }
+    debug!("{}: reset --hard to {} ({})", repo.path().display(), commit.id(), message);
+    if let Some(reset) = checkout::git_reset_hard(repo, &commit, message) {
+        return reset;
+    }
+    repo.checkout_tree(commit.as_object(), Some(&mut checkout::builder(repo, &commit.tree()?)))?;
+    match head.symbolic_target() {
+        Some(branch) => repo.reference(branch, commit.id(), true, message)?,
+        None => repo.reference("HEAD", commit.id(), true, message)?,
+    };
+    let mut index = repo.index()?;
+    index.read_tree(&commit.tree()?)?;
+    index.write()?;
+    sparse::reapply(repo);
+    Ok(())
+}
+
+// Whether the index already has a commit's tree and the working copy matches it, so a reset --hard to
+// it would only move the ref
+fn is_checked_out(repo: &Repository, commit: &Commit) -> bool {
+    let mut index = match repo.index() {
+        Ok(index) => index,
+        Err(_) => return false,
+    };
+    // Conflicts (and other runs' changes) only show up once it's read again
+    if index.read(false).is_err() || index.has_conflicts() {
+        return false;
+    }
+    let tree = match commit.tree() {
+        Ok(tree) => tree,
+        Err(_) => return false,
+    };
+    let staged = repo.diff_tree_to_index(Some(&tree), Some(&index), None).map(|diff| diff.deltas().len());
+    staged.is_ok_and(|staged| staged == 0) && sparse::changed_files(repo, None).is_ok_and(|changed| changed == 0)
+}
+
+impl std::fmt::Display for HeadState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeadState::Symbolic(target) => write!(f, "{}", target),
+            HeadState::Detached(id) => write!(f, "{} (detached)", id),
+        }
+    }
+}
+
+// --backup-name's default, for --backup-prefix to replace
+const DEFAULT_BACKUP_NAME: &str = "backup/{branch}_{run}";
+
+// Commits listed per repo in the plan, the rest are just counted
+const PLAN_COMMITS_SHOWN: usize = 10;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Where each submodule recurse_subs has been into is, from the superproject, by its path of submodule
+// names. Names and paths are often the same, but --exclude-submodule takes paths
+static WORKDIR_PATHS: Mutex<Option<HashMap<Vec<String>, String>>> = Mutex::new(None);
+
+fn sub_workdir_path(path: &[String]) -> String {
+    let known = WORKDIR_PATHS.lock().expect("Workdir paths lock poisoned").as_ref().and_then(|paths| paths.get(path).cloned());
+    known.unwrap_or_else(|| path.join("/"))
 }
 
 fn sub_path_to_string(path: &Vec<String>) -> String {
@@ -61,38 +868,50 @@ fn branch_name_to_canonical(repo: &Repository, name: &String) -> Result<String>
 
 // Find which version of a submodule is checked out in a parent's tree
 fn submodule_at_tree(submodule: &Submodule, tree: &Tree) -> Result<Option<Oid>> {
-    let mut sub_object = None;
-
-    // Find target commit in submodule for target in parent
-    tree.walk(TreeWalkMode::PreOrder, |path, entry| {
-        (|| -> Result<TreeWalkResult> {
-            let combined = path.to_owned() + entry.name().ok_or(Error::msg("unknown name for object"))?;
-            match entry.kind() {
-                Some(ObjectType::Commit) => {
-
-                    if <String as AsRef<OsStr>>::as_ref(&combined) == submodule.path().as_os_str() {
-                        sub_object = Some(entry.id());
-                    }
-                }
-                _ => {}
-            }
+    match tree.get_path(submodule.path()) {
+        Ok(entry) if entry.kind() == Some(ObjectType::Commit) => Ok(Some(entry.id())),
+        Ok(_) => Ok(None),
+        Err(e) if e.code() == NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
 
-            Ok(TreeWalkResult::Ok)
-        })().unwrap_or_else(|err| {
-            eprintln!("{}", err);
-            TreeWalkResult::Abort
-        })
-    })?;
+// Submodules listed in .gitmodules aren't always gitlinks: repos that moved to git-subtree or vendored
+// a snapshot often keep the old entry around. Says what's there instead, or None if it really is a gitlink.
+fn non_gitlink_kind(submodule: &Submodule, tree: &Tree) -> Option<&'static str> {
+    match tree.get_path(submodule.path()).ok()?.kind() {
+        Some(ObjectType::Tree) => Some("directory"),
+        Some(ObjectType::Blob) => Some("file"),
+        _ => None,
+    }
+}
 
-    Ok(sub_object)
+// How a traversal gets at the submodules it visits
+#[derive(Clone, Copy, PartialEq)]
+enum SubWalk {
+    // Getting a rebase ready, the first pass over the tree: like Rebase, and shallow clones fetch the
+    // targets they're missing too, so every later pass finds them without going to the network
+    Prepare,
+    // Submodules that don't open get initialized, and cloned if need be
+    Rebase,
+    // Only submodules that are already there, for commands that look without touching anything.
+    // Nothing gets cloned or fetched, missing ones are left out, and ones without their target are
+    // looked through at their HEAD
+    ReadOnly,
 }
 
 // Postorder traverse submodules in a repository and apply a function to them, collecting results
 // Parent repo will be provided a hashmap of the return values of the calls on its child submodules
-fn recurse_subs<F, T>(repo: &Repository, target: &Commit, op: &F) -> Result<T>
+fn recurse_subs<F, T>(repo: &Repository, target: &Commit, resolver: &dyn TargetResolver, op: &F) -> Result<T>
+    where F: Fn(&Repository, Option<&Submodule>, &Commit, &Vec<String>, HashMap<String, T>) -> Result<T>
+{
+    recurse_subs_in(SubWalk::Rebase, repo, target, resolver, op)
+}
+
+fn recurse_subs_in<F, T>(walk: SubWalk, repo: &Repository, target: &Commit, resolver: &dyn TargetResolver, op: &F) -> Result<T>
     where F: Fn(&Repository, Option<&Submodule>, &Commit, &Vec<String>, HashMap<String, T>) -> Result<T>
 {
-    fn recurse<F, T>(repo: &Repository, submodule: Option<&Submodule>, target: &Commit, path: &mut Vec<String>, op: &F) -> Result<T>
+    fn recurse<F, T>(walk: SubWalk, repo: &Repository, submodule: Option<&Submodule>, target: &Commit, path: &mut Vec<String>, resolver: &dyn TargetResolver, op: &F) -> Result<T>
         where F: Fn(&Repository, Option<&Submodule>, &Commit, &Vec<String>, HashMap<String, T>) -> Result<T>
     {
         let mut results = HashMap::new();
@@ -100,61 +919,73 @@ fn recurse_subs<F, T>(repo: &Repository, target: &Commit, op: &F) -> Result<T>
         // Iterate submodules
         let submodules = repo.submodules()?;
         for mut sub in submodules {
-            let sub_repo = if let Ok(sub_repo) = sub.open() {
+            if let Some(kind) = non_gitlink_kind(&sub, &target.tree()?) {
+                say!("Submodule {} is a {}, not a gitlink. Ignoring it.", sub.name().expect("Submodule neads name"), kind);
+                continue;
+            }
+            if resolver.skip(repo, &sub, target)? {
+                continue;
+            }
+
+            let sub_repo = if let Ok(sub_repo) = cache::open_submodule(repo, &sub) {
                 sub_repo
+            } else if walk == SubWalk::ReadOnly {
+                debug!("Submodule {} isn't checked out, leaving it out", sub.name().expect("Submodule neads name"));
+                continue;
             } else {
-                println!("Submodule {} didn't load, trying to update...", sub.name().expect("Submodule neads name"));
-                // Sometimes the sub can be empty and still exist, so nuke it if that happens
-                if sub.path().exists() {
-                    if !sub.path().read_dir()?.any(|_| true) {
-                        // Whoops?
-                        let cmd = Command::new("git")
-                            .arg("submodule")
-                            .arg("update")
-                            .arg("--init")
-                            .arg("--recursive")
-                            .arg(sub.name().expect("Submodule should have name"))
-                            .current_dir(repo.workdir().expect("Has workdir"))
-                            .output()?;
-                        eprintln!("{}", String::from_utf8(cmd.stdout)?);
-                        eprintln!("{}", String::from_utf8(cmd.stderr)?);
-                    }
-                    sub.update(true, None)?;
-                } else {
-                    sub.update(true, None)?;
-                }
+                say!("Submodule {} didn't load, trying to update...", sub.name().expect("Submodule neads name"));
+                temp_clones::record(repo, sub.name().expect("Submodule neads name"), &sub.path().to_string_lossy());
+                let sub_name = sub.name().expect("Submodule should have name").to_string();
+                update_submodule(repo, &mut sub, &sub_name)?;
 
-                if let Ok(sub_repo) = sub.open() {
+                if let Ok(sub_repo) = cache::open_submodule(repo, &sub) {
                     sub_repo
                 } else {
-                    println!("Submodule {} didn't load, was it deleted?", sub.name().expect("Submodule neads name"));
+                    say!("Submodule {} didn't load, was it deleted?", sub.name().expect("Submodule neads name"));
 
                     // Why did it fail to open? Could be deleted on the new commit
                     match submodule_at_tree(&sub, &target.tree()?) {
                         Ok(Some(_)) => {
-                            println!("... No it wasn't? Ignoring it.");
+                            say!("... No it wasn't? Ignoring it.");
                             continue;
                         },
                         Ok(None) => {
-                            println!("... Yes it was");
+                            say!("... Yes it was");
                             continue;
                         },
                         e @ Err(_) => {
-                            println!("... Git has no idea. Aborting...");
+                            say!("... Git has no idea. Aborting...");
                             return e.map(|_| panic!())
                         },
                     }
                 }
             };
 
-            let sub_object = submodule_at_tree(&sub, &target.tree()?)?;
+            let sub_object = resolver.resolve(repo, &sub, target)?;
             if let Some(sub_object) = sub_object {
-                let sub_target = sub_repo.find_commit(sub_object)?;
-
                 let sub_name: String = sub.name().expect("Submodule needs name").into();
 
+                let parent_path = sub_workdir_path(path);
                 path.push(sub_name.clone());
-                results.insert(sub_name, recurse(&sub_repo, Some(&sub), &sub_target, path, op)?);
+                let workdir_path = if parent_path.is_empty() { sub.path().to_string_lossy().to_string() } else { format!("{}/{}", parent_path, sub.path().display()) };
+                WORKDIR_PATHS.lock().expect("Workdir paths lock poisoned").get_or_insert_with(HashMap::new).entry(path.clone()).or_insert(workdir_path);
+                if walk == SubWalk::Prepare {
+                    shallow::fetch_target(&sub_path_to_string(path), &sub_repo, sub_object)?;
+                }
+                let sub_target = match sub_repo.find_commit(sub_object) {
+                    Ok(sub_target) => sub_target,
+                    // What's checked out is as good as anything to look through
+                    Err(_) if walk == SubWalk::ReadOnly => match sub_repo.head().and_then(|head| head.peel_to_commit()) {
+                        Ok(head) => head,
+                        Err(_) => {
+                            debug!("Submodule {} has neither {} nor a HEAD, leaving it out", sub_name, sub_object);
+                            path.remove(path.len() - 1);
+                            continue;
+                        }
+                    },
+                    Err(e) => return Err(e.into()),
+                };
+                results.insert(sub_name, recurse(walk, &sub_repo, Some(&sub), &sub_target, path, resolver, op)?);
                 path.remove(path.len() - 1);
             }
         }
@@ -162,273 +993,722 @@ fn recurse_subs<F, T>(repo: &Repository, target: &Commit, op: &F) -> Result<T>
         Ok(op(repo, submodule, target, path, results)?)
     }
 
-    recurse(repo, None, target, &mut vec![], op)
+    recurse(walk, repo, None, target, &mut vec![], resolver, op)
 }
 
-fn update_submodules(repo: &Repository, target: &Commit) -> Result<()> {
-    // Clean up submodules to point to real branches
-    let need_checkouts = recurse_subs(&repo, &target, &|repo: &Repository, _submodule, _target, _path, child_results: HashMap<String, bool>| -> Result<bool> {
-        // Only for repos with no checked out branch
-        let head = repo.head()?;
-        if head.name().expect("Ref expected name") == "HEAD" || head.name().expect("Ref expected name").contains("/multi_rebase_") {
-            Ok(true)
+// A crashed run can leave repos checked out on multi_rebase_new, which the branch heuristics would
+// treat like a detached HEAD. Offer to put it back where it was, or keep what was rebased
+fn recover_crashed_head(repo: &Repository, named_path: &str) -> Result<()> {
+    let head = repo.find_reference("HEAD")?;
+    match head.symbolic_target() {
+        Some(target) if target.starts_with("refs/heads/multi_rebase_") => {}
+        _ => return Ok(()),
+    }
+    let current = repo.head()?.peel_to_commit()?.id();
+    let old = match repo.find_branch("multi_rebase_old", BranchType::Local) {
+        Ok(old) => old.get().peel_to_commit()?.id(),
+        Err(_) => {
+            warn!("[{}] HEAD is on {} from an old run, but multi_rebase_old is gone", named_path, head.symbolic_target().unwrap_or(""));
+            return Ok(());
+        }
+    };
+    let branch = backups::protected_ref(repo, old)?;
+
+    warn!("[{}] HEAD is still on {} from a run that didn't finish", named_path, head.symbolic_target().unwrap_or(""));
+    let (ahead, behind) = repo.graph_ahead_behind(current, old)?;
+    warn!("[{}]   before the run: {} ({})", named_path, old, branch.as_deref().unwrap_or("detached"));
+    warn!("[{}]   rebased so far: {} ({} ahead, {} behind)", named_path, current, ahead, behind);
+    let mut walk = repo.revwalk()?;
+    walk.push(current)?;
+    walk.hide(old)?;
+    for id in walk.take(10) {
+        let commit = repo.find_commit(id?)?;
+        warn!("[{}]     {} {}", named_path, commit.id(), commit.summary().unwrap_or(""));
+    }
+    warn!("{}", tr!("[{}] (r)estore the old commit, or (a)dopt the rebased one [r]", named_path));
+    let choice = prompter::answer(&format!("crashed-head {}", named_path))?;
+    let (commit, message) = match choice.trim().chars().next().map(|c| c.to_ascii_lowercase()) {
+        Some('a') => (current, "sub-rebase: adopt commit from unfinished run"),
+        _ => (old, "sub-rebase: restore commit from before unfinished run"),
+    };
+    match &branch {
+        Some(branch) => {
+            repo.reference(branch, commit, true, message)?;
+            set_head(repo, branch, message)?;
+        }
+        None => detach_head(repo, commit, message)?,
+    }
+    reset_hard(repo, &repo.find_object(commit, None)?, message)?;
+    say!("[{}] Now on {} at {}", named_path, branch.as_deref().unwrap_or("detached HEAD"), commit);
+    Ok(())
+}
+
+// Ask which of a repo's candidate branches to check out, best kind first
+fn pick_branch(named_path: &str, checkout: &plan::Checkout) -> Result<plan::BranchChoice> {
+    if let Some(spec) = &checkout.spec {
+        return Ok(spec.clone());
+    }
+    if let Some(((local, remote), at_head)) = &checkout.configured {
+        if *at_head {
+            say!("[{}] Checking out {}, the submodule's configured branch", named_path, local);
         } else {
-            Ok(child_results.len() > 0 || child_results.into_iter().map(|(_sub, needs)| needs).all(|needs| needs))
+            say!("{}", tr!("Check out {} for {}? (not HEAD, will reset --hard) [Y/n]", local, named_path));
         }
-    })?;
+        if *at_head || prompter::confirm(&format!("confirm-branch {}", named_path), true)? {
+            return Ok((local.clone(), remote.clone()));
+        }
+    }
+
+    // Whether they're at HEAD, or checking one out means a reset --hard
+    let kinds = [(&checkout.tracked, true), (&checkout.local, true), (&checkout.remote, true), (&checkout.all_local, false)];
+    for (branches, at_head) in kinds.iter() {
+        if branches.len() == 1 {
+            if *at_head {
+                say!("{}", tr!("Check out {} for {}? (same as HEAD) [Y/n]", branches[0].0, named_path));
+            } else {
+                say!("{}", tr!("Check out {} for {}? (not HEAD, will reset --hard) [Y/n]", branches[0].0, named_path));
+            }
+            if !prompter::confirm(&format!("confirm-branch {}", named_path), true)? {
+                return Err(anyhow!("Cancelling..."));
+            }
+            return Ok(branches[0].clone());
+        } else if branches.len() > 1 {
+            say!("{}", tr!("Need to check out a branch for {}: [pick one]", named_path));
+            for (i, (local, _remote)) in branches.iter().enumerate() {
+                if *at_head {
+                    say!("{}", tr!("[{}] {} (same as HEAD)", i + 1, local));
+                } else {
+                    say!("{}", tr!("[{}] {} (not HEAD, will reset --hard)", i + 1, local));
+                }
+            }
+            let index = prompter::choose(&format!("pick-branch {}", named_path), branches.len())?;
+            return Ok(branches[index].clone());
+        }
+    }
+    Err(anyhow!("No branches found for {}", named_path))
+}
+
+fn update_submodules(config: &Config, repo: &Repository, target: &Commit, plan: &plan::Plan) -> Result<()> {
+    let resolver = resolver::for_config(config);
 
-    if need_checkouts {
+    // Clean up submodules to point to real branches
+    if plan.needs_checkouts() {
         // Find branch names we can checkout
-        println!("Some of your submodules have no checked out branch. This will make rebasing fail! Trying to fix...");
-
-        let checkout_names = recurse_subs(&repo, &target, &|repo: &Repository, _submodule, _target, path: &Vec<String>, child_results: HashMap<String, HashMap<Vec<String>, (String, String)>>| -> Result<HashMap<Vec<String>, (String, String)>> {
-            // Only for repos with no checked out branch
-            let head = repo.head()?;
-            let format_path = sub_path_to_string(path);
-            let head_name = head.name().expect("Ref expected name");
-
-            let mut results = HashMap::new();
-            for (_child, result) in child_results {
-                results.extend(result.into_iter());
-            }
-
-            if head_name == "HEAD" || head_name.contains("/multi_rebase_") {
-                // Find all local branches that are equal to HEAD of a named remote branch
-                let matching_tracked_branches = repo.branches(Some(BranchType::Remote))?.map(|b| -> Result<Option<String>> {
-                    let (branch, _branch_type) = b?;
-                    let name: String = branch.name()?.expect("Branch has name").into();
-                    if name.starts_with("backup/") || name.ends_with("HEAD") || name.contains("multi_rebase_") {
-                        return Ok(None);
-                    }
-                    if branch.into_reference().peel_to_commit()?.id() == head.peel_to_commit()?.id() {
-                        Ok(Some(name))
-                    } else {
-                        Ok(None)
-                    }
-                }).flat_map(|n| n).flat_map(|n| n).flat_map(|remote_branch_name| -> Result<Vec<(String, String)>> {
-                    let branches = repo.branches(Some(BranchType::Local))?.map(|b| -> Result<Option<(String, String)>> {
-                        let (branch, _type) = b?;
-                        if let (Some(name), Some(upstream_name)) = (branch.name()?, branch.upstream()?.name()?) {
-                            if upstream_name == remote_branch_name {
-                                Ok(Some((name.to_string(), remote_branch_name.clone())))
-                            } else {
-                                Ok(None)
-                            }
-                        } else {
-                            Ok(None)
-                        }
-                    }).flat_map(|n| n).flat_map(|n| n).collect::<Vec<_>>();
+        say!("Some of your submodules have no checked out branch. This will make rebasing fail! Trying to fix...");
 
-                    Ok(branches)
-                }).flat_map(|r| r).collect::<Vec<_>>();
+        let mut checkout_names = HashMap::new();
+        for repo_plan in &plan.repos {
+            if let Some(checkout) = &repo_plan.checkout {
+                checkout_names.insert(repo_plan.path.clone(), pick_branch(&sub_path_to_string(&repo_plan.path), checkout)?);
+            }
+        }
 
-                // Find all branches that are equal to HEAD
-                let matching_local_branches = repo.branches(Some(BranchType::Local))?.map(|b| -> Result<Option<(String, String)>> {
-                    let (branch, _branch_type) = b?;
-                    let name: String = branch.name()?.expect("Branch has name").into();
-                    if name.starts_with("backup/") || name.ends_with("HEAD") || name.contains("multi_rebase_") {
-                        return Ok(None);
-                    }
-                    if branch.into_reference().peel_to_commit()?.id() == head.peel_to_commit()?.id() {
-                        Ok(Some((name.clone(), name)))
-                    } else {
-                        Ok(None)
-                    }
-                }).flat_map(|n| n).flat_map(|n| n).collect::<Vec<_>>();
+        // Pretty print
+        let max_branch_len = checkout_names.iter().map(|(path, _)| sub_path_to_string(path).len()).max().unwrap_or(0);
+        let max_local_len = checkout_names.iter().map(|(_, (local, _))| local.len()).max().unwrap_or(0);
+        say!("\n");
+        say!("Checking out branches for submodules: ");
+        for (sub, (local, remote)) in checkout_names.iter().collect::<BTreeMap<_, _>>() {
+            say!("{}:{} {}{} {} {}", sub_path_to_string(sub), output::pad(sub_path_to_string(sub).len(), max_branch_len), local, output::pad(local.len(), max_local_len), output::arrow(), remote);
+        }
 
-                let matching_remote_branches = repo.branches(Some(BranchType::Remote))?.map(|b| -> Result<Option<(String, String)>> {
-                    let (branch, _branch_type) = b?;
-                    let name: String = branch.name()?.expect("Branch has name").into();
-                    if name.starts_with("backup/") || name.ends_with("HEAD") || name.contains("multi_rebase_") {
-                        return Ok(None);
-                    }
-                    if branch.into_reference().peel_to_commit()?.id() == head.peel_to_commit()?.id() {
-                        Ok(Some((branch_name_to_canonical(repo, &name)?, name)))
+        say!("\n");
+        say!("Running: ");
+        recurse_subs(repo, target, resolver.as_ref(), &|repo: &Repository, _submodule, _target, path, _child_results| -> Result<()> {
+            if let Some((local, remote)) = checkout_names.get(path) {
+                say!("{}:{} {}{} {} {}", sub_path_to_string(path), output::pad(sub_path_to_string(path).len(), max_branch_len), local, output::pad(local.len(), max_local_len), output::arrow(), remote);
+
+                backups::make_backup(config, repo, "HEAD", repo.head()?.peel_to_commit()?.id())?;
+
+                let current = repo.head()?.peel_to_commit()?;
+                let branch =
+                    if let Ok(branch) = repo.find_branch(local.as_str(), BranchType::Local) {
+                        branch
                     } else {
-                        Ok(None)
-                    }
-                }).flat_map(|n| n).flat_map(|n| n).collect::<Vec<_>>();
+                        // Create branch
+                        repo.branch(local.as_str(), &current, false)?
+                    };
+                track_upstream(repo, &sub_path_to_string(path), local, remote)?;
+                let message = format!("sub-rebase: check out {} to rebase", local);
+                set_head(repo, branch.into_reference().name().expect("Branch has name"), &message)?;
+                reset_hard(repo, current.as_object(), &message)?;
+            }
+            Ok(())
+        })?;
+    }
 
-                let all_local_branches = repo.branches(Some(BranchType::Local))?.map(|b| -> Result<Option<(String, String)>> {
-                    let (branch, _type) = b?;
-                    let name: String = branch.name()?.expect("Branch has name").into();
-                    if name.starts_with("backup/") || name.ends_with("HEAD") || name.contains("multi_rebase_") {
-                        return Ok(None);
-                    }
-                    Ok(Some((name.clone(), name)))
-                }).flat_map(|n| n).flat_map(|n| n).collect::<Vec<_>>();
+    if plan.has_stale_state() {
+        warn!("{}", tr!("Detected old multi-rebase operation that probably failed."));
+        warn!("{}", tr!("Press ENTER to clean it up and start over..."));
+        prompter::wait_for_resolution("clean-old-rebase")?;
+        recurse_subs(repo, target, resolver.as_ref(), &|repo, _submodule, _target, _path, _child_results| {
+            for (name, _) in STATE_BRANCHES {
+                if let Ok(branch) = repo.find_branch(name, BranchType::Local) {
+                    branch.into_reference().delete()?;
+                }
+            }
 
-                // See if we can line up any
+            Ok(())
+        })?;
+    }
 
-                let branch_name =
-                    if matching_tracked_branches.len() == 1 {
-                        println!("Check out {} for {}? (same as HEAD) [Y/n]", matching_tracked_branches[0].0, format_path);
-                        let choice = read_stdin()?;
-                        if choice.starts_with("n") || choice.starts_with("N") {
-                            return Err(anyhow!("Cancelling..."));
-                        }
+    Ok(())
+}
 
-                        matching_tracked_branches[0].clone()
-                    } else if matching_tracked_branches.len() > 1 {
-                        println!("Need to check out a branch for {}: [pick one]", format_path);
-                        for (i, (local, _remote)) in matching_tracked_branches.iter().enumerate() {
-                            println!("[{}] {} (same as HEAD)", i + 1, local);
-                        }
+fn merge_options(config: &Config) -> MergeOptions {
+    let mut mopts = MergeOptions::new();
+    for option in &config.strategy_options {
+        match option {
+            StrategyOption::Ours => mopts.file_favor(FileFavor::Ours),
+            StrategyOption::Theirs => mopts.file_favor(FileFavor::Theirs),
+            StrategyOption::Union => mopts.file_favor(FileFavor::Union),
+            StrategyOption::IgnoreSpaceChange => mopts.ignore_whitespace_change(true),
+            StrategyOption::IgnoreAllSpace => mopts.ignore_whitespace(true),
+            StrategyOption::IgnoreSpaceAtEol => mopts.ignore_whitespace_eol(true),
+            StrategyOption::Patience => mopts.patience(true),
+            StrategyOption::Minimal => mopts.minimal(true),
+        };
+    }
+    mopts
+}
 
-                        let choice = read_stdin()?;
-                        let index = usize::from_str(choice.as_str().trim())?;
-                        if index == 0 || index > matching_tracked_branches.len() {
-                            return Err(anyhow!("Bad index, cancelling..."));
-                        }
+// Run git rerere in a repo, since libgit2 doesn't implement it
+fn run_rerere(repo: &Repository, args: &[&str]) -> Result<String> {
+    let cmd = Command::new("git")
+        .arg("rerere")
+        .args(args)
+        .current_dir(repo.workdir().expect("Has workdir"))
+        .output()?;
+    if !cmd.status.success() {
+        return Err(anyhow!("git rerere failed: {}", String::from_utf8_lossy(&cmd.stderr)));
+    }
+    Ok(String::from_utf8(cmd.stdout)?)
+}
 
-                        matching_tracked_branches[index - 1].clone()
-                    } else if matching_local_branches.len() == 1 {
-                        println!("Check out {} for {}? (same as HEAD) [Y/n]", matching_local_branches[0].0, format_path);
-                        let choice = read_stdin()?;
-                        if choice.starts_with("n") || choice.starts_with("N") {
-                            return Err(anyhow!("Cancelling..."));
-                        }
+fn conflicted_paths(repo: &Repository) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for conflict in repo.index()?.conflicts()? {
+        let conflict = conflict?;
+        if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+            paths.push(PathBuf::from(String::from_utf8(entry.path)?));
+        }
+    }
+    Ok(paths)
+}
 
-                        matching_local_branches[0].clone()
-                    } else if matching_local_branches.len() > 1 {
-                        println!("Need to check out a branch for {}: [pick one]", format_path);
-                        for (i, (local, _remote)) in matching_local_branches.iter().enumerate() {
-                            println!("[{}] {} (same as HEAD)", i + 1, local);
-                        }
+// Conflicted files and how they conflict, like git status says it
+fn conflicted_files(repo: &Repository) -> Result<Vec<(String, &'static str)>> {
+    let mut files = vec![];
+    for conflict in repo.index()?.conflicts()? {
+        let conflict = conflict?;
+        let status = match (&conflict.ancestor, &conflict.our, &conflict.their) {
+            (None, Some(_), Some(_)) => "both added",
+            (_, Some(_), Some(_)) => "both modified",
+            (_, Some(_), None) => "deleted by them",
+            (_, None, Some(_)) => "deleted by us",
+            (_, None, None) => "both deleted",
+        };
+        if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+            files.push((String::from_utf8(entry.path)?, status));
+        }
+    }
+    Ok(files)
+}
 
-                        let choice = read_stdin()?;
-                        let index = usize::from_str(choice.as_str().trim())?;
-                        if index == 0 || index > matching_local_branches.len() {
-                            return Err(anyhow!("Bad index, cancelling..."));
-                        }
+// Say which files are conflicted and how, and optionally print the conflict markers in them
+fn print_conflicts(repo: &Repository, named_path: &str, show_hunks: bool) -> Result<()> {
+    for (file_path, status) in conflicted_files(repo)? {
+        warn!("[{}] Conflicted ({}): {}", named_path, status, file_path);
 
-                        matching_local_branches[index - 1].clone()
-                    } else if matching_remote_branches.len() == 1 {
-                        println!("Check out {} for {}? (same as HEAD) [Y/n]", matching_remote_branches[0].0, format_path);
-                        let choice = read_stdin()?;
-                        if choice.starts_with("n") || choice.starts_with("N") {
-                            return Err(anyhow!("Cancelling..."));
-                        }
+        if show_hunks {
+            let contents = match std::fs::read(repo.workdir().expect("Has workdir").join(&file_path)) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            let mut in_hunk = false;
+            for (i, line) in String::from_utf8_lossy(&contents).lines().enumerate() {
+                if line.starts_with("<<<<<<<") {
+                    in_hunk = true;
+                }
+                if in_hunk {
+                    warn!("{:>6}  {}", i + 1, line);
+                }
+                if line.starts_with(">>>>>>>") {
+                    in_hunk = false;
+                    eprintln!();
+                }
+            }
+        }
+    }
+    Ok(())
+}
 
-                        matching_remote_branches[0].clone()
-                    } else if matching_remote_branches.len() > 1 {
-                        println!("Need to check out a branch for {}: [pick one]", format_path);
-                        for (i, (local, _remote)) in matching_remote_branches.iter().enumerate() {
-                            println!("[{}] {} (same as HEAD)", i + 1, local);
-                        }
+// Whether the mergetool's exit code says if it worked, like git: mergetool.<tool>.trustExitCode for
+// the configured tool, or --trust-exit-codes if that isn't set
+fn mergetool_trusts_exit_code(config: &Config, repo: &Repository) -> Result<bool> {
+    let git_config = repo.config()?;
+    let trusted = git_config.get_string("merge.tool").ok()
+        .and_then(|tool| git_config.get_bool(&format!("mergetool.{}.trustExitCode", tool)).ok());
+    Ok(trusted.unwrap_or(config.trust_exit_codes))
+}
 
-                        let choice = read_stdin()?;
-                        let index = usize::from_str(choice.as_str().trim())?;
-                        if index == 0 || index > matching_remote_branches.len() {
-                            return Err(anyhow!("Bad index, cancelling..."));
-                        }
+// Launch the repo's configured mergetool on the conflicted files. Returns whether the conflict got
+// resolved, going by the exit code if the tool is trusted and by what's left in the index if not
+fn run_mergetool(config: &Config, repo: &Repository, named_path: &str) -> Result<bool> {
+    let paths = conflicted_paths(repo)?;
+    if paths.is_empty() {
+        say!("[{}] No conflicted files for mergetool", named_path);
+        return Ok(true);
+    }
 
-                        matching_remote_branches[index - 1].clone()
-                    } else if all_local_branches.len() == 1 {
-                        println!("Check out {} for {}? (not HEAD, will reset --hard) [Y/n]", all_local_branches[0].0, format_path);
-                        let choice = read_stdin()?;
-                        if choice.starts_with("n") || choice.starts_with("N") {
-                            return Err(anyhow!("Cancelling..."));
-                        }
+    say!("[{}] Running git mergetool", named_path);
+    let status = Command::new("git")
+        .arg("mergetool")
+        .arg("--")
+        .args(&paths)
+        .current_dir(repo.workdir().expect("Has workdir"))
+        .stdout(output::child_stdout())
+        .spawn()?
+        .wait()?;
+    if !status.success() {
+        warn!("[{}] git mergetool failed: {:?}", named_path, status.code());
+    }
 
-                        all_local_branches[0].clone()
-                    } else if all_local_branches.len() > 1 {
-                        println!("Need to check out a branch for {}: [pick one]", format_path);
-                        for (i, (local, _remote)) in all_local_branches.iter().enumerate() {
-                            println!("[{}] {} (not HEAD, will reset --hard)", i + 1, local);
-                        }
+    if mergetool_trusts_exit_code(config, repo)? {
+        return Ok(status.success());
+    }
+    // git mergetool staged whatever it resolved, behind libgit2's back
+    repo.index()?.read(true)?;
+    let remaining = conflicted_paths(repo)?;
+    for path in &remaining {
+        warn!("[{}] Still conflicted after mergetool: {}", named_path, path.display());
+    }
+    Ok(remaining.is_empty())
+}
 
-                        let choice = read_stdin()?;
-                        let index = usize::from_str(choice.as_str().trim())?;
-                        if index == 0 || index > all_local_branches.len() {
-                            return Err(anyhow!("Bad index, cancelling..."));
-                        }
+// Tell commands we run at a conflict where they are:
+// - SUB_REBASE_REPO:   working copy of the conflicted repo
+// - SUB_REBASE_PATH:   submodule path of the conflicted repo, as printed in [brackets]
+// - SUB_REBASE_OLD:    the commit being picked (SUB_REBASE_COMMIT is the same, for older hooks)
+// - SUB_REBASE_NEW:    the rewritten commit it's being picked onto
+fn conflict_env(cmd: &mut Command, repo: &Repository, named_path: &str, commit: Option<Oid>) {
+    let old = commit.map(|c| c.to_string()).unwrap_or_default();
+    let new = repo.head().and_then(|h| h.peel_to_commit()).map(|c| c.id().to_string()).unwrap_or_default();
+    cmd.env("SUB_REBASE_REPO", repo.workdir().expect("Has workdir"))
+        .env("SUB_REBASE_PATH", named_path)
+        .env("SUB_REBASE_COMMIT", &old)
+        .env("SUB_REBASE_OLD", &old)
+        .env("SUB_REBASE_NEW", &new);
+}
+
+// Drop the user into a shell in the conflicted repo, returning when it exits
+fn open_shell(repo: &Repository, named_path: &str, commit: Option<Oid>) -> Result<()> {
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string());
+    say!("[{}] Starting {} in {}, exit it to get back to the rebase", named_path, shell, repo.workdir().expect("Has workdir").display());
+    let mut cmd = Command::new(shell);
+    cmd.current_dir(repo.workdir().expect("Has workdir")).stdout(output::child_stdout());
+    conflict_env(&mut cmd, repo, named_path, commit);
+    cmd.spawn()?.wait()?;
+    Ok(())
+}
+
+fn show_diff(repo: &Repository) -> Result<()> {
+    Command::new("git")
+        .arg("diff")
+        .current_dir(repo.workdir().expect("Has workdir"))
+        .stdout(output::child_stdout())
+        .spawn()?
+        .wait()?;
+    Ok(())
+}
+
+// Files over --max-blob that committing the index would bring into history, and that weren't in the
+// original commit
+fn large_new_blobs(repo: &Repository, original: &Commit, max_blob: u64) -> Result<Vec<(PathBuf, u64)>> {
+    let mut index = repo.index()?;
+    if index.has_conflicts() {
+        return Ok(vec![]);
+    }
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let head_tree = repo.head()?.peel_to_tree()?;
+    let original_tree = original.tree()?;
+
+    let mut large = vec![];
+    for delta in repo.diff_tree_to_tree(Some(&head_tree), Some(&tree), None)?.deltas() {
+        let file = delta.new_file();
+        if (delta.status() != Delta::Added && delta.status() != Delta::Modified) || file.mode() == FileMode::Commit {
+            continue;
+        }
+        let path = match file.path() {
+            Some(path) => path,
+            None => continue,
+        };
+        if original_tree.get_path(path).map(|entry| entry.id()).ok() == Some(file.id()) {
+            continue;
+        }
+        let size = repo.find_blob(file.id())?.size() as u64;
+        if size > max_blob {
+            large.push((path.to_path_buf(), size));
+        }
+    }
+    Ok(large)
+}
+
+// Pause before committing large files that weren't in the original commit. False to give up
+fn guard_large_blobs(config: &Config, repo: &Repository, named_path: &str, original: &Commit) -> Result<bool> {
+    let max_blob = match config.max_blob {
+        Some(max_blob) => max_blob,
+        None => return Ok(true),
+    };
+    loop {
+        let large = large_new_blobs(repo, original, max_blob)?;
+        if large.is_empty() {
+            return Ok(true);
+        }
+        for (path, size) in &large {
+            warn!("[{}] Warning: {} is {} bytes (over {}) and wasn't in {}", named_path, path.display(), size, max_blob, original.id());
+        }
+        warn!("{}", tr!("[{}] (c)ommit anyway, (o)pen shell to fix it, or (a)bort [a]", named_path));
+        let choice = prompter::answer(&format!("large-blob {}", named_path))?;
+        match choice.trim().chars().next().map(|c| c.to_ascii_lowercase()) {
+            Some('c') => return Ok(true),
+            Some('o') => {
+                open_shell(repo, named_path, Some(original.id()))?;
+                // Pick up whatever got staged in the shell
+                repo.index()?.read(true)?;
+            }
+            _ => return Ok(false),
+        }
+    }
+}
+
+enum ConflictResolution {
+    // The user (or something) resolved it, try committing again
+    Resolved,
+    // Drop the commit being picked
+    Skip,
+    // Give up on the whole rebase
+    Abort,
+}
+
+// Let the user resolve a conflict, trying rerere and the conflict hook first
+// These only get one shot per conflict so something that claims success without fixing anything can't loop forever
+fn resolve_conflict(config: &Config, repo: &Repository, named_path: &str, commit: Option<Oid>, first_attempt: bool) -> Result<ConflictResolution> {
+    let rerere = repo.config()?.get_bool("rerere.enabled").unwrap_or(false);
+    if rerere && first_attempt {
+        // Records the conflict and replays any resolution we've seen before
+        run_rerere(repo, &[])?;
+        let paths = conflicted_paths(repo)?;
+        if !paths.is_empty() && run_rerere(repo, &["remaining"])?.trim().is_empty() {
+            let mut index = repo.index()?;
+            for path in &paths {
+                say!("[{}] Resolved {} using previous resolution", named_path, path.display());
+                index.add_path(path)?;
+            }
+            index.write()?;
+            return Ok(ConflictResolution::Resolved);
+        }
+    }
+
+    if let (Some(cmd), true) = (&config.exec_on_conflict, first_attempt) {
+        say!("[{}] Running conflict hook: {}", named_path, cmd);
+        let mut hook = Command::new("sh");
+        hook.arg("-c")
+            .arg(cmd)
+            .current_dir(repo.workdir().expect("Has workdir"))
+            .stdout(output::child_stdout());
+        conflict_env(&mut hook, repo, named_path, commit);
+        let status = hook.spawn()?.wait()?;
+        if status.success() {
+            say!("[{}] Conflict hook succeeded, continuing", named_path);
+            if rerere {
+                run_rerere(repo, &[])?;
+            }
+            return Ok(ConflictResolution::Resolved);
+        }
+        warn!("[{}] Conflict hook failed: {:?}", named_path, status.code());
+    }
+
+    // Let user resolve and then continue
+    warn!("{}", tr!("[{}] Rebase conflict in {}!", named_path, repo.workdir().expect("Has workdir").display()));
+    output::event(&["conflict", named_path, &commit.map(|c| c.to_string()).unwrap_or_default()]);
+    print_conflicts(repo, named_path, config.conflict_hunks)?;
+    if config.on_conflict == OnConflictPolicy::Abort {
+        return Ok(ConflictResolution::Abort);
+    }
+    if !config.mergetool || !run_mergetool(config, repo, named_path)? {
+        loop {
+            if output::plain() {
+                // One choice per line reads better than a run of parenthesized letters
+                warn!("{}", tr!("Repo {}: Please resolve, then type a letter and press ENTER. Just ENTER continues.", named_path));
+                for (key, name, _) in CONFLICT_ACTIONS {
+                    warn!("{}", tr!("Choice {}: {}", key, messages::text(name)));
+                }
+            } else {
+                let menu = CONFLICT_ACTIONS.iter().map(|(key, name, _)| {
+                    // Translations don't always start with the key letter
+                    let name = messages::text(name);
+                    if name.starts_with(*key) { format!("({}){}", key, &name[1..]) } else { format!("{}: {}", key, name) }
+                }).collect::<Vec<_>>().join(", ");
+                warn!("{}", tr!("[{}] Please resolve, then pick: {} [c]", named_path, menu));
+            }
+            let choice = prompter::answer(&format!("conflict {}", named_path))?;
+            match choice.trim().chars().next().map(|c| c.to_ascii_lowercase()) {
+                Some('c') | None => break,
+                Some('s') => return Ok(ConflictResolution::Skip),
+                Some('a') => return Ok(ConflictResolution::Abort),
+                Some('m') => {
+                    if run_mergetool(config, repo, named_path)? {
+                        break;
+                    }
+                }
+                Some('o') => open_shell(repo, named_path, commit)?,
+                Some('d') => show_diff(repo)?,
+                Some(_) => warn!("{}", tr!("[{}] Unknown choice {}", named_path, choice.trim())),
+            }
+        }
+    }
+
+    // Record the resolution for next time
+    if rerere {
+        run_rerere(repo, &[])?;
+    }
+    Ok(ConflictResolution::Resolved)
+}
+
+// Author signature to use for the rewritten version of a commit, None to keep the original
+fn rewritten_author(config: &Config, repo: &Repository, original: &Commit) -> Result<Option<Signature<'static>>> {
+    if !config.reset_author && !config.ignore_date && config.author.is_none() {
+        return Ok(None);
+    }
+
+    let original_author = original.author();
+    let base = if config.reset_author { repo.signature()? } else { original_author.to_owned() };
+    let (name, email) = match &config.author {
+        Some(identity) => (identity.name.clone(), identity.email.clone()),
+        None => (base.name().unwrap_or("").to_string(), base.email().unwrap_or("").to_string()),
+    };
+
+    if config.reset_author || config.ignore_date {
+        Ok(Some(Signature::now(&name, &email)?))
+    } else {
+        Ok(Some(Signature::new(&name, &email, &original_author.when())?))
+    }
+}
+
+// Committer for anything we create in a repo, unless told to preserve the original
+fn default_committer(config: &Config, repo: &Repository) -> Result<Signature<'static>> {
+    match &config.committer {
+        Some(identity) => Ok(Signature::now(&identity.name, &identity.email)?),
+        None => Ok(repo.signature()?),
+    }
+}
+
+// Committer signature to use for the rewritten version of a commit
+fn rewritten_committer(config: &Config, repo: &Repository, original: &Commit, author: Option<&Signature>) -> Result<Signature<'static>> {
+    let default = default_committer(config, repo)?;
+    if config.preserve_committer {
+        Ok(original.committer().to_owned())
+    } else if config.committer_date_is_author_date {
+        let when = author.map(|a| a.when()).unwrap_or_else(|| original.author().when());
+        Ok(Signature::new(default.name().unwrap_or(""), default.email().unwrap_or(""), &when)?)
+    } else {
+        Ok(default)
+    }
+}
+
+// Format a signature time the way git expects in GIT_COMMITTER_DATE
+fn git_date_string(time: &git2::Time) -> String {
+    let offset = time.offset_minutes().abs();
+    format!("@{} {}{:02}{:02}", time.seconds(), time.sign(), offset / 60, offset % 60)
+}
+
+// Index entry for a submodule pointing at a commit
+fn gitlink_entry(path: &str, id: Oid) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode: 0o160000,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id,
+        flags: 0,
+        flags_extended: 0,
+        path: path.as_bytes().to_vec(),
+    }
+}
+
+// Whether a repo's commits can be picked in memory. Commit hooks and signing happen in `git commit
+// --amend` after each pick, which needs the commit checked out, as does --max-blob's shell to fix
+// things in, and a rebase through the working copy flattens merges the way git does, which picking in
+// memory can't
+fn picks_in_memory(config: &Config, repo: &Repository, base: Oid) -> Result<bool> {
+    if config.worktree_picks || config.max_blob.is_some() || (config.verify && !config.no_verify) || repo.config()?.get_bool("commit.gpgSign").unwrap_or(false) {
+        return Ok(false);
+    }
+    if ["prepare-commit-msg", "post-commit"].iter().any(|hook| hooks::has_hook(repo, hook)) {
+        return Ok(false);
+    }
+    let mut walk = repo.revwalk()?;
+    walk.push_head()?;
+    walk.hide(base)?;
+    for id in walk {
+        if repo.find_commit(id?)?.parent_count() != 1 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
 
-                        all_local_branches[index - 1].clone()
-                    } else {
-                        return Err(anyhow!("No branches found for {}", format_path));
-                    };
+// How far tree_rebase got
+enum TreeRebased {
+    // Every commit, with the rewritten head
+    Done(Oid),
+    // Up to this commit, which needs a working copy: it conflicts, or adds a submodule that needs rebasing
+    Stopped(Oid),
+}
 
-                results.insert(path.clone(), branch_name);
-            }
+// What a gitlink points at once its submodule is rebased: the rewritten commit, or with --map-notes
+// whatever an earlier run rewrote it to
+fn translate_gitlink(config: &Config, repo: &Repository, child_results: &HashMap<String, HashMap<Oid, Oid>>, sub_path: &str, id: Oid) -> Oid {
+    if let Some(rewritten) = child_results.get(sub_path).and_then(|map| map.get(&id)) {
+        return *rewritten;
+    }
+    if config.map_notes {
+        let sub_repo = repo.find_submodule(sub_path).ok().and_then(|submodule| cache::open_submodule(repo, &submodule).ok());
+        if let Some(rewritten) = sub_repo.and_then(|sub_repo| commit_map::read_note(&sub_repo, id)) {
+            return rewritten;
+        }
+    }
+    id
+}
 
-            Ok(results)
-        })?;
+// What tree_rebase does with files other than gitlinks
+#[derive(Clone, Copy, PartialEq)]
+enum TreeMerge {
+    // Nothing, they must not have been changed upstream
+    GitlinksOnly,
+    // Merge them in memory, failing on conflicts
+    Content,
+    // Merge them in memory, stopping at a commit that needs a working copy instead of failing it
+    UntilWorktree,
+}
 
-        // Pretty print
-        let max_branch_len = checkout_names.iter().map(|(path, _)| sub_path_to_string(path).len()).max().unwrap_or(0);
-        let max_local_len = checkout_names.iter().map(|(_, (local, _))| local.len()).max().unwrap_or(0);
-        println!("\n");
-        println!("Checking out branches for submodules: ");
-        for (sub, (local, remote)) in checkout_names.iter().collect::<BTreeMap<_, _>>() {
-            println!("{}:{} {}{} ==> {}", sub_path_to_string(sub), String::from_utf8(vec![b' '; max_branch_len - sub_path_to_string(sub).len()])?, local, String::from_utf8(vec![b' '; max_local_len - local.len()])?, remote);
+// Rebase a repo without touching the working copy, by replaying each commit's changes on top of
+// the rewritten parent's tree. Gitlinks are translated via the child commit maps, other files are
+// handled as `merge` says
+fn tree_rebase(config: &Config, repo: &Repository, named_path: &str, base: Oid, child_results: &HashMap<String, HashMap<Oid, Oid>>, commit_map: &mut HashMap<Oid, Oid>, merge: TreeMerge) -> Result<TreeRebased> {
+    let stop_for_worktree = merge == TreeMerge::UntilWorktree;
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    walk.push_head()?;
+    walk.hide(base)?;
+
+    let mut new_head = commit_map[&base];
+    for commit in walk {
+        let commit = repo.find_commit(commit?)?;
+        if commit.parent_count() != 1 {
+            return Err(anyhow!("[{}] Commit {} is a merge, can't rewrite it without a working copy", named_path, commit.id()));
         }
+        let old_parent = commit.parent(0)?;
+        let new_parent = repo.find_commit(*commit_map.get(&old_parent.id()).ok_or(anyhow!("[{}] Parent of {} was not rewritten", named_path, commit.id()))?)?;
 
-        println!("\n");
-        println!("Running: ");
-        recurse_subs(&repo, &target, &|repo: &Repository, _submodule, _target, path, _child_results| -> Result<()> {
-            if let Some((local, remote)) = checkout_names.get(path) {
-                println!("{}:{} {}{} ==> {}", sub_path_to_string(path), String::from_utf8(vec![b' '; max_branch_len - sub_path_to_string(path).len()])?, local, String::from_utf8(vec![b' '; max_local_len - local.len()])?, remote);
+        let old_parent_tree = old_parent.tree()?;
+        let new_parent_tree = new_parent.tree()?;
+        let commit_tree = commit.tree()?;
 
-                // Make a backup branch because aaa my data
-                let branch_name = format!("backup/HEAD_{}", Local::now().format("%H-%M-%S"));
-                repo.branch(&branch_name, &repo.head()?.peel_to_commit()?, true)?;
+        let tree = if merge != TreeMerge::GitlinksOnly {
+            let mut index = repo.merge_trees(&old_parent_tree, &new_parent_tree, &commit_tree, Some(&merge_options(config)))?;
 
-                let current = repo.head()?.peel_to_commit()?;
-                let branch =
-                    if let Ok(branch) = repo.find_branch(local.as_str(), BranchType::Local) {
-                        branch
-                    } else {
-                        // Create branch
-                        let mut branch = repo.branch(local.as_str(), &current, false)?;
-                        branch.set_upstream(repo.find_branch(remote.as_str(), BranchType::Remote)?.name()?)?;
-                        branch
-                    };
-                repo.set_head(branch.into_reference().name().expect("Branch has name"))?;
-                repo.reset(current.as_object(), ResetType::Mixed, None)?;
-                repo.reset(current.as_object(), ResetType::Hard, None)?;
-            }
-            Ok(())
-        })?;
-    }
+            // Gitlinks changed by this commit always win, translated to the rewritten submodule commit
+            for delta in repo.diff_tree_to_tree(Some(&old_parent_tree), Some(&commit_tree), None)?.deltas() {
+                let new_file = delta.new_file();
+                if delta.status() == Delta::Deleted || new_file.mode() != FileMode::Commit {
+                    continue;
+                }
+                let file_path = new_file.path().expect("Delta expected path");
+                let file_name = file_path.to_str().expect("Path should be utf-8").to_string();
+                // A submodule added partway through gets rebased along the way, from a working copy
+                if stop_for_worktree && !child_results.contains_key(&file_name) && repo.find_submodule(&file_name).is_ok() {
+                    return Ok(TreeRebased::Stopped(commit.id()));
+                }
+                let id = translate_gitlink(config, repo, child_results, &file_name, new_file.id());
+                say!("[{}] Update submodule {} to {}", named_path, file_name, id);
 
-    let need_clean_old_rebase = recurse_subs(&repo, &target, &|repo, _submodule, _target, _path, child_results| {
-        for (_child, result) in child_results {
-            if result {
-                return Ok(true);
+                for stage in 1..=3 {
+                    let _ = index.remove(file_path, stage);
+                }
+                index.add(&gitlink_entry(&file_name, id))?;
             }
-        }
-
-        let multi_rebase_old = repo.find_branch("multi_rebase_old", BranchType::Local);
-        let multi_rebase_cur = repo.find_branch("multi_rebase_cur", BranchType::Local);
-        let multi_rebase_new = repo.find_branch("multi_rebase_new", BranchType::Local);
-        let multi_rebase_track = repo.find_branch("multi_rebase_track", BranchType::Local);
 
-        Ok(multi_rebase_old.is_ok() || multi_rebase_cur.is_ok() || multi_rebase_new.is_ok() || multi_rebase_track.is_ok())
-    })?;
-    if need_clean_old_rebase {
-        eprintln!("Detected old multi-rebase operation that probably failed.");
-        eprintln!("Press ENTER to clean it up and start over...");
-        let _ = read_stdin()?;
-        recurse_subs(&repo, &target, &|repo, _submodule, _target, _path, _child_results| {
-            if let Ok(multi_rebase_old) = repo.find_branch("multi_rebase_old", BranchType::Local) {
-                multi_rebase_old.into_reference().delete()?;
-            }
-            if let Ok(multi_rebase_cur) = repo.find_branch("multi_rebase_cur", BranchType::Local) {
-                multi_rebase_cur.into_reference().delete()?;
+            if index.has_conflicts() && stop_for_worktree {
+                return Ok(TreeRebased::Stopped(commit.id()));
             }
-            if let Ok(multi_rebase_new) = repo.find_branch("multi_rebase_new", BranchType::Local) {
-                multi_rebase_new.into_reference().delete()?;
+            if index.has_conflicts() {
+                for conflict in index.conflicts()? {
+                    let conflict = conflict?;
+                    if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+                        warn!("[{}] Conflicted: {}", named_path, String::from_utf8_lossy(&entry.path));
+                    }
+                }
+                return Err(anyhow!("[{}] Commit {} conflicts, rerun without --no-worktree-rebase to resolve it", named_path, commit.id()));
             }
-            if let Ok(multi_rebase_track) = repo.find_branch("multi_rebase_track", BranchType::Local) {
-                multi_rebase_track.into_reference().delete()?;
+            repo.find_tree(index.write_tree_to(repo)?)?
+        } else {
+            let mut update = TreeUpdateBuilder::new();
+            for delta in repo.diff_tree_to_tree(Some(&old_parent_tree), Some(&commit_tree), None)?.deltas() {
+                let old_file = delta.old_file();
+                let new_file = delta.new_file();
+                let file_path = new_file.path().or(old_file.path()).expect("Delta expected path");
+                let file_name = file_path.to_str().expect("Path should be utf-8").to_string();
+
+                if delta.status() == Delta::Deleted {
+                    // Same as a change, unless upstream deleted it too
+                    let upstream = new_parent_tree.get_path(file_path).ok().map(|e| e.id());
+                    if upstream.is_some() && upstream != Some(old_file.id()) {
+                        return Err(anyhow!("[{}] {} was deleted by {} and changed upstream, gitlink-only mode can't merge it", named_path, file_name, commit.id()));
+                    }
+                    update.remove(file_path);
+                } else if new_file.mode() == FileMode::Commit {
+                    // Gitlink, point it at the rewritten submodule commit
+                    let id = translate_gitlink(config, repo, child_results, &file_name, new_file.id());
+                    say!("[{}] Update submodule {} to {}", named_path, file_name, id);
+                    update.upsert(file_path, id, FileMode::Commit);
+                } else {
+                    // Regular file, only safe if upstream didn't touch it
+                    let upstream = new_parent_tree.get_path(file_path).ok().map(|e| e.id());
+                    let ours = old_parent_tree.get_path(file_path).ok().map(|e| e.id());
+                    if upstream != ours && upstream != Some(new_file.id()) {
+                        return Err(anyhow!("[{}] {} was changed by both {} and upstream, gitlink-only mode can't merge it", named_path, file_name, commit.id()));
+                    }
+                    update.upsert(file_path, new_file.id(), new_file.mode());
+                }
             }
+            repo.find_tree(update.create_updated(repo, &new_parent_tree)?)?
+        };
 
-            Ok(())
-        })?;
+        // Like a rebase, a commit whose changes upstream already has gets dropped
+        if tree.id() == new_parent_tree.id() && commit_tree.id() != old_parent_tree.id() {
+            say!("[{}] Commit patch was already applied! Assuming that means we can ignore it.", named_path);
+            commit_map.insert(commit.id(), new_parent.id());
+            new_head = new_parent.id();
+            continue;
+        }
+
+        let author = rewritten_author(config, repo, &commit)?.unwrap_or_else(|| commit.author().to_owned());
+        let committer = rewritten_committer(config, repo, &commit, Some(&author))?;
+        new_head = repo.commit(None, &author, &committer, commit.message().unwrap_or(""), &tree, &[&new_parent])?;
+
+        say!("[{}] Rebased commit {} {} {}", named_path, commit.id(), output::arrow(), new_head);
+        output::event(&["rewrite", named_path, &commit.id().to_string(), &new_head.to_string()]);
+        output::picked();
+        commit_map.insert(commit.id(), new_head);
     }
 
-    Ok(())
+    Ok(TreeRebased::Done(new_head))
 }
 
 struct RebaseState {
@@ -455,74 +1735,219 @@ extern "C" fn sign_commit(
     }
 }
 
-fn multi_rebase_inner(repo: &Repository, _submodule: Option<&Submodule>, target: &Commit, path: &Vec<String>, mut child_results: HashMap<String, HashMap<Oid, Oid>>) -> Result<HashMap<Oid, Oid>> {
+// Excluded submodules keep their old pointers, and so does anything inside them
+fn is_excluded(config: &Config, path: &[String]) -> bool {
+    (1..=path.len()).any(|n| config.exclude_submodules.contains(&sub_workdir_path(&path[..n])))
+}
+
+// Point a submodule at a commit while its parent is being rebased. That's multi_rebase_cur if the
+// submodule is being rebased too, otherwise it gets detached (e.g. excluded submodules)
+fn point_submodule_at(sub_repo: &Repository, id: Oid) -> Result<()> {
+    let message = format!("sub-rebase: point submodule at {}", id);
+    match sub_repo.find_branch("multi_rebase_cur", BranchType::Local) {
+        Ok(cur) => set_head(sub_repo, cur.into_reference().name().expect("Branch ref needs name"), &message)?,
+        Err(_) => detach_head(sub_repo, id, &message)?,
+    }
+    reset_hard(sub_repo, &sub_repo.find_object(id, Some(ObjectType::Commit))?, &message)?;
+    Ok(())
+}
+
+// `git submodule update --init --recursive` for one submodule, with libgit2: the URL from .gitmodules
+// (relative ones resolved against the parent's remote) goes into the parent's config, the clone's git
+// dir goes under .git/modules/<name>, and the gitlink gets checked out. Then the same for anything
+// inside it that isn't there yet
+fn update_submodule(repo: &Repository, submodule: &mut Submodule, named_path: &str) -> Result<()> {
+    submodule.init(false)?;
+    submodule.sync()?;
+    // Resolved by init, if .gitmodules has it relative
+    let name = submodule.name().expect("Submodule should have name").to_string();
+    let url = repo.config()?.get_string(&format!("submodule.{}.url", name))
+        .or_else(|_| submodule.url().map(|url| url.to_string()).ok_or_else(|| anyhow!("[{}] Submodule has no url", named_path)))?;
+    if proxy::needs_git(repo, None, &url) {
+        // git goes through SOCKS proxies, libgit2 can't
+        let mut cmd = Command::new("git");
+        cmd.arg("submodule").arg("update").arg("--init").arg("--recursive").arg("--").arg(submodule.path())
+            .current_dir(repo.workdir().expect("Has workdir"));
+        return match watchdog::run_command(named_path, "git submodule update", &mut cmd)? {
+            Some(output) if output.status.success() => Ok(()),
+            Some(output) => Err(anyhow!("[{}] git submodule update failed: {}", named_path, String::from_utf8_lossy(&output.stderr).trim())),
+            None => Err(anyhow!("[{}] git submodule update was skipped", named_path)),
+        };
+    }
+    features::check_url(&url)?;
+    let mut update_options = credentials::submodule_update_options(repo, &url);
+    watchdog::watch(named_path, "submodule update", || submodule.update(true, Some(&mut update_options)))?;
+    submodule.reload(true)?;
+
+    let sub_repo = submodule.open()?;
+    for mut nested in sub_repo.submodules()? {
+        if nested.open().is_err() {
+            let name = nested.name().expect("Submodule should have name").to_string();
+            temp_clones::record(&sub_repo, &name, &nested.path().to_string_lossy());
+            update_submodule(&sub_repo, &mut nested, &format!("{}/{}", named_path, name))?;
+        }
+    }
+    Ok(())
+}
+
+// Let the user toggle which submodules get rebased, showing how many commits each would rewrite
+fn select_submodules(config: &mut Config, repo: &Repository, target: &Commit, resolver: &dyn TargetResolver) -> Result<()> {
+    let counts = recurse_subs(repo, target, resolver, &|repo: &Repository, _submodule, target: &Commit, _path, child_results: HashMap<String, HashMap<Vec<String>, usize>>| -> Result<HashMap<Vec<String>, usize>> {
+        let head = repo.head()?.peel_to_commit()?.id();
+        let base = cache::merge_base(repo, head, target.id())?;
+        let mut walk = repo.revwalk()?;
+        walk.push(head)?;
+        walk.hide(base)?;
+
+        let mut results = HashMap::new();
+        results.insert(vec![], walk.count());
+        for (path, c_results) in child_results {
+            for (mut cpath, cvalue) in c_results.into_iter() {
+                cpath.insert(0, path.clone());
+                results.insert(cpath, cvalue);
+            }
+        }
+        Ok(results)
+    })?;
+
+    let mut paths = counts.keys().filter(|path| !path.is_empty()).cloned().collect::<Vec<_>>();
+    paths.sort();
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    loop {
+        say!("{}", tr!("Submodules to rebase:"));
+        for (i, path) in paths.iter().enumerate() {
+            let mark = if is_excluded(config, path) { " " } else { "x" };
+            say!("[{}] {}. {} ({} commits)", mark, i + 1, sub_path_to_string(path), counts[path]);
+        }
+        say!("{}", tr!("Enter numbers to toggle, or ENTER when done"));
+
+        let choice = prompter::answer("select-submodules")?;
+        if choice.trim().is_empty() {
+            return Ok(());
+        }
+        for word in choice.split(|c: char| c.is_whitespace() || c == ',').filter(|w| !w.is_empty()) {
+            let index = usize::from_str(word)?;
+            if index == 0 || index > paths.len() {
+                return Err(anyhow!("Bad index, cancelling..."));
+            }
+            let name = sub_workdir_path(&paths[index - 1]);
+            if let Some(position) = config.exclude_submodules.iter().position(|e| *e == name) {
+                config.exclude_submodules.remove(position);
+            } else {
+                config.exclude_submodules.push(name);
+            }
+        }
+    }
+}
+
+fn multi_rebase_inner(config: &Config, repo: &Repository, submodule: Option<&Submodule>, target: &Commit, path: &Vec<String>, mut child_results: HashMap<String, HashMap<Oid, Oid>>) -> Result<HashMap<Oid, Oid>> {
     // ---------------------------------------------------------------------------------------------
     // The Real Part TM
     // ---------------------------------------------------------------------------------------------
 
     let named_path = sub_path_to_string(path);
-    println!("[{}] Now rebasing", named_path);
-    if !child_results.is_empty() {
-        println!("[{}] Child submodules commit map: {:?}", named_path, child_results);
+    if is_excluded(config, path) {
+        say!("[{}] Excluded, leaving it alone", named_path);
+        return Ok(HashMap::new());
+    }
+    say!("[{}] Now rebasing", named_path);
+    output::event(&["repo", &named_path]);
+    if !child_results.is_empty() && !output::plain() {
+        say!("[{}] Child submodules commit map: {:?}", named_path, child_results);
     }
 
     let head = repo.head()?;
-    let base = repo.merge_base(head.peel_to_commit()?.id(), target.id())?;
+    let head_state = HeadState::capture(repo)?;
+    let base = cache::merge_base(repo, head.peel_to_commit()?.id(), target.id())?;
+
+    // Give the repo's pre-rebase hook a say, like git rebase, if there's anything to rewrite
+    if head.peel_to_commit()?.id() != target.id() && base != target.id() {
+        let status = if config.no_verify { None } else { hooks::run_hook(repo, "pre-rebase", &[&target.id().to_string()], None)? };
+        if let Some(status) = status {
+            if !status.success() {
+                match config.pre_rebase_reject {
+                    PreRebasePolicy::Abort => return Err(anyhow!("[{}] pre-rebase hook rejected the rebase", named_path)),
+                    PreRebasePolicy::Skip => {
+                        say!("[{}] pre-rebase hook rejected the rebase, leaving it alone", named_path);
+                        return Ok(HashMap::new());
+                    }
+                }
+            }
+        }
+    }
 
-    // Make a backup branch because aaa my data
-    let mut branch_name = head.name().expect("Head should have a name");
-    if branch_name.contains('/') {
-        branch_name = branch_name.split('/').last().expect("Split should have results");
+    if base != target.id() && rebase_config_flag(repo, "rebase.autoSquash", config.autosquash, config.no_autosquash) {
+        let mut walk = repo.revwalk()?;
+        walk.push(head.peel_to_commit()?.id())?;
+        walk.hide(base)?;
+        for commit in walk {
+            let commit = repo.find_commit(commit?)?;
+            let summary = commit.summary().unwrap_or("");
+            if summary.starts_with("fixup! ") || summary.starts_with("squash! ") || summary.starts_with("amend! ") {
+                warn!("[{}] Warning: {} ({}) won't be squashed, commits are picked in order", named_path, commit.id(), summary);
+            }
+        }
     }
-    let branch_name = format!("backup/{}_{}", branch_name, Local::now().format("%H-%M-%S"));
-    repo.branch(&branch_name, &head.peel_to_commit()?, true)?;
 
-    // Make four branches to keep track of state:
-    // - multi_rebase_old:   the previous head commit, in case of failure
-    // - multi_rebase_cur:   the rebase-head with all rebased commits so far
-    // - multi_rebase_track: the commit on the pre-rebase branch that we are rebasing next
-    // - multi_rebase_new:   the head branch used during the rebase
+    backups::make_backup(config, repo, head.name().expect("Head should have a name"), head.peel_to_commit()?.id())?;
+
+    // Like git rebase, so `git reset --hard ORIG_HEAD` gets back to before the run
+    repo.reference("ORIG_HEAD", head.peel_to_commit()?.id(), true, "sub-rebase: ORIG_HEAD")?;
+
+    // Make four branches to keep track of state, see STATE_BRANCHES
     repo.branch("multi_rebase_cur", &head.peel_to_commit()?, true)?;
     repo.branch("multi_rebase_old", &head.peel_to_commit()?, true)?;
     let mut track_branch = repo.branch("multi_rebase_track", &head.peel_to_commit()?, true)?.into_reference();
     let new_branch = repo.branch("multi_rebase_new", &head.peel_to_commit()?, true)?.into_reference();
-    repo.set_head(new_branch.name().expect("Need refname"))?;
+
+    // Another copy of the same submodule may have been through this already
+    if let Some(key) = dedupe::key(repo, submodule, target.id(), &child_results) {
+        if let Some(commit_map) = dedupe::reuse(&key, repo, &named_path)? {
+            return Ok(commit_map);
+        }
+    }
+
+    set_head(repo, new_branch.name().expect("Need refname"), &format!("sub-rebase: rebasing onto {}", target.id()))?;
 
     let mut sub_heads = HashMap::new();
     for (sub, _) in &child_results {
         let res_submodule = repo.find_submodule(&sub)?;
-        let sub_repo = res_submodule.open()?;
+        let sub_repo = cache::open_submodule(repo, &res_submodule)?;
         sub_heads.insert(sub.clone(), sub_repo.head()?.name().expect("Head needs name").to_string());
     }
 
+    // Submodules left out for never changing keep their gitlinks as they are. They may
+    // not even be checked out, so they're added after the HEADs to put back are read
+    for submodule in repo.submodules()? {
+        let sub_path = submodule.path().to_str().expect("Submodule should have path").to_string();
+        if !child_results.contains_key(&sub_path) && resolver::skipped_unchanged(repo, &submodule, target.id()) {
+            child_results.insert(sub_path, HashMap::new());
+        }
+    }
+
     // Map of old commit id -> new commit id
     let mut commit_map = HashMap::new();
 
     // If we have nothing to rebase, exit early
-    println!("[{}] HEAD is at {}", named_path, head.peel_to_commit()?.id().to_string());
-    println!("[{}] Target is  {}", named_path, target.id());
+    say!("[{}] HEAD is at {}", named_path, head.peel_to_commit()?.id().to_string());
+    say!("[{}] Target is  {}", named_path, target.id());
     if head.peel_to_commit()?.id() == target.id() {
-        println!("[{}] {} --> {}", named_path, target.id(), target.id());
+        say!("[{}] {} {} {}", named_path, target.id(), output::arrow(), target.id());
+        output::event(&["rewrite", &named_path, &target.id().to_string(), &target.id().to_string()]);
         commit_map.insert(target.id(), target.id());
-        println!("[{}] Nothing to rebase", named_path);
-        match head.name() {
-            Some("HEAD") | None => {
-                let id = head.peel_to_commit()?.id();
-                println!("[{}] Set HEAD to {}", named_path, id.to_string());
-                repo.set_head_detached(id)?;
-            }
-            Some(head_name) => {
-                println!("[{}] Set HEAD to {}", named_path, head_name);
-                repo.set_head(head_name)?;
-            }
-        }
+        say!("[{}] Nothing to rebase", named_path);
+        say!("[{}] Set HEAD to {}", named_path, head_state);
+        head_state.restore(repo)?;
         return Ok(commit_map);
     }
 
     // If we're rebasing onto the same commit as we've branched, there's no point in redoing all the commits
-    println!("[{}] base is at {}", named_path, base.to_string());
+    say!("[{}] base is at {}", named_path, base.to_string());
     if base == target.id() {
-        println!("[{}] Branched from base, using current tree.", named_path);
+        say!("[{}] Branched from base, using current tree.", named_path);
 
         // Add all the commits as themself -> themself
         let mut walk = repo.revwalk()?;
@@ -531,47 +1956,90 @@ fn multi_rebase_inner(repo: &Repository, _submodule: Option<&Submodule>, target:
         walk.hide(base)?;
         for commit in walk.into_iter() {
             let commit = commit?;
-            println!("[{}] {} --> {}", named_path, commit, commit);
+            say!("[{}] {} {} {}", named_path, commit, output::arrow(), commit);
+            output::event(&["rewrite", &named_path, &commit.to_string(), &commit.to_string()]);
             commit_map.insert(commit.clone(), commit);
         }
-        println!("[{}] {} --> {}", named_path, base, base);
+        say!("[{}] {} {} {}", named_path, base, output::arrow(), base);
         commit_map.insert(base.clone(), base);
-        match head.name() {
-            Some("HEAD") | None => {
-                let id = head.peel_to_commit()?.id();
-                println!("[{}] Set HEAD to {}", named_path, id.to_string());
-                repo.set_head_detached(id)?;
-            }
-            Some(head_name) => {
-                println!("[{}] Set HEAD to {}", named_path, head_name);
-                repo.set_head(head_name)?;
-            }
-        }
+        say!("[{}] Set HEAD to {}", named_path, head_state);
+        head_state.restore(repo)?;
         return Ok(commit_map);
     }
 
     // Mark initial commit as pointing to the head where we're rebasing onto
     commit_map.insert(base, target.id());
 
+    let mut walk = repo.revwalk()?;
+    walk.push_head()?;
+    walk.hide(base)?;
+    let _progress = output::enter_repo(&named_path, walk.count());
+
+    if config.no_worktree_rebase || (config.gitlink_only && path.is_empty()) {
+        say!("[{}] Rewriting commits without a checkout", named_path);
+        if config.verify && !config.no_verify {
+            warn!("[{}] Warning: commit hooks need a working copy, they won't run on these commits", named_path);
+        }
+        let new_head = match tree_rebase(config, repo, &named_path, base, &child_results, &mut commit_map, if config.no_worktree_rebase { TreeMerge::Content } else { TreeMerge::GitlinksOnly })? {
+            TreeRebased::Done(new_head) => new_head,
+            TreeRebased::Stopped(id) => unreachable!("Only stops for a working copy when asked to, stopped at {}", id),
+        };
+        repo.reference(new_branch.name().expect("Need refname"), new_head, true, "sub-rebase: rewrite without checkout")?;
+        say!("[{}] Set HEAD to {}", named_path, head_state);
+        head_state.restore(repo)?;
+        return Ok(commit_map);
+    }
+
+    // Pick in memory for as long as that goes cleanly, so big working copies don't get checked out
+    // (and reindexed by every editor watching them) once per commit. From the first commit that
+    // conflicts, the rest get picked in the working copy where the conflict can be resolved
+    let mut upstream_id = base;
+    let mut onto_id = target.id();
+    if picks_in_memory(config, repo, base)? {
+        match tree_rebase(config, repo, &named_path, base, &child_results, &mut commit_map, TreeMerge::UntilWorktree)? {
+            TreeRebased::Done(new_head) => {
+                repo.reference(new_branch.name().expect("Need refname"), new_head, true, "sub-rebase: rebase in memory")?;
+                say!("[{}] Set HEAD to {}", named_path, head_state);
+                head_state.restore(repo)?;
+                return Ok(commit_map);
+            }
+            TreeRebased::Stopped(id) => {
+                // Linear, so the parent is the last commit picked
+                upstream_id = repo.find_commit(id)?.parent_id(0)?;
+                onto_id = commit_map[&upstream_id];
+                say!("[{}] {} needs a working copy, picking from there on in it", named_path, id);
+            }
+        }
+    }
+
+    let mut conflict_attempts = 0;
     let mut rebase = loop {
-        let copts = CheckoutBuilder::new();
+        let mut copts = CheckoutBuilder::new();
+        // Files left out of a sparse checkout would look deleted and conflict, so they get written
+        // back while picking and taken out again once it's done
+        if sparse::is_sparse(repo) {
+            copts.recreate_missing(true);
+        }
         let mut ropts = RebaseOptions::new();
         ropts.checkout_options(copts);
+        ropts.merge_options(merge_options(config));
 
         unsafe {
             (*std::mem::transmute::<_, *mut libgit2_sys::git_rebase_options>(ropts.raw())).commit_create_cb = Some(sign_commit);
         }
 
-        match repo.rebase(Some(&repo.reference_to_annotated_commit(&new_branch)?), Some(&repo.find_annotated_commit(base)?), Some(&repo.find_annotated_commit(target.id())?), Some(ropts.borrow_mut())) {
+        let branch = repo.reference_to_annotated_commit(&new_branch)?;
+        let upstream = repo.find_annotated_commit(upstream_id)?;
+        let onto = repo.find_annotated_commit(onto_id)?;
+        match watchdog::watch(&named_path, "starting the rebase", || repo.rebase(Some(&branch), Some(&upstream), Some(&onto), Some(ropts.borrow_mut()))) {
             Ok(value) => break Ok(value),
             Err(e) if e.code() == Conflict => {
-                eprintln!("[{}] {}", named_path, e);
-
-                // Let user resolve and then continue
-                eprintln!("[{}] Rebase conflict!", named_path);
-                eprintln!("[{}] Please resolve then press enter when satisfied", named_path);
-
-                let _ = read_stdin()?;
+                warn!("[{}] {}", named_path, e);
+                // Nothing has been picked yet so there's nothing to skip
+                if let ConflictResolution::Abort = resolve_conflict(config, repo, &named_path, None, conflict_attempts == 0)? {
+                    return Err(Failure::ConflictAborted(named_path).into());
+                }
+                conflict_attempts += 1;
             }
             Err(e) => break Err(e)
         }
@@ -581,20 +2049,20 @@ fn multi_rebase_inner(repo: &Repository, _submodule: Option<&Submodule>, target:
     // Because the submodules are dumb and don't reset
 
     // Submodules like to mark themselves modified sometimes and that can cause the rebase to get unhappy
-    for entry in repo.diff_index_to_workdir(None, None)?.deltas() {
+    let skipped = sparse::skipped(repo)?;
+    for entry in repo.diff_index_to_workdir(None, None)?.deltas().filter(|entry| !sparse::is_skipped(&skipped, entry)) {
         let diff_path = String::from_utf8_lossy(entry.new_file().path_bytes().expect("New file expected path")).into_owned();
         if entry.status() != Delta::Unmodified {
-            println!("[{}] Modified: {:?}", named_path, entry.new_file().path());
+            say!("[{}] Modified: {:?}", named_path, entry.new_file().path());
             if child_results.contains_key(&diff_path) {
-                println!("[{}] Unexpected submodule diff: {:?} {:?}", named_path, entry.new_file().path(), entry.status());
+                say!("[{}] Unexpected submodule diff: {:?} {:?}", named_path, entry.new_file().path(), entry.status());
 
                 // Submodule that was not updated
                 let diff_submodule = repo.find_submodule(&diff_path)?;
-                let sub_repo = diff_submodule.open()?;
+                let sub_repo = cache::open_submodule(repo, &diff_submodule)?;
 
                 // What's its head? If it's in our results list then we shouldn't need to touch it, just stage it
-                sub_repo.set_head(sub_repo.find_branch("multi_rebase_cur", BranchType::Local)?.into_reference().name().expect("Branch ref needs name"))?;
-                sub_repo.reset(&sub_repo.find_object(entry.old_file().id(), Some(ObjectType::Commit))?, ResetType::Hard, Some(CheckoutBuilder::new().borrow_mut()))?;
+                point_submodule_at(&sub_repo, entry.old_file().id())?;
 
                 repo.index()?.update_all(&[&diff_path], None)?;
                 repo.index()?.add_path((&diff_path).as_ref())?;
@@ -603,7 +2071,15 @@ fn multi_rebase_inner(repo: &Repository, _submodule: Option<&Submodule>, target:
     }
     repo.index()?.write()?;
 
+    // Where each submodule was pointed, to spot plateaus and regressions
+    let mut pointers = pointers::Pointers::default();
+    // Commits left alone on each plateau, by submodule and the commit it stayed on
+    let mut plateaus: BTreeMap<(String, Oid), usize> = BTreeMap::new();
+    // (commit, submodule, from, to) for each time a submodule pointer moved backwards
+    let mut regressions: Vec<(Oid, String, Oid, Oid)> = vec![];
+
     while let Some(Ok(op)) = rebase.next() {
+        trace!("[{}] Picking {} ({:?})", named_path, op.id(), op.kind());
         track_branch.delete()?;
         track_branch = repo.branch("multi_rebase_track", &repo.find_commit(op.id())?, true)?.into_reference();
 
@@ -614,24 +2090,18 @@ fn multi_rebase_inner(repo: &Repository, _submodule: Option<&Submodule>, target:
         // Make sure the submodules updated (they don't on the first commit, and conflict on all later commits)
         let tree = repo.find_commit(op.id())?.tree()?;
         for mut submodule in repo.submodules()? {
-            let sub_repo = match submodule.open() {
+            if let Some(kind) = non_gitlink_kind(&submodule, &tree) {
+                say!("[{}] Submodule {} is a {}, not a gitlink. Ignoring it.", named_path, submodule.name().expect("Submodule should have name"), kind);
+                continue;
+            }
+
+            let sub_repo = match cache::open_submodule(repo, &submodule) {
                 Ok(sub_repo) => Ok(sub_repo),
                 Err(e) if e.class() == Os && e.code() == NotFound => {
-                    eprintln!("[{}] Submodule {} not found... maybe it needs init?", named_path, submodule.name().expect("Submodule should have name"));
-                    let cmd = Command::new("git")
-                        .arg("submodule")
-                        .arg("update")
-                        .arg("--init")
-                        .arg("--recursive")
-                        .arg(submodule.name().expect("Submodule should have name"))
-                        .current_dir(repo.workdir().expect("Has workdir"))
-                        .output()?;
-                    eprintln!("{}", String::from_utf8(cmd.stdout)?);
-                    eprintln!("{}", String::from_utf8(cmd.stderr)?);
-                    submodule.sync()?;
-                    submodule.update(true, None)?;
-                    submodule.reload(true)?;
-                    submodule.open()
+                    warn!("[{}] Submodule {} not found... maybe it needs init?", named_path, submodule.name().expect("Submodule should have name"));
+                    temp_clones::record(repo, submodule.name().expect("Submodule should have name"), &submodule.path().to_string_lossy());
+                    update_submodule(repo, &mut submodule, &named_path)?;
+                    cache::open_submodule(repo, &submodule)
                 },
                 Err(e) => Err(e)
             }?;
@@ -648,12 +2118,14 @@ fn multi_rebase_inner(repo: &Repository, _submodule: Option<&Submodule>, target:
                 let final_head = submodule_at_tree(&submodule, &head.peel_to_commit()?.tree()?)?;
                 let target_head = submodule_at_tree(&submodule, &tree)?;
                 if let (Some(final_head), Some(target_head)) = (final_head, target_head) {
-                    sub_repo.set_head_detached(final_head)?;
+                    detach_head(&sub_repo, final_head, &format!("sub-rebase: rebasing new submodule onto {}", target_head))?;
 
                     let mut sub_path = path.clone();
                     sub_path.push(sub_name.clone());
-                    let sub_results = recurse_subs(&sub_repo, &sub_repo.find_commit(target_head)?, &multi_rebase_inner)?;
-                    eprintln!("[{}] Rebased new submodule {} with results: {:?}", named_path, submodule.name().expect("Submodule should have name"), &sub_results);
+                    let sub_results = recurse_subs_in(SubWalk::Prepare, &sub_repo, &sub_repo.find_commit(target_head)?, resolver::for_config(config).as_ref(), &|repo: &Repository, submodule, target, path, child_results| {
+                        multi_rebase_inner(config, repo, submodule, target, path, child_results)
+                    })?;
+                    warn!("[{}] Rebased new submodule {} with results: {:?}", named_path, submodule.name().expect("Submodule should have name"), &sub_results);
                     child_results.insert(submodule.path().to_str().expect("Submodule should have path").to_string(), sub_results);
                 } else {
                     return Err(anyhow!(format!("[{}] Cannot rebase newly added inner submodule {}", named_path, submodule.name().expect("Submodule should have name"))));
@@ -663,65 +2135,132 @@ fn multi_rebase_inner(repo: &Repository, _submodule: Option<&Submodule>, target:
             let expected_commit = submodule_at_tree(&submodule, &tree)?;
             if let Some(expected_commit) = expected_commit {
                 let default_results = HashMap::new();
-                let converted_expected = child_results.get(submodule.path().to_str().expect("Submodule should have path")).unwrap_or_else(|| &default_results).get(&expected_commit);
+                let mut converted_expected = child_results.get(submodule.path().to_str().expect("Submodule should have path")).unwrap_or(&default_results).get(&expected_commit).cloned();
+                if converted_expected.is_none() && config.map_notes {
+                    // Pointing at a commit an earlier run rewrote, follow it to what it is now
+                    if let Some(rewritten) = commit_map::read_note(&sub_repo, expected_commit) {
+                        say!("[{}] {} was rewritten to {} by an earlier run", named_path, expected_commit, rewritten);
+                        converted_expected = Some(rewritten);
+                    }
+                }
                 let sub_head = loop {
                     match sub_repo.head().and_then(|h| h.peel_to_commit()) {
                         Ok(commit) => break commit.id(),
-                        _ => {
-                            eprintln!("[{}] Submodule {} has no HEAD id, please check out a branch and press ENTER...", named_path, submodule.name().expect("Submodule should have name"));
-                            let _ = read_stdin()?;
+                        _ => match config.unresolved_head {
+                            UnresolvedHeadPolicy::Prompt => {
+                                warn!("{}", tr!("[{}] Submodule {} has no HEAD id, please check out a branch and press ENTER...", named_path, submodule.name().expect("Submodule should have name")));
+                                prompter::wait_for_resolution(&format!("unresolved-head {}", named_path))?;
 
-                            submodule.reload(true)?;
+                                submodule.reload(true)?;
+                            }
+                            UnresolvedHeadPolicy::Detach => {
+                                warn!("{}", tr!("[{}] Submodule {} has no HEAD id, detaching at {}", named_path, sub_name, expected_commit));
+                                let message = format!("sub-rebase: detach unresolved HEAD at {}", expected_commit);
+                                detach_head(&sub_repo, expected_commit, &message)?;
+                                reset_hard(&sub_repo, &sub_repo.find_object(expected_commit, Some(ObjectType::Commit))?, &message)?;
+                                submodule.reload(true)?;
+                            }
+                            UnresolvedHeadPolicy::Branch => {
+                                let recovery_name = format!("recovered/{}_{}", sub_name.replace('/', "_"), config.run_id);
+                                warn!("{}", tr!("[{}] Submodule {} has no HEAD id, checking out new branch {} at {}", named_path, sub_name, recovery_name, expected_commit));
+                                let recovery_branch = sub_repo.branch(&recovery_name, &sub_repo.find_commit(expected_commit)?, true)?;
+                                let message = format!("sub-rebase: check out {} for unresolved HEAD", recovery_name);
+                                set_head(&sub_repo, recovery_branch.into_reference().name().expect("Branch ref needs name"), &message)?;
+                                reset_hard(&sub_repo, &sub_repo.find_object(expected_commit, Some(ObjectType::Commit))?, &message)?;
+                                submodule.reload(true)?;
+                            }
+                            UnresolvedHeadPolicy::Abort => {
+                                return Err(anyhow!("[{}] Submodule {} has no HEAD id, aborting", named_path, sub_name));
+                            }
                         }
                     };
                 };
 
+                // On a plateau the submodule is already where the last commit put it, nothing to do. If the
+                // pointer went backwards (e.g. a revert), just write the gitlink instead of dragging the
+                // submodule back and forth with hard resets. It'll catch up when the pointer moves forward again
+                let sub_path = submodule.path().to_str().expect("Submodule should have path").to_string();
+                let rewritten = converted_expected.unwrap_or_else(|| translate_gitlink(config, repo, &child_results, &sub_path, expected_commit));
+                match pointers.step(&sub_name, expected_commit, rewritten, sub_head, |from, to| sub_repo.graph_descendant_of(from, to).unwrap_or(false)) {
+                    pointers::PointerMove::Plateau => {
+                        *plateaus.entry((sub_name.clone(), expected_commit)).or_insert(0) += 1;
+                        continue;
+                    }
+                    pointers::PointerMove::Regressed { from, rewritten } => {
+                        say!("[{}] {} goes back from {} to {}, updating the pointer only", named_path, sub_name, from, expected_commit);
+                        let mut index = repo.index()?;
+                        index.add(&gitlink_entry(&sub_path, rewritten))?;
+                        index.write()?;
+                        regressions.push((op.id(), sub_name.clone(), from, expected_commit));
+                        continue;
+                    }
+                    pointers::PointerMove::Moved => {}
+                }
+
                 if sub_head != expected_commit {
                     if let Some(converted) = converted_expected {
-                        if sub_head != *converted {
-                            println!("[{}] Should expect {} to be at {}, it's at {}", named_path, sub_name, converted, sub_head);
+                        if sub_head != converted {
+                            say!("[{}] Should expect {} to be at {}, it's at {}", named_path, sub_name, converted, sub_head);
 
-                            sub_repo.set_head(sub_repo.find_branch("multi_rebase_cur", BranchType::Local)?.into_reference().name().expect("Branch ref needs name"))?;
-                            sub_repo.reset(&sub_repo.find_object(*converted, Some(ObjectType::Commit))?, ResetType::Hard, Some(CheckoutBuilder::new().borrow_mut()))?;
+                            point_submodule_at(&sub_repo, converted)?;
 
                             repo.index()?.update_all(&[submodule.path()], None)?;
                             repo.index()?.add_path(submodule.path())?;
                             repo.index()?.write()?;
-                            println!("[{}] Update submodule {} to {}", named_path, sub_name, *converted);
+                            say!("[{}] Update submodule {} to {}", named_path, sub_name, converted);
                         }
                     } else {
-                        println!("[{}] Should expect {} to be at {}, it's at {}", named_path, sub_name, expected_commit, sub_head);
+                        say!("[{}] Should expect {} to be at {}, it's at {}", named_path, sub_name, expected_commit, sub_head);
 
-                        sub_repo.set_head(sub_repo.find_branch("multi_rebase_cur", BranchType::Local)?.into_reference().name().expect("Branch ref needs name"))?;
-                        sub_repo.reset(&sub_repo.find_object(expected_commit, Some(ObjectType::Commit))?, ResetType::Hard, Some(CheckoutBuilder::new().borrow_mut()))?;
+                        point_submodule_at(&sub_repo, expected_commit)?;
 
                         repo.index()?.update_all(&[submodule.path()], None)?;
                         repo.index()?.add_path(submodule.path())?;
                         repo.index()?.write()?;
-                        println!("[{}] Update submodule {} to {}", named_path, sub_name, expected_commit);
+                        say!("[{}] Update submodule {} to {}", named_path, sub_name, expected_commit);
                     }
                 }
             } else {
-                println!("[{}] Submodule {} revision", named_path, sub_name);
+                say!("[{}] Submodule {} revision", named_path, sub_name);
             }
         }
 
         // Then just try to commit and see if it works
+        let mut conflict_attempts = 0;
         let new_id = loop {
-            match rebase.commit(None, &repo.signature()?, None) {
+            let original = repo.find_commit(op.id())?;
+            let author = rewritten_author(config, repo, &original)?;
+            let committer = rewritten_committer(config, repo, &original, author.as_ref())?;
+            if !guard_large_blobs(config, repo, &named_path, &original)? {
+                rebase.abort()?;
+                return Err(anyhow!("[{}] Stopped before committing large files in place of {}", named_path, op.id()));
+            }
+            match rebase.commit(author.as_ref(), &committer, None) {
                 Ok(id) => {
                     // Commit rebased, run post-commit hooks and sign it if possible
-                    let status = Command::new("git")
-                        .arg("commit")
+                    let mut amend = Command::new("git");
+                    amend.arg("commit")
                         .arg("--amend")
                         .arg("--no-edit")
-                        .arg("--no-verify")
-                        .current_dir(repo.workdir().expect("Has workdir"))
-                        .spawn()?
-                        .wait()?;
-
-                    if !status.success() {
-                        return Err(anyhow!("Amending commit after rebase pick failed: {:?}", status.code()));
+                        .current_dir(repo.workdir().expect("Has workdir"));
+                    let verify = config.verify && !config.no_verify;
+                    if !verify {
+                        amend.arg("--no-verify");
+                    }
+                    if config.preserve_committer || config.committer_date_is_author_date || config.committer.is_some() {
+                        // Otherwise the amend stamps a fresh committer over ours
+                        amend.env("GIT_COMMITTER_NAME", committer.name().unwrap_or(""))
+                            .env("GIT_COMMITTER_EMAIL", committer.email().unwrap_or(""))
+                            .env("GIT_COMMITTER_DATE", git_date_string(&committer.when()));
+                    }
+                    if let Some(output) = watchdog::run_command(&named_path, "git commit --amend", &mut amend)? {
+                        say!("{}", String::from_utf8_lossy(&output.stdout).trim_end());
+                        eprint!("{}", String::from_utf8_lossy(&output.stderr));
+                        if !output.status.success() && verify {
+                            return Err(anyhow!("[{}] Commit hooks rejected {}, fix them or rerun with --no-verify: {:?}", named_path, op.id(), output.status.code()));
+                        } else if !output.status.success() {
+                            return Err(anyhow!("Amending commit after rebase pick failed: {:?}", output.status.code()));
+                        }
                     }
 
                     // The hook could have changed the commit hash
@@ -729,105 +2268,346 @@ fn multi_rebase_inner(repo: &Repository, _submodule: Option<&Submodule>, target:
                 },
                 Err(e) if e.code() == Applied && e.class() == Rebase => {
                     // Whatever the last commit is, should be the new id
-                    println!("[{}] Commit patch was already applied! Assuming that means we can ignore it.", named_path);
+                    say!("[{}] Commit patch was already applied! Assuming that means we can ignore it.", named_path);
                     break repo.head()?.peel_to_commit()?.id()
                 }
                 Err(e) => {
-                    eprintln!("[{}] {}", named_path, e);
-
-                    // Let user resolve and then continue
-                    eprintln!("[{}] Rebase conflict!", named_path);
-                    eprintln!("[{}] Please resolve then press enter when satisfied", named_path);
-
-                    let _ = read_stdin()?;
+                    warn!("[{}] {}", named_path, e);
+                    match resolve_conflict(config, repo, &named_path, Some(op.id()), conflict_attempts == 0)? {
+                        ConflictResolution::Resolved => {}
+                        ConflictResolution::Skip if MissingCommitsCheck::for_repo(config, repo) == MissingCommitsCheck::Error => {
+                            warn!("[{}] Not dropping {}, rebase.missingCommitsCheck is error", named_path, op.id());
+                        }
+                        ConflictResolution::Skip => {
+                            if MissingCommitsCheck::for_repo(config, repo) == MissingCommitsCheck::Warn {
+                                warn!("[{}] Warning: dropping {} ({})", named_path, op.id(), repo.find_commit(op.id())?.summary().unwrap_or(""));
+                            }
+                            // Throw away the pick, the dropped commit maps to whatever came before it
+                            let current = repo.head()?.peel_to_commit()?;
+                            say!("[{}] Skipping commit {}, reset (hard) to {}", named_path, op.id(), current.id());
+                            output::event(&["skip", &named_path, &op.id().to_string()]);
+                            reset_hard(repo, current.as_object(), &format!("sub-rebase: skip {}", op.id()))?;
+                            break current.id();
+                        }
+                        ConflictResolution::Abort => {
+                            rebase.abort()?;
+                            return Err(Failure::ConflictAborted(named_path).into());
+                        }
+                    }
+                    conflict_attempts += 1;
                 }
             }
         };
 
-        println!("[{}] Rebased commit {} --> {}", named_path, op.id(), new_id);
+        say!("[{}] Rebased commit {} {} {}", named_path, op.id(), output::arrow(), new_id);
+        output::event(&["rewrite", &named_path, &op.id().to_string(), &new_id.to_string()]);
+        output::picked();
         commit_map.insert(op.id(), new_id);
     }
-    rebase.finish(Some(&repo.signature()?))?;
+    rebase.finish(Some(&default_committer(config, repo)?))?;
 
-    // Revert head for parent to rebase
-    match head.name() {
-        Some("HEAD") | None => {
-            let id = head.peel_to_commit()?.id();
-            println!("[{}] Set HEAD to {}", named_path, id.to_string());
-            repo.set_head_detached(id)?;
-        }
-        Some(head_name) => {
-            println!("[{}] Set HEAD to {}", named_path, head_name);
-            repo.set_head(head_name)?;
+    for ((sub_name, pointer), skipped) in &plateaus {
+        say!("[{}] {} stayed at {} for {} more commits, left it alone", named_path, sub_name, pointer, skipped);
+    }
+    if !regressions.is_empty() {
+        say!("[{}] Submodule pointers that went backwards:", named_path);
+        for (commit, sub_name, from, to) in &regressions {
+            say!("[{}]   {} in {}: {} {} {}", named_path, sub_name, commit, from, output::arrow(), to);
         }
     }
 
-    println!("[{}] Reset HEAD (hard) to finalized commit {}", named_path, head.peel_to_commit()?.id().to_string());
-    repo.reset(&head.peel_to_commit()?.into_object(), ResetType::Hard, Some(CheckoutBuilder::new().borrow_mut()))?;
+    // Revert head for parent to rebase
+    say!("[{}] Set HEAD to {}", named_path, head_state);
+    head_state.restore(repo)?;
+
+    say!("[{}] Reset HEAD (hard) to finalized commit {}", named_path, head.peel_to_commit()?.id().to_string());
+    let finalized = head.peel_to_commit()?.into_object();
+    watchdog::watch(&named_path, "checkout", || reset_hard(repo, &finalized, &format!("sub-rebase: rebased onto {}", target.id())))?;
 
     // Reset subs
     for (sub, _) in &child_results {
         if let Some(sub_head_name) = sub_heads.get(sub) {
             let res_submodule = repo.find_submodule(sub)?;
-            let sub_repo = res_submodule.open()?;
+            let sub_repo = cache::open_submodule(repo, &res_submodule)?;
             let sub_head = sub_repo.find_reference(sub_head_name)?;
             if sub_head.name().expect("Head should have a name") != "HEAD" {
-                println!("[{}] Set submodule {} HEAD to {}", named_path, sub, sub_head.name().expect("Need refname"));
-                sub_repo.set_head(sub_head.name().expect("Sub head has name"))?;
+                say!("[{}] Set submodule {} HEAD to {}", named_path, sub, sub_head.name().expect("Need refname"));
+                set_head(&sub_repo, sub_head.name().expect("Sub head has name"), "sub-rebase: put back submodule HEAD")?;
             }
-            println!("[{}] Reset submodule {} HEAD (hard) to finalized commit {}", named_path, sub, sub_head.peel_to_commit()?.id().to_string());
-            sub_repo.reset(&sub_head.peel_to_commit()?.into_object(), ResetType::Hard, Some(CheckoutBuilder::new().borrow_mut()))?;
+            say!("[{}] Reset submodule {} HEAD (hard) to finalized commit {}", named_path, sub, sub_head.peel_to_commit()?.id().to_string());
+            let finalized = sub_head.peel_to_commit()?.into_object();
+            watchdog::watch(&named_path, "submodule checkout", || reset_hard(&sub_repo, &finalized, "sub-rebase: put back submodule HEAD"))?;
         }
     }
 
     Ok(commit_map)
 }
 
-fn main() -> Result<()> {
+// Put HEAD back how it was in a repo the rebase didn't need to touch
+fn restore_untouched_head(repo: &Repository, named_path: &str, original_head: Option<&HeadState>) -> Result<()> {
+    let original_head = match original_head {
+        Some(original_head) => original_head,
+        None => return Ok(()),
+    };
+    if HeadState::capture(repo)? == *original_head {
+        return Ok(());
+    }
+    say!("[{}] Set HEAD to {}", named_path, original_head);
+    original_head.restore(repo)?;
+    // Unborn branches have nothing to check out
+    if let Ok(commit) = repo.head().and_then(|h| h.peel_to_commit()) {
+        say!("[{}] Reset HEAD (hard) to {}", named_path, commit.id());
+        reset_hard(repo, commit.as_object(), "sub-rebase: restore HEAD")?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let code = match run() {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            output::transcribe("error", &format!("{:?}", e));
+            for cause in e.chain() {
+                if let Some(e) = cause.downcast_ref::<git2::Error>() {
+                    debug!("libgit2 error class {:?}, code {:?}", e.class(), e.code());
+                }
+            }
+            match e.downcast_ref::<Failure>() {
+                Some(failure) => failure.exit_code(),
+                None => 1,
+            }
+        }
+    };
+    output::event(&["done", &code.to_string()]);
+    output::transcribe("done", &code.to_string());
+    std::process::exit(code);
+}
+
+fn set_output(config: &Config) {
+    output::set_porcelain(config.porcelain);
+    output::set_plain(config.plain);
+    output::set_verbosity(config.verbose, config.quiet);
+    output::set_color(match config.color {
+        ColorWhen::Always => Some(true),
+        ColorWhen::Never => Some(false),
+        ColorWhen::Auto if config.plain || std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) => Some(false),
+        ColorWhen::Auto => None,
+    });
+    messages::set_locale(messages::Locale::detect(config.lang.as_deref()));
+    output::set_notify(config.bell, config.notify_command.clone());
+}
+
+// The repo this one is checked out in as a submodule, and its path there. None for a repo that
+// just happens to be inside another's working copy without being one of its submodules
+fn superproject(repo: &Repository) -> Option<(Repository, String)> {
+    let workdir = repo.workdir()?;
+    let parent = Repository::discover(workdir.parent()?).ok()?;
+    let path = workdir.strip_prefix(parent.workdir()?).ok()?.to_path_buf();
+    let is_submodule = parent.submodules().ok()?.iter().any(|submodule| submodule.path() == path);
+    if is_submodule {
+        Some((parent, path.to_string_lossy().to_string()))
+    } else {
+        None
+    }
+}
+
+fn run() -> Result<()> {
     ctrlc::set_handler(move || {
         INTERRUPTED.store(true, atomic::Ordering::SeqCst);
     })?;
 
+    // Before anything else, so relative paths in other options are from there too
+    if let Some(dir) = Config::clap().get_matches().value_of_os("repo") {
+        std::env::set_current_dir(dir).map_err(|e| anyhow!("Can't change to {}: {}", PathBuf::from(dir).display(), e))?;
+    }
+    // From anywhere inside the working copy, like git
     let base = std::env::current_dir()?;
-    let repo = Repository::open(&base)?;
+    let repo = Repository::discover(&base);
 
-    let config = Config::from_args();
+    let mut config = settings::load(repo.as_ref().ok())?;
+    set_output(&config);
+    let mut prompter: Box<dyn prompter::Prompter + Send> = if config.yes {
+        Box::new(prompter::YesPrompter)
+    } else {
+        Box::new(prompter::StdinPrompter)
+    };
+    if let Some(answers) = &config.answers {
+        prompter = Box::new(prompter::AnswersPrompter::load(answers, prompter)?);
+    }
+    prompter::set_prompter(prompter);
+    if config.serve {
+        return serve::run();
+    }
+    // Help and completion scripts work outside of a repo too
+    if let Some(SubCommand::Help(command)) = &config.command {
+        return help::run(command);
+    }
+    if let Some(SubCommand::Completions(command)) = &config.command {
+        return completions::run(repo.as_ref().ok(), command);
+    }
+    let mut repo = repo?;
+    // Standing in a submodule, the rebase most likely wants to start from the top
+    let mut walked_up = false;
+    while let Some((parent, path)) = superproject(&repo).filter(|_| config.repo.is_none()) {
+        say!("{}", tr!("{} is a submodule of {}", path, parent.workdir().expect("Has workdir").display()));
+        say!("{}", tr!("Rebase from the superproject instead? [Y/n]"));
+        if !prompter::confirm("superproject", true)? {
+            break;
+        }
+        repo = parent;
+        walked_up = true;
+    }
+    if walked_up {
+        // Its settings, not the submodule's
+        config = settings::load(Some(&repo))?;
+        set_output(&config);
+    }
+    if config.yes {
+        if config.on_conflict == OnConflictPolicy::Prompt {
+            config.on_conflict = OnConflictPolicy::Abort;
+        }
+        if config.unresolved_head == UnresolvedHeadPolicy::Prompt {
+            config.unresolved_head = UnresolvedHeadPolicy::Abort;
+        }
+    }
+    watchdog::set_stall_timeout(config.stall_timeout);
+    checkout::set_workers(config.checkout_workers);
+    if let Some(prefix) = &config.backup_prefix {
+        if config.backup_name == DEFAULT_BACKUP_NAME {
+            config.backup_name = format!("{}{{branch}}_{{run}}", prefix);
+        }
+    }
+    // With the date, so runs at the same time on different days don't share backup names
+    config.run_id = Local::now().format("%Y-%m-%d-%H-%M-%S").to_string();
+    if let Some(name) = &config.name {
+        // It goes into branch names
+        let name = name.trim().replace(char::is_whitespace, "-");
+        if !git2::Reference::is_valid_name(&format!("refs/heads/{}", name)) {
+            return Err(anyhow!("Run name {} can't be used in a branch name", name));
+        }
+        config.run_id = format!("{}-{}", name, config.run_id);
+    }
+    let transcript = repo.path().join("sub-rebase").join("log");
+    let header = format!("{} ({})", std::env::args().collect::<Vec<_>>().join(" "), config.run_id);
+    if let Err(e) = output::open_transcript(&transcript, &header) {
+        warn!("Can't write the transcript to {}: {}", transcript.display(), e);
+    }
 
-    let stats = repo.diff_index_to_workdir(None, None)?.stats()?;
-    if stats.files_changed() != 0 {
-        eprintln!("Cannot run with a dirty working copy! Please stash first.");
-        return Err(Error::msg("Dirty working copy"));
+    match &config.command {
+        Some(SubCommand::Backups(command)) => return backups::run(&repo, command),
+        Some(SubCommand::Config(command)) => return settings::run(&repo, command),
+        Some(SubCommand::Conflicts) => return conflicts::run(&repo),
+        Some(SubCommand::Undo) => return backups::undo(&repo),
+        Some(SubCommand::RangeDiff { run_id }) => return backups::range_diff(&repo, run_id.as_deref()),
+        Some(SubCommand::Help(_)) | Some(SubCommand::Completions(_)) | None => {}
+    }
+    // Removed again whenever run() returns, after the autostash is popped
+    let bare_worktree;
+    if repo.is_bare() {
+        bare_worktree = bare_worktree::BareWorktree::create(&repo, &config.run_id, config.keep_worktree)?;
+        repo = bare_worktree.open()?;
+    }
+    if config.stdin_refs {
+        read_stdin_refs(&mut config, &repo)?;
+    }
+    let ref_ = config.ref_.clone().ok_or(anyhow!("No ref for the superproject given on stdin"))?;
+
+    if config.fetch_all {
+        let jobs = config.jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()));
+        fetch::fetch_all(&repo, jobs)?;
+    } else if config.fetch {
+        let remote = match fetch::target_remote(&repo, &ref_) {
+            Some(remote) => remote,
+            None => {
+                warn!("{} isn't a remote-tracking branch, so fetching won't move it", ref_);
+                fetch::default_remote(&repo, None)?.ok_or_else(|| anyhow!("No remote to fetch"))?
+            }
+        };
+        fetch::fetch_tree(&repo, &remote)?;
     }
 
     // I ~don't~ know where I'm going, but I'm on my way
     // The road goes on forever, but the party never ends
     // - Warriors
-    let target = match repo.resolve_reference_from_short_name(config.ref_.as_str()) {
+    let target = match repo.resolve_reference_from_short_name(ref_.as_str()) {
         Ok(obj) => obj.peel_to_commit()?,
         Err(e) => {
-            eprintln!("Cannot find object {}: {}", config.ref_, e);
+            warn!("{}", tr!("Cannot find object {}: {}", ref_, e));
             return Err(Error::from(e));
         }
     };
 
+    shallow::deepen_for_base("*root*", &repo, repo.head()?.peel_to_commit()?.id(), target.id(), !config.deepen)?;
+
+    let resolver = resolver::for_config(&config);
+    recurse_subs_in(SubWalk::Prepare, &repo, &target, resolver.as_ref(), &|repo: &Repository, submodule, target: &Commit, path, _child_results| -> Result<()> {
+        if submodule.is_some() && !is_excluded(&config, path) {
+            shallow::deepen_for_base(&sub_path_to_string(path), repo, repo.head()?.peel_to_commit()?.id(), target.id(), !config.deepen)?;
+        }
+        Ok(())
+    })?;
+
+    output::event(&["phase", "check"]);
+    // Popped again whenever run() returns
+    let auto_stash = RefCell::new(autostash::AutoStash::new());
+    let plan = plan::plan(&config, &repo, &target, resolver.as_ref(), &auto_stash)?;
     // Make sure nobody is locked
-    recurse_subs(&repo, &target, &|repo: &Repository, _submodule, _target, path, child_results| -> Result<()> {
-        let mut worktree = PathBuf::from(repo.path());
-        worktree.push("index.lock");
-        if worktree.exists() {
-            return Err(anyhow!("Lockfile for {} exists, please finish your operations or delete it before starting.", sub_path_to_string(path)));
+    if let Some(locked) = plan.locked() {
+        return Err(anyhow!("Lockfile for {} exists, please finish your operations or delete it before starting.", sub_path_to_string(&locked.path)));
+    }
+    if sparse::changed_files(&repo, None)? != 0 {
+        warn!("{}", tr!("Cannot run with a dirty working copy! Please stash first."));
+        return Err(Failure::DirtyWorkingCopy.into());
+    }
+
+    if !config.stack.is_empty() {
+        check_stack(&repo, &config.stack)?;
+    }
+
+    if !plan.needs_rebase() {
+        say!("{}", tr!("Everything is already on top of {}", ref_));
+        return Err(Failure::NothingToRebase.into());
+    }
+
+    // Partial clones get everything the rebase will read up front, libgit2 can't fetch it on the way
+    recurse_subs(&repo, &target, resolver.as_ref(), &|repo: &Repository, _submodule, target: &Commit, path, _child_results| -> Result<()> {
+        if !is_excluded(&config, path) {
+            let head = repo.head()?.peel_to_commit()?.id();
+            promisor::prefetch(&sub_path_to_string(path), repo, cache::merge_base(repo, head, target.id())?, head, target.id())?;
         }
         Ok(())
     })?;
 
-    update_submodules(&repo, &target)?;
+    if config.lint {
+        let problems = recurse_subs(&repo, &target, resolver.as_ref(), &|repo: &Repository, _submodule, target: &Commit, path, child_results: HashMap<String, usize>| -> Result<usize> {
+            let named_path = sub_path_to_string(path);
+            let head = repo.head()?.peel_to_commit()?.id();
+            let base = cache::merge_base(repo, head, target.id())?;
+            let report = if is_excluded(&config, path) { vec![] } else { lint::lint_range(&config, repo, base, head)? };
+            for (commit, summary, problems) in &report {
+                warn!("[{}] {} {}", named_path, commit, summary);
+                for problem in problems {
+                    warn!("[{}]   {}", named_path, problem);
+                }
+            }
+            Ok(report.len() + child_results.values().sum::<usize>())
+        })?;
+        if problems != 0 {
+            warn!("Lint found problems in {} commits, fix them before rebasing", problems);
+            return Err(Failure::LintFailed.into());
+        }
+        say!("Lint found no problems");
+    }
+
+    output::event(&["phase", "prepare"]);
+    update_submodules(&config, &repo, &target, &plan)?;
 
     // Find the named branches all the submodules were using so we can update them after the rebase
-    let original_branch_names = recurse_subs(&repo, &target, &|repo: &Repository, _submodule, _target, _path, child_results: HashMap<String, HashMap<Vec<String>, String>>| -> Result<HashMap<Vec<String>, String>> {
-        let head = repo.head()?;
+    let original_heads = recurse_subs(&repo, &target, resolver.as_ref(), &|repo: &Repository, _submodule, _target, _path, child_results: HashMap<String, HashMap<Vec<String>, HeadState>>| -> Result<HashMap<Vec<String>, HeadState>> {
         let mut results = HashMap::new();
 
-        results.insert(vec![], head.name().expect("Ref expected name").into());
+        results.insert(vec![], HeadState::capture(repo)?);
         for (path, c_results) in child_results {
             for (mut cpath, cvalue) in c_results.into_iter() {
                 cpath.insert(0, path.clone());
@@ -838,44 +2618,64 @@ fn main() -> Result<()> {
         Ok(results)
     })?;
 
-    println!("Submodule branches to restore after running:");
-    let mut sorted_names = original_branch_names.iter().collect::<Vec<(_, _)>>();
+    say!("{}", tr!("Submodule branches to restore after running:"));
+    let mut sorted_names = original_heads.iter().map(|(path, head)| (path, head.to_string())).collect::<Vec<(_, _)>>();
     sorted_names.sort();
     let max_sub_len = sorted_names.iter().map(|(path, _)| sub_path_to_string(path).len()).max().unwrap_or(0);
     for (path, branch) in sorted_names {
-        println!("{}:{} {}", sub_path_to_string(path), String::from_utf8(vec![b' '; max_sub_len - sub_path_to_string(path).len()])?, branch);
+        say!("{}:{} {}", sub_path_to_string(path), output::pad(sub_path_to_string(path).len(), max_sub_len), branch);
+    }
+
+    if config.select_submodules {
+        select_submodules(&mut config, &repo, &target, resolver.as_ref())?;
     }
+    if config.edit_plan {
+        todo::edit(&mut config, &repo, &target, resolver.as_ref())?;
+    }
+
+    let total_commits = show_plan(&config, &repo, &target, resolver.as_ref())?;
 
-    println!("Press ENTER to begin...");
-    let _ = read_stdin()?;
+    say!("{}", tr!("Press ENTER to begin..."));
+    prompter::wait_for_resolution("begin")?;
 
     // Rebase!
-    println!("REBASE!! START!!");
-    if let Err(e) = recurse_subs(&repo, &target, &multi_rebase_inner) {
-        println!("Reverting branches...");
+    say!("{}", tr!("REBASE!! START!!"));
+    output::event(&["phase", "rebase"]);
+    let commit_maps = RefCell::new(commit_map::CommitMaps::new());
+    output::start_progress(total_commits);
+    let rebased = recurse_subs(&repo, &target, resolver.as_ref(), &|repo: &Repository, submodule, target, path, child_results| {
+        let key = dedupe::key(repo, submodule, target.id(), &child_results);
+        let commit_map = multi_rebase_inner(&config, repo, submodule, target, path, child_results)?;
+        if let Some(key) = key {
+            dedupe::record(key, repo, &sub_path_to_string(path), &commit_map);
+        }
+        commit_maps.borrow_mut().insert(path.clone(), commit_map.clone());
+        Ok(commit_map)
+    });
+    output::end_progress();
+    if let Err(e) = rebased {
+        say!("{}", tr!("Reverting branches..."));
+        output::event(&["phase", "revert"]);
 
         // Revert branches
-        recurse_subs(&repo, &target, &|repo: &Repository, _submodule, _target, path, _child_results| {
+        recurse_subs(&repo, &target, resolver.as_ref(), &|repo: &Repository, _submodule, _target, path, _child_results| {
             let named_path = sub_path_to_string(path);
             let rebase_old = repo.find_branch("multi_rebase_old", BranchType::Local);
             if let Err(_) = rebase_old {
-                // Not touched
-                println!("[{}] Already done", named_path);
-                return Ok(());
+                // Not touched, but something (like excluding it) may have moved its HEAD
+                say!("[{}] Already done", named_path);
+                return restore_untouched_head(repo, &named_path, original_heads.get(path));
             }
             let old_head = rebase_old?.into_reference().peel_to_commit()?;
-            let update_branch = original_branch_names.get(path);
-            if let Some(branch_name) = update_branch {
-                if branch_name != "HEAD" {
-                    println!("[{}] Set HEAD to {}", named_path, branch_name);
-                    repo.set_head(&branch_name)?;
-                }
-                println!("[{}] Reset HEAD (hard) to old commit {}", named_path, old_head.id());
-                repo.reset(&old_head.into_object(), ResetType::Hard, Some(CheckoutBuilder::new().borrow_mut()))?;
+            if let Some(original_head) = original_heads.get(path) {
+                say!("[{}] Set HEAD to {}", named_path, original_head);
+                original_head.restore(repo)?;
+                say!("[{}] Reset HEAD (hard) to old commit {}", named_path, old_head.id());
+                watchdog::watch(&named_path, "checkout", || reset_hard(repo, old_head.as_object(), "sub-rebase: revert to before the rebase"))?;
             }
 
             // Clean up extra branches
-            println!("[{}] Cleaning up branches", named_path);
+            say!("[{}] Cleaning up branches", named_path);
             repo.find_branch("multi_rebase_old", BranchType::Local)?.into_reference().delete()?;
             repo.find_branch("multi_rebase_cur", BranchType::Local)?.into_reference().delete()?;
             repo.find_branch("multi_rebase_new", BranchType::Local)?.into_reference().delete()?;
@@ -884,31 +2684,92 @@ fn main() -> Result<()> {
             Ok(())
         })?;
 
-        println!("REBASE FAIL!");
+        say!("{}", tr!("REBASE FAIL!"));
         return Err(e);
     } else {
         // Switch branches to multi_rebase_new for all repos
-        recurse_subs(&repo, &target, &|repo: &Repository, _submodule, _target, path, _child_results| {
+        output::event(&["phase", "finish"]);
+        recurse_subs(&repo, &target, resolver.as_ref(), &|repo: &Repository, _submodule, _target, path, _child_results| {
             let named_path = sub_path_to_string(path);
             let rebase_new = repo.find_branch("multi_rebase_new", BranchType::Local);
             if let Err(_) = rebase_new {
-                // Not touched
-                println!("[{}] Already done", named_path);
-                return Ok(());
+                // Not touched, but something (like excluding it) may have moved its HEAD
+                say!("[{}] Already done", named_path);
+                return restore_untouched_head(repo, &named_path, original_heads.get(path));
             }
             let new_head = rebase_new?.into_reference().peel_to_commit()?;
-            let update_branch = original_branch_names.get(path);
-            if let Some(branch_name) = update_branch {
-                if branch_name != "HEAD" {
-                    println!("[{}] Set HEAD to {}", named_path, branch_name);
-                    repo.set_head(&branch_name)?;
+            if let Some(original_head) = original_heads.get(path) {
+                say!("[{}] Set HEAD to {}", named_path, original_head);
+                original_head.restore(repo)?;
+                say!("[{}] Reset HEAD (hard) to finalized commit {}", named_path, new_head.id());
+                watchdog::watch(&named_path, "checkout", || reset_hard(repo, new_head.as_object(), &format!("sub-rebase: finish rebasing onto {}", ref_)))?;
+            }
+
+            if let Some(map) = commit_maps.borrow().get(path) {
+                let copied = commit_map::copy_notes(repo, map, &default_committer(&config, repo)?)?;
+                if copied != 0 {
+                    say!("[{}] Copied {} notes to rewritten commits", named_path, copied);
+                }
+            }
+            if let Some(map) = commit_maps.borrow().get(path) {
+                // Like git rebase, tell post-rewrite hooks what became of each commit
+                let rewrites = commit_map::rewrites(repo, map)?;
+                if !rewrites.is_empty() {
+                    let input = rewrites.iter().map(|(old, new)| format!("{} {}\n", old, new)).collect::<String>();
+                    if let Some(status) = hooks::run_hook(repo, "post-rewrite", &["rebase"], Some(&input))? {
+                        if !status.success() {
+                            warn!("[{}] post-rewrite hook failed: {:?}", named_path, status.code());
+                        }
+                    }
+                }
+            }
+            if config.map_notes {
+                if let Some(map) = commit_maps.borrow().get(path) {
+                    let written = commit_map::write_notes(repo, map, &default_committer(&config, repo)?, &config.run_id)?;
+                    say!("[{}] Noted {} rewritten commits in {}", named_path, written, commit_map::NOTES_REF);
+                }
+            }
+            if path.is_empty() && !config.stack.is_empty() {
+                if let Some(map) = commit_maps.borrow().get(path) {
+                    for (name, old, new) in commit_map::update_branches(repo, map, Some(&config.stack), &config.run_id)? {
+                        say!("[{}] Moved stacked branch {}: {} {} {}", named_path, name, old, output::arrow(), new);
+                    }
+                }
+            }
+            if rebase_config_flag(repo, "rebase.updateRefs", config.update_refs, config.no_update_refs) {
+                if let Some(map) = commit_maps.borrow().get(path) {
+                    for (name, old, new) in commit_map::update_branches(repo, map, None, &config.run_id)? {
+                        say!("[{}] Updated branch {}: {} {} {}", named_path, name, old, output::arrow(), new);
+                    }
+                }
+            }
+            if config.retag {
+                if let Some(map) = commit_maps.borrow().get(path) {
+                    let tags = commit_map::tags_to_move(repo, map)?;
+                    if !tags.is_empty() {
+                        say!("[{}] Tags pointing at rewritten commits:", named_path);
+                        for (name, old, new) in &tags {
+                            say!("[{}]   {}: {} {} {}", named_path, name, old, output::arrow(), new);
+                        }
+                        say!("{}", tr!("Move them? [Y/n]"));
+                        if prompter::confirm(&format!("retag {}", named_path), true)? {
+                            for (name, _old, new) in &tags {
+                                commit_map::move_tag(repo, name, *new)?;
+                                say!("[{}] Moved tag {} to {}", named_path, name, new);
+                            }
+                        }
+                    }
+                }
+            }
+            if config.replace_refs {
+                if let Some(map) = commit_maps.borrow().get(path) {
+                    let written = commit_map::write_replace_refs(repo, map, &config.run_id)?;
+                    say!("[{}] Made {} replace refs for rewritten commits", named_path, written);
                 }
-                println!("[{}] Reset HEAD (hard) to finalized commit {}", named_path, new_head.id());
-                repo.reset(&new_head.into_object(), ResetType::Hard, Some(CheckoutBuilder::new().borrow_mut()))?;
             }
 
             // Clean up extra branches
-            println!("[{}] Cleaning up branches", named_path);
+            say!("[{}] Cleaning up branches", named_path);
             repo.find_branch("multi_rebase_old", BranchType::Local)?.into_reference().delete()?;
             repo.find_branch("multi_rebase_cur", BranchType::Local)?.into_reference().delete()?;
             repo.find_branch("multi_rebase_new", BranchType::Local)?.into_reference().delete()?;
@@ -917,7 +2778,44 @@ fn main() -> Result<()> {
             Ok(())
         })?;
 
-        println!("REBASE!! DONE!!");
+        say!("{}", tr!("REBASE!! DONE!!"));
+        say!("{}", tr!("Run id: {}", config.run_id));
+        let (hits, misses) = cache::stats();
+        debug!("Lookup cache: {} hits, {} misses", hits, misses);
+        let (reused, opened) = cache::repo_stats();
+        debug!("Submodule repos: {} reused, {} opened", reused, opened);
+
+        if !config.exclude_submodules.is_empty() {
+            say!("{}", tr!("Deferred submodules, still on their old commits:"));
+            for name in &config.exclude_submodules {
+                say!("{}", name);
+            }
+        }
+
+        if let Some(map_out) = &config.map_out {
+            let map_out = PathBuf::from(map_out.to_string_lossy().replace("{run}", &config.run_id));
+            commit_map::write_json(&commit_maps.borrow(), &map_out)?;
+        }
+
+        if config.push {
+            // Innermost first, the way recurse_subs goes
+            let pushes = RefCell::new(vec![]);
+            recurse_subs(&repo, &target, resolver.as_ref(), &|repo: &Repository, _submodule, _target, path, _child_results| -> Result<()> {
+                let rewritten = match commit_maps.borrow().get(path) {
+                    Some(map) => !commit_map::rewrites(repo, map)?.is_empty(),
+                    None => false,
+                };
+                if rewritten {
+                    if let Some(push) = push::plan(repo, &sub_path_to_string(path))? {
+                        pushes.borrow_mut().push(push);
+                    }
+                }
+                Ok(())
+            })?;
+            push::push(&pushes.into_inner())?;
+        }
+
+        temp_clones::offer_cleanup()?;
     }
 
     return Ok(());
@@ -0,0 +1,141 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// Message catalog for prompts and run-level messages, gettext style: the English text is the key, so
+// anything missing from a catalog (like the per-repo progress lines) stays in English. Use tr! for
+// anything a person has to read and act on.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Locale {
+    En,
+    De,
+}
+
+impl Locale {
+    // "de", "de_DE.UTF-8" and so on. Anything we don't have gets English
+    pub fn from_name(name: &str) -> Locale {
+        match name.split(['_', '-', '.']).next().unwrap_or("").to_ascii_lowercase().as_str() {
+            "de" => Locale::De,
+            _ => Locale::En,
+        }
+    }
+
+    // --lang (or its setting), then the usual locale environment variables
+    pub fn detect(lang: Option<&str>) -> Locale {
+        if let Some(lang) = lang {
+            return Locale::from_name(lang);
+        }
+        for var in &["LC_ALL", "LC_MESSAGES", "LANG"] {
+            if let Ok(value) = std::env::var(var) {
+                if !value.is_empty() {
+                    return Locale::from_name(&value);
+                }
+            }
+        }
+        Locale::En
+    }
+}
+
+static LOCALE: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_locale(locale: Locale) {
+    LOCALE.store(locale as u8, Ordering::SeqCst);
+}
+
+fn locale() -> Locale {
+    match LOCALE.load(Ordering::SeqCst) {
+        1 => Locale::De,
+        _ => Locale::En,
+    }
+}
+
+const DE: &[(&str, &str)] = &[
+    ("Plan:", "Plan:"),
+    ("Press ENTER to begin...", "ENTER drücken, um zu beginnen..."),
+    ("REBASE!! START!!", "REBASE!! LOS!!"),
+    ("REBASE!! DONE!!", "REBASE!! FERTIG!!"),
+    ("Run id: {}", "Lauf-ID: {}"),
+    ("REBASE FAIL!", "REBASE FEHLGESCHLAGEN!"),
+    ("Reverting branches...", "Setze Branches zurück..."),
+    ("Cannot run with a dirty working copy! Please stash first.", "Die Arbeitskopie hat Änderungen! Bitte zuerst stashen."),
+    ("Cannot find object {}: {}", "Objekt {} nicht gefunden: {}"),
+    ("Everything is already on top of {}", "Alles liegt bereits auf {}"),
+    ("Submodule branches to restore after running:", "Branches der Submodule, die danach wiederhergestellt werden:"),
+    ("Deferred submodules, still on their old commits:", "Zurückgestellte Submodule, noch auf ihren alten Commits:"),
+    ("Detected old multi-rebase operation that probably failed.", "Alte Multi-Rebase-Operation gefunden, die vermutlich fehlgeschlagen ist."),
+    ("Press ENTER to clean it up and start over...", "ENTER drücken, um aufzuräumen und neu anzufangen..."),
+    ("[{}] (r)estore the old commit, or (a)dopt the rebased one [r]", "[{}] (r) alten Commit wiederherstellen, oder (a) den umgeschriebenen übernehmen [r]"),
+    ("Check out {} for {}? (same as HEAD) [Y/n]", "{} für {} auschecken? (gleich wie HEAD) [J/n]"),
+    ("Check out {} for {}? (not HEAD, will reset --hard) [Y/n]", "{} für {} auschecken? (nicht HEAD, setzt mit --hard zurück) [J/n]"),
+    ("Need to check out a branch for {}: [pick one]", "Für {} muss ein Branch ausgecheckt werden: [einen wählen]"),
+    ("[{}] {} (same as HEAD)", "[{}] {} (gleich wie HEAD)"),
+    ("[{}] {} (not HEAD, will reset --hard)", "[{}] {} (nicht HEAD, setzt mit --hard zurück)"),
+    ("Checking out branches for submodules: ", "Checke Branches für Submodule aus: "),
+    ("Running: ", "Führe aus: "),
+    ("[{}] Rebase conflict in {}!", "[{}] Rebase-Konflikt in {}!"),
+    ("[{}] Please resolve, then pick: {} [c]", "[{}] Bitte auflösen, dann wählen: {} [c]"),
+    ("Repo {}: Please resolve, then type a letter and press ENTER. Just ENTER continues.", "Repo {}: Bitte auflösen, dann einen Buchstaben eingeben und ENTER drücken. Nur ENTER macht weiter."),
+    ("Choice {}: {}", "Auswahl {}: {}"),
+    ("[{}] Unknown choice {}", "[{}] Unbekannte Auswahl {}"),
+    ("continue", "weiter"),
+    ("skip this commit", "diesen Commit überspringen"),
+    ("abort whole rebase", "ganzen Rebase abbrechen"),
+    ("mergetool", "Mergetool"),
+    ("open shell", "Shell öffnen"),
+    ("diff", "Diff"),
+    ("Submodules to rebase:", "Submodule für den Rebase:"),
+    ("Enter numbers to toggle, or ENTER when done", "Nummern zum Umschalten eingeben, oder ENTER wenn fertig"),
+    ("[{}] Submodule {} has no HEAD id, please check out a branch and press ENTER...", "[{}] Submodul {} hat keine HEAD-ID, bitte einen Branch auschecken und ENTER drücken..."),
+    ("[{}] Submodule {} has no HEAD id, detaching at {}", "[{}] Submodul {} hat keine HEAD-ID, löse HEAD bei {}"),
+    ("[{}] Submodule {} has no HEAD id, checking out new branch {} at {}", "[{}] Submodul {} hat keine HEAD-ID, checke neuen Branch {} bei {} aus"),
+    ("[{}] {} has been running for {}s and may be stuck. Press Ctrl-C to abort", "[{}] {} läuft seit {}s und hängt vielleicht. Strg-C bricht ab"),
+    ("[{}] {} has been running for {}s and may be stuck", "[{}] {} läuft seit {}s und hängt vielleicht"),
+    ("[{}] (r)etry, (s)kip, (a)bort, or ENTER to keep waiting", "[{}] (r) wiederholen, (s) überspringen, (a) abbrechen, oder ENTER zum Weiterwarten"),
+    ("Move them? [Y/n]", "Verschieben? [J/n]"),
+    ("Submodules that were initialized just for this run:", "Submodule, die nur für diesen Lauf initialisiert wurden:"),
+    ("{} is a submodule of {}", "{} ist ein Submodul von {}"),
+    ("Rebase from the superproject instead? [Y/n]", "Stattdessen vom Superprojekt aus rebasen? [J/n]"),
+    ("Deinitialize them again? [y/N]", "Wieder deinitialisieren? [j/N]"),
+    ("Also delete the {} clones made for this run, with their backups and everything fetched into them? [y/N]", "Auch die {} für diesen Lauf angelegten Klone löschen, mit ihren Backups und allem, was in sie geholt wurde? [j/N]"),
+    ("[{}] (c)ommit anyway, (o)pen shell to fix it, or (a)bort [a]", "[{}] (c) trotzdem committen, (o) Shell öffnen zum Beheben, oder (a) abbrechen [a]"),
+    ("No backups found", "Keine Backups gefunden"),
+    ("Restoring run {}:", "Stelle Lauf {} wieder her:"),
+    ("Press ENTER to restore...", "ENTER drücken zum Wiederherstellen..."),
+    ("Press ENTER to prune...", "ENTER drücken zum Aufräumen..."),
+    ("[{}] Cannot restore with a dirty working copy! Please stash first.", "[{}] Wiederherstellen mit geänderter Arbeitskopie nicht möglich! Bitte zuerst stashen."),
+];
+
+// The current locale's version of a message
+pub fn text(english: &'static str) -> &'static str {
+    let catalog = match locale() {
+        Locale::En => return english,
+        Locale::De => DE,
+    };
+    catalog.iter().find(|(key, _)| *key == english).map(|(_, translated)| *translated).unwrap_or(english)
+}
+
+// Fill each {} in a message with the next argument. Translations can't reorder arguments
+pub fn fill(template: &str, args: &[&dyn Display]) -> String {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut rest = template;
+    while let Some(index) = rest.find("{}") {
+        out.push_str(&rest[..index]);
+        if let Some(arg) = args.next() {
+            out.push_str(&arg.to_string());
+        }
+        rest = &rest[index + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// format! for catalog messages
+macro_rules! tr {
+    ($text:expr) => {
+        $crate::messages::fill($crate::messages::text($text), &[])
+    };
+    ($text:expr, $($arg:expr),+ $(,)?) => {
+        $crate::messages::fill($crate::messages::text($text), &[$(&$arg as &dyn std::fmt::Display),+])
+    };
+}
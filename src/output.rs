@@ -0,0 +1,489 @@
+use chrono::Local;
+use std::fs::{self, File, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+// With --porcelain, stdout only gets stable, space-separated event lines for wrappers to parse, and
+// everything meant for people (say!) and anything git or hooks print moves to stderr. Paths are
+// submodule paths as printed in [brackets], *root* for the superproject. Fields that are empty or have
+// spaces, quotes, backslashes or control characters in them are quoted like C strings.
+pub const EVENTS: &[(&str, &str)] = &[
+    ("phase <name>", "Entering a phase: check, prepare, rebase, finish or revert"),
+    ("plan <path> <base> <target> <count>", "Before starting: a repo will have count commits from base moved onto target"),
+    ("repo <path>", "Starting to rebase a repo"),
+    ("rewrite <path> <old> <new>", "A commit was rewritten, or kept if old and new are the same"),
+    ("skip <path> <old>", "A commit was dropped at a conflict"),
+    ("conflict <path> <old>", "A pick conflicted, old is empty if nothing was picked yet"),
+    ("progress <path> <picked> <count> <done> <total>", "After each pick: picked of the repo's count commits, done of total overall"),
+    ("prompt <kind> [<path>]", "Waiting for a line on stdin"),
+    ("done <exit code>", "Last line of every run"),
+];
+
+static PORCELAIN: AtomicBool = AtomicBool::new(false);
+// --plain, for screen readers: no column alignment or arrows, and labelled lines
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_porcelain(enabled: bool) {
+    PORCELAIN.store(enabled, Ordering::SeqCst);
+}
+
+pub fn porcelain() -> bool {
+    PORCELAIN.load(Ordering::SeqCst)
+}
+
+pub fn set_plain(enabled: bool) {
+    PLAIN.store(enabled, Ordering::SeqCst);
+}
+
+pub fn plain() -> bool {
+    PLAIN.load(Ordering::SeqCst)
+}
+
+// -v, -vv and -q. say! is the normal level, debug! shows up with -v and trace! with -vv. With -q, say!
+// lines are held back and only shown when the run stops to ask something, so a question still comes
+// with its context
+// TODO: Move to tracing once it can be added as a dependency, it isn't among the crates this builds with
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Quiet,
+    Normal,
+    Debug,
+    Trace,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+static HELD: Mutex<Vec<String>> = Mutex::new(vec![]);
+// Lines kept with -q, older ones are dropped
+const MAX_HELD: usize = 100;
+
+pub fn set_verbosity(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => Level::Quiet,
+        (false, 0) => Level::Normal,
+        (false, 1) => Level::Debug,
+        _ => Level::Trace,
+    };
+    LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::SeqCst)
+}
+
+// A timestamped record of everything a run says and asks, and what it was told, appended to
+// .git/sub-rebase/log so a run that went wrong can be pieced together afterwards. It gets debug! and
+// trace! lines whatever the verbosity
+static TRANSCRIPT: Mutex<Option<File>> = Mutex::new(None);
+
+pub fn open_transcript(path: &Path, header: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *TRANSCRIPT.lock().expect("Transcript lock poisoned") = Some(file);
+    transcribe("run", header);
+    Ok(())
+}
+
+pub fn transcribing() -> bool {
+    TRANSCRIPT.lock().expect("Transcript lock poisoned").is_some()
+}
+
+pub fn transcribe(kind: &str, line: &str) {
+    if let Some(file) = TRANSCRIPT.lock().expect("Transcript lock poisoned").as_mut() {
+        let now = Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+        for line in line.lines() {
+            let _ = writeln!(file, "{} {:<6} {}", now, kind, line);
+        }
+    }
+}
+
+// --color: repo paths, old and new commits, warnings and conflicts. Some(enabled) when decided by
+// --color or NO_COLOR, otherwise it depends on whether the line is going to a terminal
+static COLOR: Mutex<Option<bool>> = Mutex::new(None);
+
+const CYAN: &str = "\x1b[36m";
+const YELLOW: &str = "\x1b[33m";
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+pub fn set_color(enabled: Option<bool>) {
+    *COLOR.lock().expect("Color lock poisoned") = enabled;
+}
+
+fn color(to_stderr: bool) -> bool {
+    let decided = *COLOR.lock().expect("Color lock poisoned");
+    decided.unwrap_or_else(|| if to_stderr { std::io::stderr().is_terminal() } else { std::io::stdout().is_terminal() })
+}
+
+fn is_commit_id(word: &str) -> bool {
+    word.len() == 40 && word.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Color commit ids: old ones (before an arrow) yellow, new ones green
+fn paint_ids(text: &str) -> String {
+    let arrow = text.find(" --> ").map(|i| i + 5).unwrap_or(0);
+    let mut out = String::new();
+    let mut rest = text;
+    let mut offset = 0;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_hexdigit()) {
+        let len = rest[start..].find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(rest.len() - start);
+        let word = &rest[start..start + len];
+        out.push_str(&rest[..start]);
+        if is_commit_id(word) {
+            let paint = if arrow != 0 && offset + start >= arrow { GREEN } else { YELLOW };
+            out.push_str(&format!("{}{}{}", paint, word, RESET));
+        } else {
+            out.push_str(word);
+        }
+        offset += start + len;
+        rest = &rest[start + len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// A say! line, with its [repo path] in cyan
+fn paint_say(line: &str) -> String {
+    match line.find("] ") {
+        Some(end) if line.starts_with('[') => format!("{}{}{}{}", CYAN, &line[..end + 1], RESET, paint_ids(&line[end + 1..])),
+        _ => paint_ids(line),
+    }
+}
+
+// A warn! line, red if it's about a conflict
+fn paint_warn(line: &str) -> String {
+    let paint = if line.to_lowercase().contains("conflict") { RED } else { YELLOW };
+    format!("{}{}{}", paint, line, RESET)
+}
+
+// Where say! lines go
+pub fn say_line(line: String) {
+    transcribe("say", &line);
+    let line = if color(porcelain()) { paint_say(&line) } else { line };
+    if !enabled(Level::Normal) {
+        let mut held = HELD.lock().expect("Held lines lock poisoned");
+        if held.len() == MAX_HELD {
+            held.remove(0);
+        }
+        held.push(line);
+        return;
+    }
+    clear_status();
+    if porcelain() {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+    draw_status();
+}
+
+// Show what -q held back, before asking something
+pub fn release_held() {
+    for line in HELD.lock().expect("Held lines lock poisoned").drain(..) {
+        if porcelain() {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+// Where warn! lines go, stderr for warnings and errors that don't end the run
+pub fn warn_line(line: String) {
+    transcribe("warn", &line);
+    let line = if color(true) { paint_warn(&line) } else { line };
+    clear_status();
+    eprintln!("{}", line);
+    draw_status();
+}
+
+// debug! and trace! lines, always on stderr
+pub fn log_line(level: Level, line: String) {
+    transcribe(if level == Level::Trace { "trace" } else { "debug" }, &line);
+    if enabled(level) {
+        clear_status();
+        eprintln!("{}: {}", if level == Level::Trace { "trace" } else { "debug" }, line);
+        draw_status();
+    }
+}
+
+// Spaces to line up a column after text of this length, none in plain mode
+pub fn pad(len: usize, width: usize) -> String {
+    if plain() {
+        String::new()
+    } else {
+        " ".repeat(width.saturating_sub(len))
+    }
+}
+
+// Between an old and a new thing, which screen readers would read out as dashes
+pub fn arrow() -> &'static str {
+    if plain() { "to" } else { "-->" }
+}
+
+// In plain mode, "[libs/foo] Now rebasing" reads as "Repo libs/foo: Now rebasing"
+pub fn label(line: String) -> String {
+    if !plain() || !line.starts_with('[') {
+        return line;
+    }
+    match line.find("] ") {
+        // Numbered choices and checkboxes use brackets too
+        Some(end) if end > 2 && !line[1..end].chars().all(|c| c.is_ascii_digit()) => {
+            format!("Repo {}: {}", &line[1..end], line[end + 2..].trim_start())
+        }
+        _ => line,
+    }
+}
+
+// --bell and --notify-command, for getting someone's attention when a long run needs input
+static BELL: AtomicBool = AtomicBool::new(false);
+static NOTIFY_COMMAND: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_notify(bell: bool, command: Option<String>) {
+    BELL.store(bell, Ordering::SeqCst);
+    *NOTIFY_COMMAND.lock().expect("Notify lock poisoned") = command;
+}
+
+// The run has stopped to wait for an answer
+pub fn notify_waiting(prompt: &str) {
+    if BELL.load(Ordering::SeqCst) {
+        // stderr so it doesn't end up in porcelain output
+        eprint!("\x07");
+        let _ = std::io::stderr().flush();
+    }
+    if let Some(command) = NOTIFY_COMMAND.lock().expect("Notify lock poisoned").as_ref() {
+        // Don't wait for it, a notification shouldn't hold up the prompt
+        if let Err(e) = Command::new("sh").arg("-c").arg(command).env("SUB_REBASE_PROMPT", prompt).spawn() {
+            eprintln!("Notify command failed: {}", e);
+        }
+    }
+}
+
+// How far the rebase has got: "[libs/foo] commit 7/43, 120/500 overall". On a terminal this is a
+// status line kept under the output, redrawn after each say!
+struct Progress {
+    // (path, picked, count) for each repo being rebased, innermost last (a new submodule gets rebased
+    // in the middle of its parent)
+    repos: Vec<(String, usize, usize)>,
+    done: usize,
+    total: usize,
+}
+
+static PROGRESS: Mutex<Progress> = Mutex::new(Progress { repos: vec![], done: 0, total: 0 });
+static STATUS_LINE: AtomicBool = AtomicBool::new(false);
+
+// Start counting towards the total commits from the plan. Screen readers would read out every redraw,
+// so there's no status line in plain mode
+pub fn start_progress(total: usize) {
+    let mut progress = PROGRESS.lock().expect("Progress lock poisoned");
+    *progress = Progress { repos: vec![], done: 0, total };
+    STATUS_LINE.store(std::io::stderr().is_terminal() && !plain() && !porcelain(), Ordering::SeqCst);
+}
+
+pub fn end_progress() {
+    clear_status();
+    STATUS_LINE.store(false, Ordering::SeqCst);
+}
+
+// Counts picks for a repo until dropped
+pub struct RepoProgress;
+
+impl Drop for RepoProgress {
+    fn drop(&mut self) {
+        PROGRESS.lock().expect("Progress lock poisoned").repos.pop();
+        draw_status();
+    }
+}
+
+pub fn enter_repo(named_path: &str, count: usize) -> RepoProgress {
+    PROGRESS.lock().expect("Progress lock poisoned").repos.push((named_path.to_string(), 0, count));
+    draw_status();
+    RepoProgress
+}
+
+// A commit was picked in the innermost repo
+pub fn picked() {
+    let fields = {
+        let mut progress = PROGRESS.lock().expect("Progress lock poisoned");
+        progress.done += 1;
+        let (done, total) = (progress.done, progress.total.max(progress.done));
+        match progress.repos.last_mut() {
+            Some((path, picked, count)) => {
+                *picked += 1;
+                vec![path.clone(), picked.to_string(), count.to_string(), done.to_string(), total.to_string()]
+            }
+            None => return,
+        }
+    };
+    event(&["progress", &fields[0], &fields[1], &fields[2], &fields[3], &fields[4]]);
+    draw_status();
+}
+
+pub fn clear_status() {
+    if STATUS_LINE.load(Ordering::SeqCst) {
+        eprint!("\r\x1b[K");
+    }
+}
+
+pub fn draw_status() {
+    if !STATUS_LINE.load(Ordering::SeqCst) {
+        return;
+    }
+    let progress = PROGRESS.lock().expect("Progress lock poisoned");
+    if let Some((path, picked, count)) = progress.repos.last() {
+        // Leave the cursor at the start, so anything printed without clearing writes over it
+        eprint!("\r\x1b[K[{}] commit {}/{}, {}/{} overall\r", path, picked, count, progress.done, progress.total.max(progress.done));
+        let _ = std::io::stderr().flush();
+    }
+}
+
+// Print an event line, if anyone asked for them
+pub fn event(fields: &[&str]) {
+    if porcelain() {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = writeln!(stdout, "{}", fields.iter().map(|field| quote_field(field)).collect::<Vec<_>>().join(" "));
+        let _ = stdout.flush();
+    }
+}
+
+fn quote_field(field: &str) -> String {
+    if !field.is_empty() && !field.chars().any(|c| c == ' ' || c == '"' || c == '\\' || c.is_control()) {
+        return field.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in field.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\{:03o}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// The fields of an event line, undoing quote_field
+pub fn split_event(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ' ' {
+            continue;
+        }
+        let mut field = String::new();
+        if c != '"' {
+            field.push(c);
+            while let Some(c) = chars.next_if(|c| *c != ' ') {
+                field.push(c);
+            }
+            fields.push(field);
+            continue;
+        }
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next() {
+                    Some('n') => field.push('\n'),
+                    Some('t') => field.push('\t'),
+                    Some(d @ '0'..='7') => {
+                        let mut code = d.to_digit(8).unwrap_or(0);
+                        for _ in 0..2 {
+                            if let Some(d) = chars.next_if(|d| d.is_digit(8)) {
+                                code = code * 8 + d.to_digit(8).unwrap_or(0);
+                            }
+                        }
+                        field.extend(char::from_u32(code));
+                    }
+                    Some(c) => field.push(c),
+                    None => {}
+                },
+                c => field.push(c),
+            }
+        }
+        fields.push(field);
+    }
+    fields
+}
+
+// Where child processes (git, hooks, editors) print: with the rest of what's meant for people, so
+// stderr in porcelain mode
+pub fn child_stdout() -> Stdio {
+    if porcelain() {
+        Stdio::from(std::io::stderr())
+    } else {
+        Stdio::inherit()
+    }
+}
+
+// println! for people, kept off stdout in porcelain mode
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::output::say_line($crate::output::label(format!($($arg)*)))
+    };
+}
+
+// eprintln! for people, for warnings and errors the run carries on after
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::output::warn_line(format!($($arg)*))
+    };
+}
+
+// Details for diagnosing a run, with -v
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::output::enabled($crate::output::Level::Debug) || $crate::output::transcribing() {
+            $crate::output::log_line($crate::output::Level::Debug, format!($($arg)*))
+        }
+    };
+}
+
+// Everything, with -vv
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::output::enabled($crate::output::Level::Trace) || $crate::output::transcribing() {
+            $crate::output::log_line($crate::output::Level::Trace, format!($($arg)*))
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_fields_round_trip() {
+        let fields = ["conflict", "libs/my lib", "", "say \"hi\"", "back\\slash", "tab\there"];
+        let line = fields.iter().map(|field| quote_field(field)).collect::<Vec<_>>().join(" ");
+        assert_eq!(line, r#"conflict "libs/my lib" "" "say \"hi\"" "back\\slash" "tab\there""#);
+        assert_eq!(split_event(&line), fields);
+    }
+
+    #[test]
+    fn plain_event_fields() {
+        assert_eq!(quote_field("libs/foo"), "libs/foo");
+        assert_eq!(split_event("rewrite libs/foo abc def"), ["rewrite", "libs/foo", "abc", "def"]);
+        assert_eq!(split_event(r#"x "\001""#), ["x", "\u{1}"]);
+    }
+
+    #[test]
+    fn plain_labels() {
+        set_plain(true);
+        assert_eq!(label("[libs/foo] Rebased commit".to_string()), "Repo libs/foo: Rebased commit");
+        assert_eq!(label("[*root*] Set HEAD".to_string()), "Repo *root*: Set HEAD");
+        assert_eq!(label("[x] 2. libs/foo (3 commits)".to_string()), "[x] 2. libs/foo (3 commits)");
+        assert_eq!(label("[12] libs/bar".to_string()), "[12] libs/bar");
+        assert_eq!(label("No brackets".to_string()), "No brackets");
+        set_plain(false);
+        assert_eq!(label("[libs/foo] Rebased commit".to_string()), "[libs/foo] Rebased commit");
+    }
+}
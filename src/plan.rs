@@ -0,0 +1,165 @@
+use git2::{Repository, Commit, BranchType};
+use anyhow::{Result, anyhow};
+use std::cell::RefCell;
+use std::path::PathBuf;
+use crate::{Config, STATE_BRANCHES, autostash, backups, cache, commit_graph, branch_name_to_canonical, checkout_spec_branch, rebase_config_flag, recover_crashed_head, recurse_subs, sub_path_to_string};
+use crate::resolver::TargetResolver;
+
+// What the run needs to know about every repo before it changes anything, found in one pass over the
+// tree: whether it's locked, whether it needs rebasing, whether an old run left its branches behind,
+// and, if it has no branch checked out, which ones could be. Walking a tree of 40 submodules once for
+// each of these took minutes before the rebase even started
+
+// A branch to check out, as (local name, the branch it comes from)
+pub type BranchChoice = (String, String);
+
+// Branches a repo with no branch checked out could get, best first
+#[derive(Default)]
+pub struct Checkout {
+    // From --checkout, which settles it
+    pub spec: Option<BranchChoice>,
+    // The submodule's configured branch, and whether it's at HEAD already
+    pub configured: Option<(BranchChoice, bool)>,
+    // Local branches tracking a remote branch at HEAD
+    pub tracked: Vec<BranchChoice>,
+    // Local branches at HEAD
+    pub local: Vec<BranchChoice>,
+    // Remote branches at HEAD
+    pub remote: Vec<BranchChoice>,
+    // Any local branch, which means a reset --hard
+    pub all_local: Vec<BranchChoice>,
+}
+
+pub struct RepoPlan {
+    pub path: Vec<String>,
+    pub locked: bool,
+    // Whether this repo's own HEAD isn't on top of its target yet
+    pub needs_rebase: bool,
+    // Whether an old run's state branches are still around
+    pub stale_state: bool,
+    // Set if there's no branch checked out
+    pub checkout: Option<Checkout>,
+}
+
+pub struct Plan {
+    // Innermost first, in the order recurse_subs visits them
+    pub repos: Vec<RepoPlan>,
+}
+
+impl Plan {
+    pub fn locked(&self) -> Option<&RepoPlan> {
+        self.repos.iter().find(|repo| repo.locked)
+    }
+
+    pub fn needs_rebase(&self) -> bool {
+        self.repos.iter().any(|repo| repo.needs_rebase)
+    }
+
+    pub fn needs_checkouts(&self) -> bool {
+        self.repos.iter().any(|repo| repo.checkout.is_some())
+    }
+
+    pub fn has_stale_state(&self) -> bool {
+        self.repos.iter().any(|repo| repo.stale_state)
+    }
+}
+
+// Branches other than our own and backups, as (name, branch)
+fn candidate_branches<'r>(repo: &'r Repository, kind: BranchType) -> Result<Vec<(String, git2::Branch<'r>)>> {
+    Ok(repo.branches(Some(kind))?.map(|b| -> Result<Option<(String, git2::Branch)>> {
+        let (branch, _branch_type) = b?;
+        let name: String = branch.name()?.expect("Branch has name").into();
+        let backup = match kind {
+            BranchType::Local => backups::is_backup(repo, &name),
+            BranchType::Remote => name.starts_with("backup/"),
+        };
+        if backup || name.ends_with("HEAD") || name.contains("multi_rebase_") {
+            return Ok(None);
+        }
+        Ok(Some((name, branch)))
+    }).filter_map(|b| b.ok().flatten()).collect())
+}
+
+fn checkout(config: &Config, repo: &Repository, submodule_branch: Option<&str>, path: &Vec<String>) -> Result<Checkout> {
+    let format_path = sub_path_to_string(path);
+    let head = repo.head()?.peel_to_commit()?.id();
+    let mut checkout = Checkout::default();
+
+    if let Some(spec) = config.checkouts.iter().find(|spec| spec.path == format_path) {
+        checkout.spec = Some(checkout_spec_branch(repo, &spec.branch).map_err(|e| anyhow!("[{}] --checkout: {}", format_path, e))?);
+        return Ok(checkout);
+    }
+
+    // The branch from .gitmodules (or submodule.<name>.branch), if it's there to check out.
+    // "." means the superproject's branch, which doesn't say which one to use here
+    if let Some(configured) = submodule_branch.filter(|branch| *branch != ".") {
+        if let Ok((local, remote)) = checkout_spec_branch(repo, &configured.to_string()) {
+            let branch = repo.find_branch(&local, BranchType::Local).or_else(|_| repo.find_branch(&remote, BranchType::Remote))?;
+            let at_head = branch.into_reference().peel_to_commit()?.id() == head;
+            checkout.configured = Some(((local, remote), at_head));
+        }
+    }
+
+    let remote_branches = candidate_branches(repo, BranchType::Remote)?;
+    for (name, branch) in &remote_branches {
+        if branch.get().peel_to_commit()?.id() != head {
+            continue;
+        }
+        // Local branches following a remote branch at HEAD
+        for (local_name, local) in candidate_branches(repo, BranchType::Local)? {
+            if let Ok(upstream) = local.upstream() {
+                if upstream.name()? == Some(name.as_str()) {
+                    checkout.tracked.push((local_name, name.clone()));
+                }
+            }
+        }
+        checkout.remote.push((branch_name_to_canonical(repo, name)?, name.clone()));
+    }
+    for (name, branch) in candidate_branches(repo, BranchType::Local)? {
+        if branch.get().peel_to_commit()?.id() == head {
+            checkout.local.push((name.clone(), name.clone()));
+        }
+        checkout.all_local.push((name.clone(), name));
+    }
+    Ok(checkout)
+}
+
+// Stash changes (with rebase.autoStash) and put back HEADs a crashed run left behind first, in that
+// order so putting one back can't throw changes away, then look at every repo as it is
+pub fn plan(config: &Config, repo: &Repository, target: &Commit, resolver: &dyn TargetResolver, auto_stash: &RefCell<autostash::AutoStash>) -> Result<Plan> {
+    let repos = RefCell::new(vec![]);
+    recurse_subs(repo, target, resolver, &|repo: &Repository, submodule, target: &Commit, path, _child_results| -> Result<()> {
+        let named_path = sub_path_to_string(path);
+        // Before anything here walks the history
+        commit_graph::ensure(&named_path, repo);
+        let mut lockfile = PathBuf::from(repo.path());
+        lockfile.push("index.lock");
+        let locked = lockfile.exists();
+        if !locked {
+            if rebase_config_flag(repo, "rebase.autoStash", config.autostash, config.no_autostash) && autostash::has_changes(repo)? {
+                auto_stash.borrow_mut().stash(repo, &named_path, &config.run_id)?;
+            }
+            recover_crashed_head(repo, &named_path)?;
+        }
+
+        let head = repo.head()?;
+        let head_name = head.name().expect("Ref expected name");
+        let detached = head_name == "HEAD" || head_name.contains("/multi_rebase_");
+        let checkout = if detached {
+            Some(checkout(config, repo, submodule.and_then(|s| s.branch()), path)?)
+        } else {
+            None
+        };
+        let head = head.peel_to_commit()?.id();
+
+        repos.borrow_mut().push(RepoPlan {
+            path: path.clone(),
+            locked,
+            needs_rebase: cache::merge_base(repo, head, target.id())? != target.id(),
+            stale_state: STATE_BRANCHES.iter().any(|(name, _)| repo.find_branch(name, BranchType::Local).is_ok()),
+            checkout,
+        });
+        Ok(())
+    })?;
+    Ok(Plan { repos: repos.into_inner() })
+}
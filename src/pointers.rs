@@ -0,0 +1,91 @@
+use git2::Oid;
+use std::collections::HashMap;
+
+// Where each submodule's gitlink has been over the commits picked so far. Most of the time a commit
+// either leaves a gitlink alone (a plateau) or moves it forward, but some histories move one back and
+// then forward again, like a revert and its reapply. Neither of those needs the submodule's working
+// copy dragged back and forth with hard resets
+
+#[derive(Default)]
+pub struct Pointers {
+    // Where each submodule was pointed on the previous commit
+    previous: HashMap<String, Oid>,
+    // Submodules whose gitlink went backwards without their working copy following, and what the
+    // gitlink was rewritten to
+    regressed: HashMap<String, Oid>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PointerMove {
+    // Where the last commit left it, and the submodule is there already: nothing to do
+    Plateau,
+    // Back to an earlier commit from `from`: write `rewritten` to the gitlink and leave the working copy
+    // where it is
+    Regressed { from: Oid, rewritten: Oid },
+    // Anything else: the submodule gets checked out where the gitlink says
+    Moved,
+}
+
+impl Pointers {
+    // `expected` is the gitlink in the commit being picked and `rewritten` what that became (itself, if
+    // it wasn't rewritten). `head` is where the submodule's working copy is, and `goes_back(from, to)`
+    // says whether `from` descends from `to`
+    pub fn step(&mut self, sub_name: &str, expected: Oid, rewritten: Oid, head: Oid, goes_back: impl Fn(Oid, Oid) -> bool) -> PointerMove {
+        let previous = self.previous.insert(sub_name.to_string(), expected);
+        // After going backwards, later commits go by the gitlink, not by the working copy left behind
+        let effective_head = self.regressed.get(sub_name).copied().unwrap_or(head);
+        if previous == Some(expected) && (effective_head == expected || effective_head == rewritten) {
+            return PointerMove::Plateau;
+        }
+        self.regressed.remove(sub_name);
+        match previous {
+            Some(previous) if previous != expected && goes_back(previous, expected) => {
+                self.regressed.insert(sub_name.to_string(), rewritten);
+                PointerMove::Regressed { from: previous, rewritten }
+            }
+            _ => PointerMove::Moved,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oid(n: u8) -> Oid {
+        Oid::from_bytes(&[n; 20]).unwrap()
+    }
+
+    // A straight line of commits 1, 2, 3, ..., each descending from the ones before
+    fn goes_back(from: Oid, to: Oid) -> bool {
+        from.as_bytes()[0] > to.as_bytes()[0]
+    }
+
+    #[test]
+    fn regress_then_plateau() {
+        let mut pointers = Pointers::default();
+        // Commits 1..=3 were rewritten to 11..=13, and the working copy is at 3's rewrite
+        let head = oid(13);
+        assert_eq!(pointers.step("libs/foo", oid(3), oid(13), head, goes_back), PointerMove::Moved);
+        assert_eq!(pointers.step("libs/foo", oid(3), oid(13), head, goes_back), PointerMove::Plateau);
+        // A revert takes the pointer back to 2, the working copy stays at 13
+        assert_eq!(pointers.step("libs/foo", oid(2), oid(12), head, goes_back), PointerMove::Regressed { from: oid(3), rewritten: oid(12) });
+        // Commits that leave it at 2 don't reset the working copy back to it
+        assert_eq!(pointers.step("libs/foo", oid(2), oid(12), head, goes_back), PointerMove::Plateau);
+        assert_eq!(pointers.step("libs/foo", oid(2), oid(12), head, goes_back), PointerMove::Plateau);
+        // Then the reapply moves it forward again
+        assert_eq!(pointers.step("libs/foo", oid(3), oid(13), head, goes_back), PointerMove::Moved);
+        assert_eq!(pointers.step("libs/foo", oid(3), oid(13), head, goes_back), PointerMove::Plateau);
+    }
+
+    #[test]
+    fn submodules_tracked_apart() {
+        let mut pointers = Pointers::default();
+        assert_eq!(pointers.step("libs/foo", oid(3), oid(3), oid(3), goes_back), PointerMove::Moved);
+        assert_eq!(pointers.step("libs/bar", oid(2), oid(2), oid(2), goes_back), PointerMove::Moved);
+        assert_eq!(pointers.step("libs/foo", oid(2), oid(2), oid(3), goes_back), PointerMove::Regressed { from: oid(3), rewritten: oid(2) });
+        assert_eq!(pointers.step("libs/bar", oid(2), oid(2), oid(2), goes_back), PointerMove::Plateau);
+        // Moving elsewhere without going back isn't a regression
+        assert_eq!(pointers.step("libs/bar", oid(5), oid(5), oid(2), goes_back), PointerMove::Moved);
+    }
+}
@@ -0,0 +1,91 @@
+use git2::{Oid, Repository};
+use anyhow::{Result, anyhow};
+use std::collections::HashSet;
+use std::process::Command;
+use crate::watchdog;
+
+// Partial clones (a promisor remote, e.g. cloned with --filter=blob:none). git fetches missing
+// objects when it needs them, libgit2 doesn't and fails halfway through a rebase instead. So
+// everything a repo's rebase reads gets fetched before it starts: the trees and blobs of the commits
+// being rebased, and the whole trees of the merge base and the target
+
+// Objects at a time for `git fetch`, to stay under command line limits
+const FETCH_CHUNK: usize = 1000;
+
+// The remote missing objects come from, if this is a partial clone
+pub fn promisor_remote(repo: &Repository) -> Result<Option<String>> {
+    let config = repo.config()?;
+    if let Ok(remote) = config.get_string("extensions.partialclone") {
+        return Ok(Some(remote));
+    }
+    let entries = config.entries(Some("^remote\\..*\\.promisor$"))?;
+    for entry in &entries {
+        let entry = entry?;
+        if let (Some(name), Some(value)) = (entry.name(), entry.value()) {
+            if value.eq_ignore_ascii_case("true") {
+                return Ok(Some(name["remote.".len()..name.len() - ".promisor".len()].to_string()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn git(named_path: &str, repo: &Repository, operation: &str, args: &[String]) -> Result<String> {
+    let mut cmd = Command::new("git");
+    cmd.args(args).current_dir(repo.workdir().unwrap_or(repo.path()));
+    match watchdog::run_command(named_path, operation, &mut cmd)? {
+        Some(output) if output.status.success() => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+        Some(output) => Err(anyhow!("[{}] {} failed: {}", named_path, operation, String::from_utf8_lossy(&output.stderr).trim())),
+        None => Err(anyhow!("[{}] {} was skipped", named_path, operation)),
+    }
+}
+
+// Objects a rebase of base..head onto target needs that aren't in the repo
+fn missing_objects(named_path: &str, repo: &Repository, base: Oid, head: Oid, target: Oid) -> Result<Vec<String>> {
+    let mut missing = vec![];
+    let walks = [
+        vec![head.to_string(), format!("^{}", base)],
+        vec!["--no-walk".to_string(), base.to_string(), target.to_string()],
+    ];
+    for walk in &walks {
+        let mut args = vec!["rev-list".to_string(), "--objects".to_string(), "--no-object-names".to_string(), "--missing=print".to_string()];
+        args.extend(walk.iter().cloned());
+        let listed = git(named_path, repo, "git rev-list --missing=print", &args)?;
+        for line in listed.lines() {
+            if let Some(id) = line.strip_prefix('?') {
+                if !missing.iter().any(|m| m == id) {
+                    missing.push(id.to_string());
+                }
+            }
+        }
+    }
+    Ok(missing)
+}
+
+// Fetch what the rebase will need, if the repo is a partial clone and anything is missing
+pub fn prefetch(named_path: &str, repo: &Repository, base: Oid, head: Oid, target: Oid) -> Result<()> {
+    let remote = match promisor_remote(repo)? {
+        Some(remote) => remote,
+        None => return Ok(()),
+    };
+    // Trees fetched on one pass can point at blobs that are still missing, so go until nothing is
+    let mut requested = HashSet::new();
+    loop {
+        let missing = missing_objects(named_path, repo, base, head, target)?;
+        if missing.is_empty() {
+            return Ok(());
+        }
+        if let Some(id) = missing.iter().find(|id| requested.contains(*id)) {
+            return Err(anyhow!("[{}] Object {} is still missing after fetching it from {}", named_path, id, remote));
+        }
+        requested.extend(missing.iter().cloned());
+        say!("[{}] Partial clone, fetching {} missing objects from {}", named_path, missing.len(), remote);
+        for chunk in missing.chunks(FETCH_CHUNK) {
+            // The same fetch git makes for missing objects itself
+            let mut args = ["-c", "fetch.negotiationAlgorithm=noop", "fetch", remote.as_str(), "--no-tags", "--no-write-fetch-head", "--recurse-submodules=no", "--filter=blob:none"]
+                .iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+            args.extend(chunk.iter().cloned());
+            git(named_path, repo, "git fetch", &args)?;
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::{Result, anyhow};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::fs;
+use std::io::{IsTerminal, stdin};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use crate::{Failure, INTERRUPTED, output};
+
+// Everything a run asks goes through a Prompter, so something other than a person at a terminal can
+// answer. Each prompt has a kind (see `help porcelain`), e.g. "conflict libs/foo". Only answer is
+// needed, the rest are worked out from lines of input the way the CLI reads them
+pub trait Prompter {
+    // A line of input, e.g. a letter from a menu
+    fn answer(&self, kind: &str) -> Result<String>;
+
+    // Yes or no, default for no answer at all
+    fn confirm(&self, kind: &str, default: bool) -> Result<bool> {
+        Ok(confirmed(&self.answer(kind)?, default))
+    }
+
+    // One of a list of count things, numbered from 1. Gives the index into the list
+    fn choose(&self, kind: &str, count: usize) -> Result<usize> {
+        chosen(&self.answer(kind)?, count)
+    }
+
+    // Wait while something gets sorted out outside the run (a branch checked out, the plan read over)
+    fn wait_for_resolution(&self, kind: &str) -> Result<()> {
+        self.answer(kind)?;
+        Ok(())
+    }
+}
+
+fn confirmed(choice: &str, default: bool) -> bool {
+    match choice.trim().chars().next() {
+        None => default,
+        Some(c) if default => !"nN".contains(c),
+        Some(c) => "yYjJ".contains(c),
+    }
+}
+
+fn chosen(choice: &str, count: usize) -> Result<usize> {
+    let index = usize::from_str(choice.trim())?;
+    if index == 0 || index > count {
+        return Err(anyhow!("Bad index, cancelling..."));
+    }
+    Ok(index - 1)
+}
+
+// The CLI's, a line from stdin for each answer. Ctrl-C while waiting interrupts the run. Without a
+// terminal only --porcelain readers are expected to answer, anything else piped in would get taken
+// as answers to questions nobody saw
+pub struct StdinPrompter;
+
+impl Prompter for StdinPrompter {
+    fn answer(&self, kind: &str) -> Result<String> {
+        if !output::porcelain() && !stdin().is_terminal() {
+            return Err(Failure::NoInput(kind.to_string()).into());
+        }
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let mut choice = String::new();
+        let read = stdin().read_line(&mut choice)?;
+        match INTERRUPTED.load(Ordering::SeqCst) {
+            false if read == 0 => Err(Failure::NoInput(kind.to_string()).into()),
+            false => Ok(choice),
+            _ => Err(Failure::Interrupted.into()),
+        }
+    }
+}
+
+// --yes, the default for everything: ENTER at every prompt. Lists have no default to take
+pub struct YesPrompter;
+
+impl Prompter for YesPrompter {
+    fn answer(&self, _kind: &str) -> Result<String> {
+        Ok(String::new())
+    }
+
+    fn choose(&self, kind: &str, _count: usize) -> Result<usize> {
+        Err(anyhow!("Prompt {} has no default for --yes to take", kind))
+    }
+}
+
+// --answers <file>: answers recorded ahead of time, one per line as `<kind> [<path>] = <answer>`, e.g.
+// `conflict libs/foo = s`. A path of * matches any repo, and leaving it out does too. The same key
+// can be listed more than once to answer each time it comes up, the last answer sticks. Anything
+// not in the file goes to the prompter it was layered over
+pub struct AnswersPrompter {
+    answers: RefCell<HashMap<String, VecDeque<String>>>,
+    fallback: Box<dyn Prompter + Send>,
+}
+
+impl AnswersPrompter {
+    pub fn load(path: &Path, fallback: Box<dyn Prompter + Send>) -> Result<AnswersPrompter> {
+        let mut answers: HashMap<String, VecDeque<String>> = HashMap::new();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, answer) = line.split_at(line.find('=').ok_or_else(|| anyhow!("{}:{}: expected <kind> [<path>] = <answer>", path.display(), number + 1))?);
+            let key = key.split_whitespace().collect::<Vec<_>>().join(" ");
+            answers.entry(key).or_default().push_back(answer[1..].trim().to_string());
+        }
+        Ok(AnswersPrompter { answers: RefCell::new(answers), fallback })
+    }
+
+    // The next recorded answer for a prompt, most specific key first
+    fn recorded(&self, kind: &str) -> Option<String> {
+        let name = kind.split(' ').next().unwrap_or("");
+        let keys = [kind.to_string(), format!("{} *", name), name.to_string()];
+        let mut answers = self.answers.borrow_mut();
+        let key = keys.iter().find(|key| answers.contains_key(*key))?;
+        let queue = answers.get_mut(key)?;
+        if queue.len() > 1 { queue.pop_front() } else { queue.front().cloned() }
+    }
+}
+
+impl Prompter for AnswersPrompter {
+    fn answer(&self, kind: &str) -> Result<String> {
+        match self.recorded(kind) {
+            Some(answer) => Ok(answer),
+            None => self.fallback.answer(kind),
+        }
+    }
+
+    fn confirm(&self, kind: &str, default: bool) -> Result<bool> {
+        match self.recorded(kind) {
+            Some(answer) => Ok(confirmed(&answer, default)),
+            None => self.fallback.confirm(kind, default),
+        }
+    }
+
+    fn choose(&self, kind: &str, count: usize) -> Result<usize> {
+        match self.recorded(kind) {
+            Some(answer) => chosen(&answer, count),
+            None => self.fallback.choose(kind, count),
+        }
+    }
+
+    fn wait_for_resolution(&self, kind: &str) -> Result<()> {
+        match self.recorded(kind) {
+            Some(_) => Ok(()),
+            None => self.fallback.wait_for_resolution(kind),
+        }
+    }
+}
+
+static PROMPTER: Mutex<Option<Box<dyn Prompter + Send>>> = Mutex::new(None);
+
+pub fn set_prompter(prompter: Box<dyn Prompter + Send>) {
+    *PROMPTER.lock().expect("Prompter lock poisoned") = Some(prompter);
+}
+
+// Ask the prompter, telling porcelain readers, notifiers and the transcript
+fn ask<T: Debug>(kind: &str, f: impl FnOnce(&dyn Prompter) -> Result<T>) -> Result<T> {
+    output::release_held();
+    output::clear_status();
+    // Kinds come with the repo they're about, "conflict libs/foo"
+    match kind.split_once(' ') {
+        Some((name, path)) => output::event(&["prompt", name, path]),
+        None => output::event(&["prompt", kind]),
+    }
+    output::notify_waiting(kind);
+    output::transcribe("prompt", kind);
+    let prompter = PROMPTER.lock().expect("Prompter lock poisoned");
+    let result = match prompter.as_ref() {
+        Some(prompter) => f(prompter.as_ref()),
+        None => f(&StdinPrompter),
+    };
+    match &result {
+        Ok(value) => output::transcribe("answer", &format!("{:?}", value)),
+        Err(e) => output::transcribe("answer", &format!("({})", e)),
+    }
+    result
+}
+
+pub fn answer(kind: &str) -> Result<String> {
+    ask(kind, |prompter| prompter.answer(kind))
+}
+
+pub fn confirm(kind: &str, default: bool) -> Result<bool> {
+    ask(kind, |prompter| prompter.confirm(kind, default))
+}
+
+pub fn choose(kind: &str, count: usize) -> Result<usize> {
+    ask(kind, |prompter| prompter.choose(kind, count))
+}
+
+pub fn wait_for_resolution(kind: &str) -> Result<()> {
+    ask(kind, |prompter| prompter.wait_for_resolution(kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, contents: &str) -> Result<AnswersPrompter> {
+        let path = std::env::temp_dir().join(format!("sub-rebase-answers-{}-{}", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let prompter = AnswersPrompter::load(&path, Box::new(YesPrompter));
+        fs::remove_file(&path).unwrap();
+        prompter
+    }
+
+    #[test]
+    fn answers_most_specific_first() {
+        let prompter = load("specific", "# recorded\n\nconflict   libs/foo = s\nconflict * = a\nbranch = 2\n").unwrap();
+        assert_eq!(prompter.answer("conflict libs/foo").unwrap(), "s");
+        assert_eq!(prompter.answer("conflict libs/bar").unwrap(), "a");
+        assert_eq!(prompter.answer("branch libs/bar").unwrap(), "2");
+        assert_eq!(prompter.choose("branch libs/bar", 3).unwrap(), 1);
+        // Not in the file, so it's the fallback's
+        assert_eq!(prompter.answer("large-blob libs/foo").unwrap(), "");
+    }
+
+    #[test]
+    fn answers_in_turn_last_sticks() {
+        let prompter = load("turns", "conflict = s\nconflict = c\n").unwrap();
+        assert_eq!(prompter.answer("conflict libs/foo").unwrap(), "s");
+        assert_eq!(prompter.answer("conflict libs/foo").unwrap(), "c");
+        assert_eq!(prompter.answer("conflict libs/foo").unwrap(), "c");
+    }
+
+    #[test]
+    fn answers_need_equals() {
+        let error = load("malformed", "conflict = s\nconflict libs/foo s\n").err().unwrap();
+        assert!(error.to_string().ends_with(":2: expected <kind> [<path>] = <answer>"), "{}", error);
+    }
+}
@@ -0,0 +1,131 @@
+use git2::{ProxyOptions, Repository};
+
+// Proxies for libgit2's fetches and clones, picked the way git picks them: remote.<name>.proxy, then
+// the longest matching http.<url>.proxy, then http.proxy, then the environment variables curl reads,
+// minus anything no_proxy covers. libgit2 only speaks HTTP proxies, so anything going through a SOCKS
+// one is left to git
+
+// The proxy for fetching `url`, if any. `remote` is the remote's name when it's a configured one
+pub fn proxy_for(repo: &Repository, remote: Option<&str>, url: &str) -> Option<String> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return None;
+    }
+    let configured = repo.config().ok().and_then(|config| {
+        if let Some(proxy) = remote.and_then(|remote| config.get_string(&format!("remote.{}.proxy", remote)).ok()) {
+            return Some(proxy);
+        }
+        let mut longest: Option<(usize, String)> = None;
+        if let Ok(entries) = config.entries(Some("^http\\..+\\.proxy$")) {
+            for entry in &entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                if let (Some(name), Some(value)) = (entry.name(), entry.value()) {
+                    let prefix = &name["http.".len()..name.len() - ".proxy".len()];
+                    if url.starts_with(prefix) && longest.as_ref().is_none_or(|(len, _)| prefix.len() > *len) {
+                        longest = Some((prefix.len(), value.to_string()));
+                    }
+                }
+            }
+        }
+        longest.map(|(_, proxy)| proxy).or_else(|| config.get_string("http.proxy").ok())
+    });
+    // Set but empty turns it off, like in git
+    if let Some(proxy) = configured {
+        return if proxy.is_empty() { None } else { Some(proxy) };
+    }
+
+    if no_proxy(url) {
+        return None;
+    }
+    let names: &[&str] = if url.starts_with("https://") {
+        &["https_proxy", "HTTPS_PROXY", "all_proxy", "ALL_PROXY"]
+    } else {
+        // curl ignores HTTP_PROXY, since CGI scripts get it from a request header
+        &["http_proxy", "all_proxy", "ALL_PROXY"]
+    };
+    names.iter().filter_map(|name| std::env::var(name).ok()).find(|proxy| !proxy.is_empty())
+}
+
+// Whether no_proxy names the url's host, or a domain it's in
+fn no_proxy(url: &str) -> bool {
+    match std::env::var("no_proxy").or_else(|_| std::env::var("NO_PROXY")) {
+        Ok(list) => no_proxy_covers(&list, url),
+        Err(_) => false,
+    }
+}
+
+fn no_proxy_covers(list: &str, url: &str) -> bool {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split('/').next().unwrap_or(rest);
+    let host_port = authority.rsplit('@').next().unwrap_or(authority);
+    let host = host_port.rsplitn(2, ':').last().unwrap_or(host_port).to_ascii_lowercase();
+    list.split(',').map(|entry| entry.trim().trim_start_matches('.').to_ascii_lowercase()).any(|entry| {
+        entry == "*" || (!entry.is_empty() && (host == entry || host.ends_with(&format!(".{}", entry))))
+    })
+}
+
+pub fn is_socks(proxy: &str) -> bool {
+    proxy.starts_with("socks")
+}
+
+// Whether fetching `url` has to go through git, because its proxy is one libgit2 can't use
+pub fn needs_git(repo: &Repository, remote: Option<&str>, url: &str) -> bool {
+    match proxy_for(repo, remote, url) {
+        Some(proxy) if is_socks(&proxy) => {
+            debug!("{} goes through SOCKS proxy {}, fetching it with git", url, proxy);
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn options(repo: &Repository, remote: Option<&str>, url: &str) -> ProxyOptions<'static> {
+    let mut options = ProxyOptions::new();
+    if let Some(proxy) = proxy_for(repo, remote, url) {
+        debug!("Fetching {} through {}", url, proxy);
+        options.url(&proxy);
+    }
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_proxy_hosts() {
+        assert!(no_proxy_covers("example.com", "https://example.com/repo.git"));
+        assert!(no_proxy_covers("example.com", "https://git.example.com/repo.git"));
+        assert!(no_proxy_covers(" .example.com, other.org", "http://user@GIT.Example.com:8080/repo.git"));
+        assert!(no_proxy_covers("*", "https://anything.net/repo.git"));
+        assert!(!no_proxy_covers("example.com", "https://notexample.com/repo.git"));
+        assert!(!no_proxy_covers("", "https://example.com/repo.git"));
+    }
+
+    #[test]
+    fn configured_proxies() {
+        let dir = std::env::temp_dir().join(format!("sub-rebase-proxy-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("http.proxy", "http://fallback:3128").unwrap();
+        config.set_str("http.https://example.com.proxy", "http://short:3128").unwrap();
+        config.set_str("http.https://example.com/team.proxy", "socks5://long:1080").unwrap();
+        config.set_str("http.https://quiet.org.proxy", "").unwrap();
+        config.set_str("remote.origin.proxy", "http://remote:3128").unwrap();
+
+        assert_eq!(proxy_for(&repo, None, "https://example.com/team/repo.git").as_deref(), Some("socks5://long:1080"));
+        assert_eq!(proxy_for(&repo, None, "https://example.com/other/repo.git").as_deref(), Some("http://short:3128"));
+        assert_eq!(proxy_for(&repo, None, "https://elsewhere.net/repo.git").as_deref(), Some("http://fallback:3128"));
+        assert_eq!(proxy_for(&repo, Some("origin"), "https://example.com/team/repo.git").as_deref(), Some("http://remote:3128"));
+        assert_eq!(proxy_for(&repo, None, "https://quiet.org/repo.git"), None);
+        assert_eq!(proxy_for(&repo, None, "git@example.com:team/repo.git"), None);
+        assert!(needs_git(&repo, None, "https://example.com/team/repo.git"));
+        assert!(!needs_git(&repo, None, "https://example.com/other/repo.git"));
+
+        drop(repo);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
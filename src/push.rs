@@ -0,0 +1,88 @@
+use git2::{Oid, Repository};
+use anyhow::{Result, anyhow};
+use std::path::PathBuf;
+use std::process::Command;
+use crate::{output, prompter, watchdog};
+
+// --push: once a run succeeds, push every branch that had commits rewritten to its upstream, with
+// --force-with-lease so nothing pushed by someone else since the last fetch gets overwritten
+
+pub struct Push {
+    named_path: String,
+    workdir: PathBuf,
+    branch: String,
+    remote: String,
+    // Branch on the remote, e.g. refs/heads/main
+    merge: String,
+    // Where the remote-tracking branch says the remote is, which the push expects to replace
+    lease: Option<Oid>,
+    new: Oid,
+}
+
+// The push for a repo's checked out branch, or None (saying why) if it has nowhere to go
+pub fn plan(repo: &Repository, named_path: &str) -> Result<Option<Push>> {
+    let head = repo.head()?;
+    if !head.is_branch() {
+        say!("[{}] Not pushing, no branch is checked out", named_path);
+        return Ok(None);
+    }
+    let branch = head.shorthand().ok_or_else(|| anyhow!("[{}] Branch name isn't UTF-8", named_path))?.to_string();
+    let config = repo.config()?;
+    let (remote, merge) = match (config.get_string(&format!("branch.{}.remote", branch)), config.get_string(&format!("branch.{}.merge", branch))) {
+        (Ok(remote), Ok(merge)) => (remote, merge),
+        _ => {
+            say!("[{}] Not pushing {}, it has no upstream", named_path, branch);
+            return Ok(None);
+        }
+    };
+    let lease = match repo.branch_upstream_name(head.name().expect("Branch has name")) {
+        Ok(upstream) => match upstream.as_str() {
+            Some(upstream) => repo.refname_to_id(upstream).ok(),
+            None => None,
+        },
+        Err(_) => None,
+    };
+    Ok(Some(Push {
+        named_path: named_path.to_string(),
+        workdir: repo.workdir().expect("Has workdir").to_path_buf(),
+        branch,
+        remote,
+        merge,
+        lease,
+        new: head.peel_to_commit()?.id(),
+    }))
+}
+
+// Show what would be pushed, ask, and push it. Submodules should come before the repos they're in,
+// so no gitlink gets pushed before the commit it points at
+pub fn push(pushes: &[Push]) -> Result<()> {
+    if pushes.is_empty() {
+        say!("Nothing to push");
+        return Ok(());
+    }
+    say!("Would push:");
+    for push in pushes {
+        let lease = push.lease.map_or("(new)".to_string(), |lease| lease.to_string());
+        say!("[{}] {} to {} {}: {} {} {}", push.named_path, push.branch, push.remote, push.merge, lease, output::arrow(), push.new);
+    }
+    say!("Push them? [Y/n]");
+    if !prompter::confirm("push", true)? {
+        return Ok(());
+    }
+
+    for push in pushes {
+        let lease = push.lease.map_or(String::new(), |lease| lease.to_string());
+        let mut cmd = Command::new("git");
+        cmd.arg("push")
+            .arg(format!("--force-with-lease={}:{}", push.merge, lease))
+            .arg(&push.remote)
+            .arg(format!("{}:{}", push.new, push.merge))
+            .current_dir(&push.workdir);
+        match watchdog::run_command(&push.named_path, "git push", &mut cmd)? {
+            Some(output) if output.status.success() => say!("[{}] Pushed {} to {}", push.named_path, push.branch, push.remote),
+            Some(output) => return Err(anyhow!("[{}] Pushing {} failed: {}", push.named_path, push.branch, String::from_utf8_lossy(&output.stderr).trim())),
+            None => warn!("[{}] Skipped pushing {}", push.named_path, push.branch),
+        }
+    }
+    Ok(())
+}
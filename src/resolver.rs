@@ -0,0 +1,274 @@
+use git2::{Repository, Commit, Submodule, Oid, ObjectType};
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::{Config, cache, submodule_at_tree};
+
+// Decides which commit each submodule should be rebased onto, given the superproject commit we're
+// rebasing onto
+pub trait TargetResolver {
+    fn resolve(&self, repo: &Repository, submodule: &Submodule, target: &Commit) -> Result<Option<Oid>>;
+
+    // Whether this could give a submodule some target other than its gitlink, worked out without
+    // opening it
+    fn overrides_gitlink(&self, _repo: &Repository, _submodule: &Submodule, _target: &Commit) -> Result<bool> {
+        Ok(false)
+    }
+
+    // Whether to leave a submodule out altogether, worked out without opening it
+    fn skip(&self, _repo: &Repository, _submodule: &Submodule, _target: &Commit) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+// The normal way: whatever gitlink is in the target's tree
+pub struct GitlinkResolver;
+
+impl TargetResolver for GitlinkResolver {
+    fn resolve(&self, _repo: &Repository, submodule: &Submodule, target: &Commit) -> Result<Option<Oid>> {
+        submodule_at_tree(submodule, &target.tree()?)
+    }
+}
+
+// For repos that pin their dependencies in a lockfile, one "<path> <sha>" per line (= or : work too).
+// Repos that don't have the lockfile in the target, or don't list a submodule in it, fall back to gitlinks.
+pub struct LockfileResolver {
+    pub lockfile: PathBuf,
+    // Parsed lockfiles by repo and target, so each is only read once however many submodules ask
+    parsed: Mutex<HashMap<(PathBuf, Oid), Option<Pins>>>,
+}
+
+// (path, sha) pairs from a lockfile
+type Pins = Vec<(String, String)>;
+
+impl LockfileResolver {
+    pub fn new(lockfile: PathBuf) -> LockfileResolver {
+        LockfileResolver { lockfile, parsed: Mutex::new(HashMap::new()) }
+    }
+
+    fn read(&self, repo: &Repository, target: &Commit) -> Result<Option<Pins>> {
+        let key = (repo.path().to_path_buf(), target.id());
+        if let Some(pins) = self.parsed.lock().expect("Lockfile lock poisoned").get(&key) {
+            return Ok(pins.clone());
+        }
+        let pins = match target.tree()?.get_path(&self.lockfile) {
+            Ok(entry) => {
+                let blob = entry.to_object(repo)?.peel_to_blob()?;
+                Some(parse_lockfile(&self.lockfile, std::str::from_utf8(blob.content())?)?)
+            }
+            Err(_) => None,
+        };
+        self.parsed.lock().expect("Lockfile lock poisoned").insert(key, pins.clone());
+        Ok(pins)
+    }
+}
+
+// A lockfile's pins, skipping blank lines and # comments
+fn parse_lockfile(lockfile: &Path, contents: &str) -> Result<Pins> {
+    let is_separator = |c: char| c.is_whitespace() || c == '=' || c == ':';
+    let mut pins = vec![];
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // "libs/foo = abc123" has more than one separator between the path and the sha
+        let sha = line.find(is_separator).map(|end| (&line[..end], line[end..].trim_start_matches(is_separator)));
+        match sha {
+            Some((path, sha)) if !sha.is_empty() => pins.push((path.trim_end_matches('/').to_string(), sha.to_string())),
+            _ => return Err(anyhow!("Bad line in {}: {}", lockfile.display(), line)),
+        }
+    }
+    Ok(pins)
+}
+
+impl TargetResolver for LockfileResolver {
+    fn resolve(&self, repo: &Repository, submodule: &Submodule, target: &Commit) -> Result<Option<Oid>> {
+        if let Some(pins) = self.read(repo, target)? {
+            let sub_path = submodule.path().to_str().expect("Submodule should have path");
+            if let Some((_path, sha)) = pins.iter().find(|(path, _sha)| path == sub_path) {
+                // Could be abbreviated, so look it up in the submodule to get the full id
+                let sub_repo = submodule.open()?;
+                let id = sub_repo.revparse_single(sha)?.peel(ObjectType::Commit)?.id();
+                return Ok(Some(id));
+            }
+        }
+        GitlinkResolver.resolve(repo, submodule, target)
+    }
+
+    fn overrides_gitlink(&self, repo: &Repository, submodule: &Submodule, target: &Commit) -> Result<bool> {
+        let sub_path = submodule.path().to_str().expect("Submodule should have path");
+        let sha = match self.read(repo, target)?.and_then(|pins| pins.into_iter().find(|(path, _sha)| path == sub_path)) {
+            Some((_path, sha)) => sha,
+            None => return Ok(false),
+        };
+        // Pins can be abbreviated, which is as far as they can be checked without the submodule
+        Ok(submodule_at_tree(submodule, &target.tree()?)?.is_none_or(|gitlink| !gitlink.to_string().starts_with(&sha.to_ascii_lowercase())))
+    }
+}
+
+// Targets given up front with --stdin-refs, by the submodule's absolute working copy path. Anything
+// not listed falls back to the next resolver
+pub struct PinnedResolver {
+    pub pins: Vec<(PathBuf, String)>,
+    pub fallback: Box<dyn TargetResolver>,
+}
+
+impl TargetResolver for PinnedResolver {
+    fn resolve(&self, repo: &Repository, submodule: &Submodule, target: &Commit) -> Result<Option<Oid>> {
+        let workdir = repo.workdir().expect("Has workdir").join(submodule.path());
+        if let Some((_path, ref_)) = self.pins.iter().find(|(path, _ref)| *path == workdir) {
+            let sub_repo = submodule.open()?;
+            let id = sub_repo.revparse_single(ref_)
+                .map_err(|e| anyhow!("Cannot find {} in {}: {}", ref_, submodule.path().display(), e))?
+                .peel(ObjectType::Commit)?.id();
+            return Ok(Some(id));
+        }
+        self.fallback.resolve(repo, submodule, target)
+    }
+
+    fn overrides_gitlink(&self, repo: &Repository, submodule: &Submodule, target: &Commit) -> Result<bool> {
+        let workdir = repo.workdir().expect("Has workdir").join(submodule.path());
+        // A ref can't be told apart from the gitlink without the submodule, so any pin counts
+        if self.pins.iter().any(|(path, _ref)| *path == workdir) {
+            return Ok(true);
+        }
+        self.fallback.overrides_gitlink(repo, submodule, target)
+    }
+}
+
+// Submodules whose gitlink is the same at the merge base, the target and every commit in between have
+// nothing to rebase. That's decided from the superproject's trees alone, so they're left out before
+// they're opened, and never get cloned, checked out or prompted about. Decided once per submodule and
+// target, the first time it's asked, so every pass over the tree leaves out the same ones even after
+// HEADs start moving
+pub struct SkipUnchanged {
+    pub inner: Box<dyn TargetResolver>,
+}
+
+// Skip decisions by (repo, submodule path, target)
+type Decisions = HashMap<(PathBuf, PathBuf, Oid), bool>;
+
+static UNCHANGED: Mutex<Option<Decisions>> = Mutex::new(None);
+
+fn unchanged_in_range(repo: &Repository, submodule: &Submodule, target: &Commit) -> Result<bool> {
+    let gitlink = match submodule_at_tree(submodule, &target.tree()?)? {
+        Some(gitlink) => gitlink,
+        None => return Ok(false),
+    };
+    let head = repo.head()?.peel_to_commit()?.id();
+    let base = cache::merge_base(repo, head, target.id())?;
+    if submodule_at_tree(submodule, &repo.find_commit(base)?.tree()?)? != Some(gitlink) {
+        return Ok(false);
+    }
+    let mut walk = repo.revwalk()?;
+    walk.push(head)?;
+    walk.hide(base)?;
+    for id in walk {
+        if submodule_at_tree(submodule, &repo.find_commit(id?)?.tree()?)? != Some(gitlink) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Whether a submodule was left out for not changing, so its parent keeps its gitlink as it is
+pub fn skipped_unchanged(repo: &Repository, submodule: &Submodule, target: Oid) -> bool {
+    let key = (repo.path().to_path_buf(), submodule.path().to_path_buf(), target);
+    UNCHANGED.lock().expect("Unchanged lock poisoned").as_ref().and_then(|unchanged| unchanged.get(&key).copied()).unwrap_or(false)
+}
+
+impl TargetResolver for SkipUnchanged {
+    fn resolve(&self, repo: &Repository, submodule: &Submodule, target: &Commit) -> Result<Option<Oid>> {
+        if self.skip(repo, submodule, target)? {
+            return Ok(None);
+        }
+        self.inner.resolve(repo, submodule, target)
+    }
+
+    fn overrides_gitlink(&self, repo: &Repository, submodule: &Submodule, target: &Commit) -> Result<bool> {
+        self.inner.overrides_gitlink(repo, submodule, target)
+    }
+
+    fn skip(&self, repo: &Repository, submodule: &Submodule, target: &Commit) -> Result<bool> {
+        let key = (repo.path().to_path_buf(), submodule.path().to_path_buf(), target.id());
+        if let Some(unchanged) = UNCHANGED.lock().expect("Unchanged lock poisoned").as_ref().and_then(|unchanged| unchanged.get(&key).copied()) {
+            return Ok(unchanged);
+        }
+        // Anything that can't be worked out here gets rebased as usual, where it'll fail properly
+        let unchanged = !self.inner.overrides_gitlink(repo, submodule, target).unwrap_or(true)
+            && unchanged_in_range(repo, submodule, target).unwrap_or(false);
+        if unchanged {
+            say!("Submodule {} doesn't change between the merge base and {}, leaving it alone", submodule.path().display(), target.id());
+        }
+        UNCHANGED.lock().expect("Unchanged lock poisoned").get_or_insert_with(HashMap::new).insert(key, unchanged);
+        Ok(unchanged)
+    }
+}
+
+pub fn for_config(config: &Config) -> Box<dyn TargetResolver> {
+    let resolver: Box<dyn TargetResolver> = match &config.lockfile {
+        Some(lockfile) => Box::new(LockfileResolver::new(lockfile.clone())),
+        None => Box::new(GitlinkResolver),
+    };
+    let resolver = if config.stdin_pins.is_empty() {
+        resolver
+    } else {
+        Box::new(PinnedResolver { pins: config.stdin_pins.clone(), fallback: resolver })
+    };
+    Box::new(SkipUnchanged { inner: resolver })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockfile_separators() {
+        let pins = parse_lockfile(Path::new("deps.lock"), "# pins\nlibs/foo abc123\nlibs/bar = def456\nlibs/baz/: 0123ab\n\nlibs/qux=789abc\n").unwrap();
+        assert_eq!(pins, vec![
+            ("libs/foo".to_string(), "abc123".to_string()),
+            ("libs/bar".to_string(), "def456".to_string()),
+            ("libs/baz".to_string(), "0123ab".to_string()),
+            ("libs/qux".to_string(), "789abc".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn lockfile_read_once_per_target() {
+        let dir = std::env::temp_dir().join(format!("sub-rebase-lockfile-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        let commit_with = |contents: Option<&str>| {
+            let mut tree = repo.treebuilder(None).unwrap();
+            if let Some(contents) = contents {
+                tree.insert("deps.lock", repo.blob(contents.as_bytes()).unwrap(), 0o100644).unwrap();
+            }
+            let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+            repo.commit(None, &signature, &signature, "lockfile", &tree, &[]).unwrap()
+        };
+        let pinned = repo.find_commit(commit_with(Some("libs/foo abc123\n"))).unwrap();
+        let unpinned = repo.find_commit(commit_with(None)).unwrap();
+        let broken = repo.find_commit(commit_with(Some("libs/foo\n"))).unwrap();
+
+        let resolver = LockfileResolver::new(PathBuf::from("deps.lock"));
+        assert_eq!(resolver.read(&repo, &pinned).unwrap(), Some(vec![("libs/foo".to_string(), "abc123".to_string())]));
+        assert_eq!(resolver.read(&repo, &unpinned).unwrap(), None);
+        assert!(resolver.read(&repo, &broken).is_err());
+        assert_eq!(resolver.parsed.lock().unwrap().len(), 2);
+
+        // Later reads come from what was parsed, not the tree
+        resolver.parsed.lock().unwrap().insert((repo.path().to_path_buf(), pinned.id()), Some(vec![]));
+        assert_eq!(resolver.read(&repo, &pinned).unwrap(), Some(vec![]));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn lockfile_missing_sha() {
+        assert!(parse_lockfile(Path::new("deps.lock"), "libs/foo\n").is_err());
+        assert!(parse_lockfile(Path::new("deps.lock"), "libs/foo =\n").is_err());
+    }
+}
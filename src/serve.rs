@@ -0,0 +1,396 @@
+use anyhow::{Result, anyhow};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio, ChildStdin};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use crate::commit_map::json_string;
+use crate::output;
+
+// --serve: JSON-RPC 2.0 over stdio, one message per line, for editor plugins. Each rebase runs as a
+// --porcelain child process, whose events become notifications and whose prompts become requests
+// for the client to answer. See `help serve`
+
+// Just enough JSON to read requests
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // Ids go back to the client as they came
+    fn to_json(&self) -> String {
+        match self {
+            Value::Null => "null".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => n.to_string(),
+            Value::String(s) => json_string(s),
+            Value::Array(items) => format!("[{}]", items.iter().map(|v| v.to_json()).collect::<Vec<_>>().join(",")),
+            Value::Object(fields) => format!("{{{}}}", fields.iter().map(|(k, v)| format!("{}:{}", json_string(k), v.to_json())).collect::<Vec<_>>().join(",")),
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_space(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_space();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            other => Err(anyhow!("Expected {} in JSON, got {:?}", expected, other)),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value> {
+        for expected in word.chars() {
+            if self.chars.next() != Some(expected) {
+                return Err(anyhow!("Bad JSON literal, expected {}", word));
+            }
+        }
+        Ok(value)
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let hex = (0..4).filter_map(|_| self.chars.next()).collect::<String>();
+        Ok(u32::from_str_radix(&hex, 16)?)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.chars.next().ok_or_else(|| anyhow!("Unterminated JSON string"))? {
+                '"' => return Ok(out),
+                '\\' => match self.chars.next().ok_or_else(|| anyhow!("Unterminated JSON string"))? {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'u' => {
+                        let mut code = self.hex4()?;
+                        // Outside the BMP, as a surrogate pair: \uD83D\uDE00
+                        if (0xd800..0xdc00).contains(&code) {
+                            let mut ahead = self.chars.clone();
+                            if ahead.next() == Some('\\') && ahead.next() == Some('u') {
+                                self.chars = ahead;
+                                let low = self.hex4()?;
+                                code = if (0xdc00..0xe000).contains(&low) {
+                                    0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00)
+                                } else {
+                                    out.push('\u{fffd}');
+                                    low
+                                };
+                            }
+                        }
+                        out.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    c => out.push(c),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.skip_space();
+        match self.chars.peek().copied() {
+            Some('{') => {
+                self.chars.next();
+                let mut fields = vec![];
+                self.skip_space();
+                if self.chars.peek() == Some(&'}') {
+                    self.chars.next();
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    self.skip_space();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_space();
+                    match self.chars.next() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Value::Object(fields)),
+                        other => return Err(anyhow!("Expected , or }} in JSON object, got {:?}", other)),
+                    }
+                }
+            }
+            Some('[') => {
+                self.chars.next();
+                let mut items = vec![];
+                self.skip_space();
+                if self.chars.peek() == Some(&']') {
+                    self.chars.next();
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_space();
+                    match self.chars.next() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Value::Array(items)),
+                        other => return Err(anyhow!("Expected , or ] in JSON array, got {:?}", other)),
+                    }
+                }
+            }
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('t') => self.literal("true", Value::Bool(true)),
+            Some('f') => self.literal("false", Value::Bool(false)),
+            Some('n') => self.literal("null", Value::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(c) = self.chars.peek().copied().filter(|c| "+-.eE".contains(*c) || c.is_ascii_digit()) {
+                    number.push(c);
+                    self.chars.next();
+                }
+                Ok(Value::Number(number.parse()?))
+            }
+            other => Err(anyhow!("Unexpected {:?} in JSON", other)),
+        }
+    }
+}
+
+fn parse(text: &str) -> Result<Value> {
+    let mut parser = Parser { chars: text.chars().peekable() };
+    let value = parser.value()?;
+    parser.skip_space();
+    match parser.chars.next() {
+        None => Ok(value),
+        Some(c) => Err(anyhow!("Unexpected {:?} after JSON", c)),
+    }
+}
+
+// What the main loop hears about, from the client and the running rebase
+enum Input {
+    Client(String),
+    ClientClosed,
+    Event(String),
+    Message(String),
+    Exited(i32),
+}
+
+fn send(message: String) {
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let _ = writeln!(stdout, "{}", message);
+    let _ = stdout.flush();
+}
+
+fn notify(method: &str, params: String) {
+    send(format!("{{\"jsonrpc\":\"2.0\",\"method\":{},\"params\":{}}}", json_string(method), params));
+}
+
+fn respond(id: &Value, result: String) {
+    send(format!("{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}", id.to_json(), result));
+}
+
+fn respond_error(id: &Value, code: i32, message: &str) {
+    send(format!("{{\"jsonrpc\":\"2.0\",\"id\":{},\"error\":{{\"code\":{},\"message\":{}}}}}", id.to_json(), code, json_string(message)));
+}
+
+// A rebase in progress, as a child process
+struct Running {
+    id: Value,
+    // Dropped once the client goes away, so prompts see EOF
+    stdin: Option<ChildStdin>,
+    // Id of the prompt request the client hasn't answered yet
+    prompt: Option<u64>,
+}
+
+fn start(params: Option<&Value>, inputs: &Sender<Input>) -> Result<ChildStdin> {
+    let args = match params.and_then(|params| params.get("args")) {
+        Some(Value::Array(args)) => args.iter().map(|arg| arg.as_str().map(|s| s.to_string()).ok_or_else(|| anyhow!("args must be strings"))).collect::<Result<Vec<_>>>()?,
+        None => vec![],
+        Some(_) => return Err(anyhow!("args must be an array of strings")),
+    };
+    let mut cmd = Command::new(std::env::current_exe()?);
+    cmd.arg("--porcelain")
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(cwd) = params.and_then(|params| params.get("cwd")).and_then(|cwd| cwd.as_str()) {
+        cmd.current_dir(cwd);
+    }
+    let mut child = cmd.spawn()?;
+    let stdin = child.stdin.take().expect("Piped stdin");
+    let stdout = child.stdout.take().expect("Piped stdout");
+    let stderr = child.stderr.take().expect("Piped stderr");
+
+    let events = inputs.clone();
+    let event_reader = thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(|line| line.ok()) {
+            let _ = events.send(Input::Event(line));
+        }
+    });
+    let messages = inputs.clone();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(|line| line.ok()) {
+            let _ = messages.send(Input::Message(line));
+        }
+    });
+    let exits = inputs.clone();
+    thread::spawn(move || {
+        let code = child.wait().ok().and_then(|status| status.code()).unwrap_or(1);
+        // Every event before the exit
+        let _ = event_reader.join();
+        let _ = exits.send(Input::Exited(code));
+    });
+    Ok(stdin)
+}
+
+pub fn run() -> Result<()> {
+    let (inputs, received) = channel();
+    let client = inputs.clone();
+    thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines().map_while(|line| line.ok()) {
+            if !line.trim().is_empty() {
+                let _ = client.send(Input::Client(line));
+            }
+        }
+        let _ = client.send(Input::ClientClosed);
+    });
+
+    let mut running: Option<Running> = None;
+    let mut next_prompt = 0;
+    let mut closed = false;
+    for input in received {
+        match input {
+            Input::Event(line) => {
+                let fields = output::split_event(&line);
+                let fields = fields.iter().map(|field| field.as_str()).collect::<Vec<_>>();
+                let params = format!("{{\"name\":{},\"fields\":[{}]}}", json_string(fields[0]), fields[1..].iter().map(|f| json_string(f)).collect::<Vec<_>>().join(","));
+                notify("event", params);
+                if let (Some(running), "prompt") = (running.as_mut(), fields[0]) {
+                    next_prompt += 1;
+                    running.prompt = Some(next_prompt);
+                    let path = fields.get(2).copied().unwrap_or_default();
+                    send(format!("{{\"jsonrpc\":\"2.0\",\"id\":{},\"method\":\"prompt\",\"params\":{{\"kind\":{},\"path\":{}}}}}",
+                        next_prompt, json_string(fields.get(1).copied().unwrap_or("")), json_string(path)));
+                }
+            }
+            Input::Message(line) => notify("message", format!("{{\"text\":{}}}", json_string(&line))),
+            Input::Exited(code) => {
+                if let Some(done) = running.take() {
+                    respond(&done.id, format!("{{\"exit_code\":{}}}", code));
+                }
+                if closed {
+                    break;
+                }
+            }
+            Input::ClientClosed => {
+                // Let a running rebase finish; it gets EOF for any prompt, same as a closed terminal
+                closed = true;
+                match running.as_mut() {
+                    Some(running) => running.stdin = None,
+                    None => break,
+                }
+            }
+            Input::Client(line) => {
+                let message = match parse(&line) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        respond_error(&Value::Null, -32700, &e.to_string());
+                        continue;
+                    }
+                };
+                let id = message.get("id").cloned().unwrap_or(Value::Null);
+                match message.get("method").and_then(|m| m.as_str()) {
+                    Some("rebase") => {
+                        if running.is_some() {
+                            respond_error(&id, -32000, "A rebase is already running");
+                            continue;
+                        }
+                        match start(message.get("params"), &inputs) {
+                            Ok(stdin) => running = Some(Running { id, stdin: Some(stdin), prompt: None }),
+                            Err(e) => respond_error(&id, -32602, &e.to_string()),
+                        }
+                    }
+                    Some("shutdown") => {
+                        respond(&id, "null".to_string());
+                        if running.is_none() {
+                            break;
+                        }
+                        closed = true;
+                    }
+                    // Notifications (no id) never get a response, not even an error
+                    Some(method) if message.get("id").is_some() => respond_error(&id, -32601, &format!("Unknown method {}", method)),
+                    Some(_) => {}
+                    // A response, to a prompt
+                    None => {
+                        let running = match running.as_mut() {
+                            Some(running) if running.prompt.is_some() && Some(&id) == running.prompt.map(|p| Value::Number(p as f64)).as_ref() => running,
+                            _ => continue,
+                        };
+                        running.prompt = None;
+                        let answer = message.get("result").and_then(|r| r.as_str()).unwrap_or("");
+                        if let Some(stdin) = running.stdin.as_mut() {
+                            // The rebase could have exited already, its exit code is on the way
+                            if writeln!(stdin, "{}", answer.lines().next().unwrap_or("")).and_then(|_| stdin.flush()).is_err() {
+                                running.stdin = None;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        let request = parse(r#"{"jsonrpc": "2.0", "id": 1, "method": "rebase", "params": {"args": ["origin/main"], "cwd": null}}"#).unwrap();
+        assert_eq!(request.get("id"), Some(&Value::Number(1.0)));
+        assert_eq!(request.get("method").and_then(|m| m.as_str()), Some("rebase"));
+        assert_eq!(request.get("params").and_then(|p| p.get("args")), Some(&Value::Array(vec![Value::String("origin/main".to_string())])));
+        assert_eq!(request.get("params").and_then(|p| p.get("cwd")), Some(&Value::Null));
+    }
+
+    #[test]
+    fn parse_escapes() {
+        assert_eq!(parse(r#""a\"b\\c\nd\u00e9""#).unwrap(), Value::String("a\"b\\c\nd\u{e9}".to_string()));
+        assert_eq!(parse(r#""\uD83D\uDE00""#).unwrap(), Value::String("\u{1f600}".to_string()));
+        assert_eq!(parse(r#""\uD83Dx""#).unwrap(), Value::String("\u{fffd}x".to_string()));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse("{\"id\": 1").is_err());
+        assert!(parse("[1, 2] 3").is_err());
+        assert!(parse("\"open").is_err());
+    }
+}
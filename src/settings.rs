@@ -0,0 +1,513 @@
+use git2::Repository;
+use anyhow::{Result, anyhow};
+use structopt::StructOpt;
+use structopt::clap::ArgMatches;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::PathBuf;
+use crate::{Config, features, output};
+
+// Settings can come from (highest priority first) the command line, SUB_REBASE_* environment
+// variables, subRebase.* in git config, and then subRebase.* in the user's config file. Anything
+// not on the command line gets injected as if it was, so Config only has to be parsed by structopt.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    // --flag, true or false
+    Flag,
+    // --option value
+    Value,
+    // --option value, repeatable
+    Multi,
+    // -v -v, given as a number outside the command line
+    Count,
+}
+
+pub struct Setting {
+    // Name of the argument in Config (structopt's kebab-cased field name)
+    pub arg: &'static str,
+    // Long flag on the command line, also used to name the git config key and environment variable
+    pub long: &'static str,
+    pub kind: Kind,
+}
+
+pub const SETTINGS: &[Setting] = &[
+    Setting { arg: "stdin-refs", long: "stdin-refs", kind: Kind::Flag },
+    Setting { arg: "stack", long: "stack", kind: Kind::Multi },
+    Setting { arg: "name", long: "name", kind: Kind::Value },
+    Setting { arg: "map-out", long: "map-out", kind: Kind::Value },
+    Setting { arg: "unresolved-head", long: "unresolved-head", kind: Kind::Value },
+    Setting { arg: "committer-date-is-author-date", long: "committer-date-is-author-date", kind: Kind::Flag },
+    Setting { arg: "preserve-committer", long: "preserve-committer", kind: Kind::Flag },
+    Setting { arg: "exec-on-conflict", long: "exec-on-conflict", kind: Kind::Value },
+    Setting { arg: "reset-author", long: "reset-author", kind: Kind::Flag },
+    Setting { arg: "ignore-date", long: "ignore-date", kind: Kind::Flag },
+    Setting { arg: "author", long: "author", kind: Kind::Value },
+    Setting { arg: "committer", long: "committer", kind: Kind::Value },
+    Setting { arg: "checkouts", long: "checkout", kind: Kind::Multi },
+    Setting { arg: "gitlink-only", long: "gitlink-only", kind: Kind::Flag },
+    Setting { arg: "no-worktree-rebase", long: "no-worktree-rebase", kind: Kind::Flag },
+    Setting { arg: "worktree-picks", long: "worktree-picks", kind: Kind::Flag },
+    Setting { arg: "strategy-options", long: "strategy-option", kind: Kind::Multi },
+    Setting { arg: "mergetool", long: "mergetool", kind: Kind::Flag },
+    Setting { arg: "trust-exit-codes", long: "trust-exit-codes", kind: Kind::Flag },
+    Setting { arg: "lockfile", long: "lockfile", kind: Kind::Value },
+    Setting { arg: "exclude-submodules", long: "exclude-submodule", kind: Kind::Multi },
+    Setting { arg: "select-submodules", long: "select-submodules", kind: Kind::Flag },
+    Setting { arg: "edit-plan", long: "edit-plan", kind: Kind::Flag },
+    Setting { arg: "conflict-hunks", long: "conflict-hunks", kind: Kind::Flag },
+    Setting { arg: "on-conflict", long: "on-conflict", kind: Kind::Value },
+    Setting { arg: "map-notes", long: "map-notes", kind: Kind::Flag },
+    Setting { arg: "replace-refs", long: "replace-refs", kind: Kind::Flag },
+    Setting { arg: "lint", long: "lint", kind: Kind::Flag },
+    Setting { arg: "lint-subject-length", long: "lint-subject-length", kind: Kind::Value },
+    Setting { arg: "lint-signoff", long: "lint-signoff", kind: Kind::Flag },
+    Setting { arg: "lint-forbid", long: "lint-forbid", kind: Kind::Multi },
+    Setting { arg: "lint-max-blob", long: "lint-max-blob", kind: Kind::Value },
+    Setting { arg: "max-blob", long: "max-blob", kind: Kind::Value },
+    Setting { arg: "backup-name", long: "backup-name", kind: Kind::Value },
+    Setting { arg: "backup-prefix", long: "backup-prefix", kind: Kind::Value },
+    Setting { arg: "no-backup", long: "no-backup", kind: Kind::Flag },
+    Setting { arg: "retag", long: "retag", kind: Kind::Flag },
+    Setting { arg: "update-refs", long: "update-refs", kind: Kind::Flag },
+    Setting { arg: "autostash", long: "autostash", kind: Kind::Flag },
+    Setting { arg: "autosquash", long: "autosquash", kind: Kind::Flag },
+    Setting { arg: "missing-commits-check", long: "missing-commits-check", kind: Kind::Value },
+    Setting { arg: "checkout-workers", long: "checkout-workers", kind: Kind::Value },
+    Setting { arg: "stall-timeout", long: "stall-timeout", kind: Kind::Value },
+    Setting { arg: "fetch", long: "fetch", kind: Kind::Flag },
+    Setting { arg: "fetch-all", long: "fetch-all", kind: Kind::Flag },
+    Setting { arg: "jobs", long: "jobs", kind: Kind::Value },
+    Setting { arg: "push", long: "push", kind: Kind::Flag },
+    Setting { arg: "deepen", long: "deepen", kind: Kind::Flag },
+    Setting { arg: "keep-worktree", long: "keep-worktree", kind: Kind::Flag },
+    Setting { arg: "porcelain", long: "porcelain", kind: Kind::Flag },
+    Setting { arg: "plain", long: "plain", kind: Kind::Flag },
+    Setting { arg: "verbose", long: "verbose", kind: Kind::Count },
+    Setting { arg: "quiet", long: "quiet", kind: Kind::Flag },
+    Setting { arg: "yes", long: "yes", kind: Kind::Flag },
+    Setting { arg: "answers", long: "answers", kind: Kind::Value },
+    Setting { arg: "color", long: "color", kind: Kind::Value },
+    Setting { arg: "lang", long: "lang", kind: Kind::Value },
+    Setting { arg: "pre-rebase-reject", long: "pre-rebase-reject", kind: Kind::Value },
+    Setting { arg: "verify", long: "verify", kind: Kind::Flag },
+    Setting { arg: "no-verify", long: "no-verify", kind: Kind::Flag },
+    Setting { arg: "no-update-refs", long: "no-update-refs", kind: Kind::Flag },
+    Setting { arg: "no-autostash", long: "no-autostash", kind: Kind::Flag },
+    Setting { arg: "no-autosquash", long: "no-autosquash", kind: Kind::Flag },
+    Setting { arg: "bell", long: "bell", kind: Kind::Flag },
+    Setting { arg: "notify-command", long: "notify-command", kind: Kind::Value },
+];
+
+impl Setting {
+    // subRebase.fooBar for --foo-bar
+    pub fn git_key(&self) -> String {
+        let mut key = String::from("subRebase.");
+        let mut upper = false;
+        for c in self.long.chars() {
+            if c == '-' {
+                upper = true;
+            } else if upper {
+                key.push(c.to_ascii_uppercase());
+                upper = false;
+            } else {
+                key.push(c);
+            }
+        }
+        key
+    }
+
+    // SUB_REBASE_FOO_BAR for --foo-bar
+    pub fn env_var(&self) -> String {
+        format!("SUB_REBASE_{}", self.long.replace('-', "_").to_ascii_uppercase())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    CommandLine,
+    Environment(String),
+    GitConfig(String),
+    ConfigFile(PathBuf),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::CommandLine => write!(f, "command line"),
+            Source::Environment(var) => write!(f, "environment {}", var),
+            Source::GitConfig(key) => write!(f, "git config {}", key),
+            Source::ConfigFile(path) => write!(f, "config file {}", path.display()),
+        }
+    }
+}
+
+pub struct Resolved {
+    pub setting: &'static Setting,
+    pub values: Vec<String>,
+    pub source: Source,
+}
+
+// User-wide settings, in git config format
+pub fn config_file_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let mut home = PathBuf::from(std::env::var_os("HOME")?);
+            home.push(".config");
+            home
+        }
+    };
+    Some(base.join("git-sub-rebase").join("config"))
+}
+
+fn parse_bool(value: &str) -> bool {
+    matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on")
+}
+
+fn from_git_config(config: &git2::Config, setting: &Setting) -> Result<Option<Vec<String>>> {
+    let key = setting.git_key();
+    match setting.kind {
+        Kind::Flag => match config.get_bool(&key) {
+            Ok(value) => Ok(Some(vec![value.to_string()])),
+            Err(_) => Ok(None),
+        },
+        Kind::Value => match config.get_string(&key) {
+            Ok(value) => Ok(Some(vec![value])),
+            Err(_) => Ok(None),
+        },
+        Kind::Count => match config.get_i64(&key) {
+            Ok(value) => Ok(Some(vec![value.to_string()])),
+            Err(_) => Ok(None),
+        },
+        Kind::Multi => {
+            let mut values = vec![];
+            if let Ok(entries) = config.multivar(&key, None) {
+                for entry in &entries {
+                    if let Some(value) = entry?.value() {
+                        values.push(value.to_string());
+                    }
+                }
+            }
+            Ok(if values.is_empty() { None } else { Some(values) })
+        }
+    }
+}
+
+fn from_env(setting: &Setting) -> Option<Vec<String>> {
+    let value = std::env::var(setting.env_var()).ok()?;
+    match setting.kind {
+        Kind::Flag => Some(vec![parse_bool(&value).to_string()]),
+        Kind::Value | Kind::Count => Some(vec![value]),
+        Kind::Multi => Some(value.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect()),
+    }
+}
+
+fn from_matches(matches: &ArgMatches, setting: &Setting) -> Option<Vec<String>> {
+    if matches.occurrences_of(setting.arg) == 0 {
+        return None;
+    }
+    match setting.kind {
+        Kind::Flag => Some(vec!["true".to_string()]),
+        Kind::Count => Some(vec![matches.occurrences_of(setting.arg).to_string()]),
+        _ => Some(matches.values_of(setting.arg)?.map(|v| v.to_string()).collect()),
+    }
+}
+
+// Per-submodule settings, [subRebase "libs/foo"] exclude = true in git config. Gives the paths of
+// submodules to exclude and the key that said so
+pub fn submodule_excludes(repo: Option<&Repository>) -> Result<Vec<(String, String)>> {
+    let config = match repo {
+        Some(repo) => repo.config()?,
+        None => return Ok(vec![]),
+    };
+    let mut excludes = vec![];
+    let entries = config.entries(Some("^subrebase\\..*\\.exclude$"))?;
+    for entry in &entries {
+        let entry = entry?;
+        let (name, value) = match (entry.name(), entry.value()) {
+            (Some(name), Some(value)) => (name.to_string(), value),
+            _ => continue,
+        };
+        // Variable names come back lowercased, subsections as written
+        let path = &name["subrebase.".len()..name.len() - ".exclude".len()];
+        if parse_bool(value) && !excludes.iter().any(|(p, _)| p == path) {
+            excludes.push((path.to_string(), name.clone()));
+        }
+    }
+    Ok(excludes)
+}
+
+// Find where each setting comes from, checking each layer in priority order
+pub fn resolve(repo: Option<&Repository>, matches: &ArgMatches) -> Result<Vec<Resolved>> {
+    let git_config = match repo {
+        Some(repo) => Some(repo.config()?),
+        None => git2::Config::open_default().ok(),
+    };
+    let file_path = config_file_path().filter(|path| path.exists());
+    let file_config = match &file_path {
+        Some(path) => Some(git2::Config::open(path)?),
+        None => None,
+    };
+
+    let mut resolved = vec![];
+    for setting in SETTINGS {
+        let (values, source) = if let Some(values) = from_matches(matches, setting) {
+            (values, Source::CommandLine)
+        } else if let Some(values) = from_env(setting) {
+            (values, Source::Environment(setting.env_var()))
+        } else if let Some(values) = match &git_config { Some(config) => from_git_config(config, setting)?, None => None } {
+            (values, Source::GitConfig(setting.git_key()))
+        } else if let Some(values) = match &file_config { Some(config) => from_git_config(config, setting)?, None => None } {
+            (values, Source::ConfigFile(file_path.clone().expect("Config file has path")))
+        } else {
+            (matches.values_of(setting.arg).map(|v| v.map(|v| v.to_string()).collect::<Vec<_>>()).unwrap_or_default(), Source::Default)
+        };
+        resolved.push(Resolved { setting, values, source });
+    }
+    Ok(resolved)
+}
+
+// Parse the command line, filling in anything it doesn't say from the other layers
+pub fn load(repo: Option<&Repository>) -> Result<Config> {
+    let args = std::env::args_os().collect::<Vec<_>>();
+    let matches = Config::clap().get_matches_from(&args);
+
+    let mut injected: Vec<OsString> = vec![];
+    for resolved in resolve(repo, &matches)? {
+        match (&resolved.source, resolved.setting.kind) {
+            (Source::Default, _) | (Source::CommandLine, _) => {}
+            (_, Kind::Flag) => {
+                if resolved.values.iter().any(|v| parse_bool(v)) {
+                    injected.push(format!("--{}", resolved.setting.long).into());
+                }
+            }
+            (_, Kind::Count) => {
+                let count = resolved.values.iter().filter_map(|v| v.trim().parse::<usize>().ok()).max().unwrap_or(0);
+                for _ in 0..count {
+                    injected.push(format!("--{}", resolved.setting.long).into());
+                }
+            }
+            (_, _) => {
+                for value in &resolved.values {
+                    injected.push(format!("--{}", resolved.setting.long).into());
+                    injected.push(value.into());
+                }
+            }
+        }
+    }
+
+    for (path, _key) in submodule_excludes(repo)? {
+        injected.push("--exclude-submodule".into());
+        injected.push(path.into());
+    }
+
+    // Before everything else, so they can't end up as arguments to a subcommand
+    let mut full_args = vec![args[0].clone()];
+    full_args.extend(injected);
+    full_args.extend(args.into_iter().skip(1));
+    Ok(Config::from_clap(&Config::clap().get_matches_from(full_args)))
+}
+
+#[derive(StructOpt)]
+pub struct ConfigCommand {
+    /// Print every setting with the value a run would use and where it came from
+    #[structopt(long)]
+    show_effective: bool,
+    #[structopt(subcommand)]
+    action: Option<ConfigAction>,
+}
+
+#[derive(StructOpt)]
+enum ConfigAction {
+    /// Save a setting in the repo's git config, e.g. `set on-conflict abort` or `set exclude-submodule libs/foo libs/bar`
+    Set {
+        /// Save it in the user config file instead, for every repo
+        #[structopt(long)]
+        user: bool,
+        /// Add to a repeatable setting instead of replacing what it has
+        #[structopt(long)]
+        add: bool,
+        setting: String,
+        values: Vec<String>,
+    },
+    /// Print a setting's value as a run would see it, one line per value
+    Get {
+        setting: String,
+    },
+    /// Remove a setting from the repo's git config
+    Unset {
+        /// Remove it from the user config file instead
+        #[structopt(long)]
+        user: bool,
+        setting: String,
+    },
+    /// List the settings saved in git config or the user config file
+    List,
+}
+
+// By option name (on-conflict), git config key (subRebase.onConflict) or the key's variable (onConflict)
+fn find_setting(name: &str) -> Result<&'static Setting> {
+    let name = name.trim_start_matches("--");
+    SETTINGS.iter().find(|setting| {
+        let key = setting.git_key();
+        setting.long == name || key.eq_ignore_ascii_case(name) || key["subRebase.".len()..].eq_ignore_ascii_case(name)
+    }).ok_or_else(|| anyhow!("Unknown setting {}, see `git-sub-rebase help settings`", name))
+}
+
+// Values as the command line would take them, so nothing gets saved that a run would then reject
+fn check_values(setting: &Setting, values: &[String]) -> Result<()> {
+    let mut args = vec!["git-sub-rebase".to_string()];
+    match setting.kind {
+        Kind::Flag => {
+            if values.len() > 1 || !values.iter().all(|v| ["1", "0", "true", "false", "yes", "no", "on", "off"].contains(&v.trim().to_ascii_lowercase().as_str())) {
+                return Err(anyhow!("--{} is a flag, expected true or false", setting.long));
+            }
+        }
+        Kind::Count => {
+            let count = match values {
+                [value] => value.trim().parse::<usize>().map_err(|_| anyhow!("--{} is a count, expected a number", setting.long))?,
+                _ => return Err(anyhow!("--{} is a count, expected a number", setting.long)),
+            };
+            args.extend((0..count).map(|_| format!("--{}", setting.long)));
+        }
+        Kind::Value if values.len() != 1 => return Err(anyhow!("--{} takes exactly one value", setting.long)),
+        Kind::Value | Kind::Multi => {
+            if values.is_empty() {
+                return Err(anyhow!("--{} needs a value", setting.long));
+            }
+            for value in values {
+                args.push(format!("--{}", setting.long));
+                args.push(value.clone());
+            }
+        }
+    }
+    args.push("HEAD".to_string());
+    Config::clap().get_matches_from_safe(args).map_err(|e| anyhow!("{}", e.message))?;
+    Ok(())
+}
+
+// The repo's own config, or the user config file (made if it isn't there yet)
+fn writable_config(repo: &Repository, user: bool) -> Result<git2::Config> {
+    if !user {
+        return Ok(repo.config()?.open_level(git2::ConfigLevel::Local)?);
+    }
+    let path = config_file_path().ok_or_else(|| anyhow!("No user config file, HOME isn't set"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    Ok(git2::Config::open(&path)?)
+}
+
+fn set(repo: &Repository, user: bool, add: bool, name: &str, values: &[String]) -> Result<()> {
+    let setting = find_setting(name)?;
+    // A flag on its own turns it on
+    let values = match (setting.kind, values.is_empty()) {
+        (Kind::Flag, true) => vec!["true".to_string()],
+        _ => values.to_vec(),
+    };
+    check_values(setting, &values)?;
+    if add && setting.kind != Kind::Multi {
+        return Err(anyhow!("--add is only for repeatable settings, and --{} isn't one", setting.long));
+    }
+
+    let mut config = writable_config(repo, user)?;
+    let key = setting.git_key();
+    match setting.kind {
+        Kind::Flag => config.set_bool(&key, parse_bool(&values[0]))?,
+        Kind::Count => config.set_i64(&key, values[0].trim().parse()?)?,
+        Kind::Value => config.set_str(&key, &values[0])?,
+        Kind::Multi => {
+            if !add {
+                let _ = config.remove_multivar(&key, ".*");
+            }
+            // Matches no existing value, so each one is added
+            for value in &values {
+                config.set_multivar(&key, "^$", value)?;
+            }
+        }
+    }
+    say!("Set {} = {}", key, values.join(", "));
+    Ok(())
+}
+
+fn unset(repo: &Repository, user: bool, name: &str) -> Result<()> {
+    let setting = find_setting(name)?;
+    let mut config = writable_config(repo, user)?;
+    let key = setting.git_key();
+    let removed = match setting.kind {
+        Kind::Multi => config.remove_multivar(&key, ".*"),
+        _ => config.remove(&key),
+    };
+    match removed {
+        Ok(()) => say!("Unset {}", key),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => say!("{} wasn't set", key),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+fn get(repo: &Repository, name: &str) -> Result<()> {
+    let setting = find_setting(name)?;
+    let matches = Config::clap().get_matches_from(vec!["git-sub-rebase", "HEAD"]);
+    let resolved = resolve(Some(repo), &matches)?.into_iter().find(|r| std::ptr::eq(r.setting, setting)).expect("Every setting resolves");
+    if resolved.values.is_empty() {
+        return Err(anyhow!("{} is not set", setting.long));
+    }
+    // The answer itself, for scripts, so it's printed whatever the verbosity
+    for value in &resolved.values {
+        println!("{}", value);
+    }
+    Ok(())
+}
+
+// Only what's saved: git config and the user config file, not the environment
+fn list(repo: &Repository) -> Result<()> {
+    let matches = Config::clap().get_matches_from(vec!["git-sub-rebase", "HEAD"]);
+    let saved = resolve(Some(repo), &matches)?.into_iter().filter(|r| matches!(r.source, Source::GitConfig(_) | Source::ConfigFile(_))).collect::<Vec<_>>();
+    let excludes = submodule_excludes(Some(repo))?;
+    if saved.is_empty() && excludes.is_empty() {
+        say!("No settings saved, see `git-sub-rebase help settings` for what can be");
+        return Ok(());
+    }
+    let max_name_len = saved.iter().map(|r| r.setting.long.len()).chain(excludes.iter().map(|_| "exclude-submodule".len())).max().unwrap_or(0);
+    for r in &saved {
+        say!("{}:{} {} ({})", r.setting.long, output::pad(r.setting.long.len(), max_name_len), r.values.join(", "), r.source);
+    }
+    for (path, key) in excludes {
+        say!("exclude-submodule:{} {} ({})", output::pad("exclude-submodule".len(), max_name_len), path, Source::GitConfig(key));
+    }
+    Ok(())
+}
+
+fn show_effective(repo: &Repository) -> Result<()> {
+    let matches = Config::clap().get_matches();
+    let resolved = resolve(Some(repo), &matches)?;
+    let max_name_len = resolved.iter().map(|r| r.setting.long.len()).max().unwrap_or(0);
+    for r in &resolved {
+        let value = if r.values.is_empty() { "(unset)".to_string() } else { r.values.join(", ") };
+        say!("{}:{} {} ({})", r.setting.long, output::pad(r.setting.long.len(), max_name_len), value, r.source);
+    }
+    for (path, key) in submodule_excludes(Some(repo))? {
+        say!("exclude-submodule:{} {} ({})", output::pad("exclude-submodule".len(), max_name_len), path, Source::GitConfig(key));
+    }
+    say!("");
+    say!("Transports in this build: {}", features::transports());
+    Ok(())
+}
+
+pub fn run(repo: &Repository, command: &ConfigCommand) -> Result<()> {
+    match &command.action {
+        Some(ConfigAction::Set { user, add, setting, values }) => set(repo, *user, *add, setting, values),
+        Some(ConfigAction::Get { setting }) => get(repo, setting),
+        Some(ConfigAction::Unset { user, setting }) => unset(repo, *user, setting),
+        Some(ConfigAction::List) => list(repo),
+        None if command.show_effective => show_effective(repo),
+        None => {
+            say!("Nothing to do, try set, get, list or --show-effective");
+            Ok(())
+        }
+    }
+}
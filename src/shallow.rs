@@ -0,0 +1,67 @@
+use git2::{Oid, Repository};
+use anyhow::{Result, anyhow};
+use std::process::Command;
+use crate::{fetch, prompter, watchdog};
+
+// Shallow clones, the superproject or submodules. When the merge base of HEAD and the target is past the shallow boundary, libgit2
+// runs into the missing parents and the run fails with nothing saying why. So check first, and fetch
+// more history until the base is there (asking first, unless --deepen)
+
+// Commits to deepen by each time, before giving up and fetching everything
+const DEEPEN_STEPS: &[u32] = &[50, 200, 1000];
+
+fn has_base(repo: &Repository, head: Oid, target: Oid) -> bool {
+    repo.merge_base(head, target).is_ok()
+}
+
+fn fetch(named_path: &str, repo: &Repository, args: &[&str]) -> Result<()> {
+    say!("[{}] Fetching more history ({})", named_path, args.join(" "));
+    let mut cmd = Command::new("git");
+    cmd.arg("fetch").args(args).current_dir(repo.workdir().unwrap_or(repo.path()));
+    match watchdog::run_command(named_path, "git fetch", &mut cmd)? {
+        Some(output) if output.status.success() => Ok(()),
+        Some(output) => Err(anyhow!("[{}] git fetch {} failed: {}", named_path, args.join(" "), String::from_utf8_lossy(&output.stderr).trim())),
+        None => Err(anyhow!("[{}] git fetch {} was skipped", named_path, args.join(" "))),
+    }
+}
+
+// Shallow submodules often don't have the commit a gitlink points at, if it's newer than the clone.
+// Fetch just that one, before anything tries to find it. Its history gets deepened along with the
+// rest by deepen_for_base
+pub fn fetch_target(named_path: &str, repo: &Repository, id: Oid) -> Result<()> {
+    if !repo.is_shallow() || repo.find_commit(id).is_ok() {
+        return Ok(());
+    }
+    let remote = match fetch::default_remote(repo, None)? {
+        Some(remote) => remote,
+        None => return Err(anyhow!("[{}] Shallow clone without {}, and no remote to fetch it from", named_path, id)),
+    };
+    say!("[{}] Shallow clone without {}, fetching it from {}", named_path, id, remote);
+    fetch(named_path, repo, &[&remote, &id.to_string()])
+}
+
+// Deepen a shallow repo until head and target have a merge base in it
+pub fn deepen_for_base(named_path: &str, repo: &Repository, head: Oid, target: Oid, ask: bool) -> Result<()> {
+    if !repo.is_shallow() || has_base(repo, head, target) {
+        return Ok(());
+    }
+    warn!("[{}] Shallow clone, the merge base of {} and {} is further back than its history goes", named_path, head, target);
+    if ask {
+        say!("[{}] Fetch more history until it's there? [Y/n]", named_path);
+        if !prompter::confirm(&format!("deepen {}", named_path), true)? {
+            return Err(anyhow!("[{}] No merge base in the shallow history, deepen it with `git fetch --deepen=<n>` or pass --deepen", named_path));
+        }
+    }
+    for step in DEEPEN_STEPS {
+        fetch(named_path, repo, &[&format!("--deepen={}", step)])?;
+        if has_base(repo, head, target) {
+            return Ok(());
+        }
+    }
+    fetch(named_path, repo, &["--unshallow"])?;
+    if has_base(repo, head, target) {
+        Ok(())
+    } else {
+        Err(anyhow!("[{}] {} and {} have no history in common", named_path, head, target))
+    }
+}
@@ -0,0 +1,54 @@
+use git2::{Delta, DiffOptions, Repository};
+use anyhow::Result;
+use std::collections::HashSet;
+use std::process::Command;
+
+// Sparse checkouts (core.sparseCheckout), in the superproject or any submodule. libgit2 doesn't know
+// about them: files left out of the working copy look deleted, and its checkouts write every file.
+// So left out files don't count as changes, rebases recreate them as they go, and every checkout is
+// followed by `git sparse-checkout reapply` to take them out again
+
+// GIT_INDEX_ENTRY_SKIP_WORKTREE, in the extended flags
+const SKIP_WORKTREE: u16 = 1 << 14;
+
+pub fn is_sparse(repo: &Repository) -> bool {
+    repo.config().and_then(|config| config.get_bool("core.sparseCheckout")).unwrap_or(false)
+}
+
+// Index paths sparse checkout leaves out of the working copy
+pub fn skipped(repo: &Repository) -> Result<HashSet<Vec<u8>>> {
+    if !is_sparse(repo) {
+        return Ok(HashSet::new());
+    }
+    Ok(repo.index()?.iter().filter(|entry| entry.flags_extended & SKIP_WORKTREE != 0).map(|entry| entry.path).collect())
+}
+
+// Whether a delta is only a file being left out, not deleted
+pub fn is_skipped(skipped: &HashSet<Vec<u8>>, delta: &git2::DiffDelta) -> bool {
+    delta.status() == Delta::Deleted && delta.old_file().path_bytes().is_some_and(|path| skipped.contains(path))
+}
+
+// Number of files changed in the working copy, like diff stats, but not counting left out files
+pub fn changed_files(repo: &Repository, opts: Option<&mut DiffOptions>) -> Result<usize> {
+    let skipped = skipped(repo)?;
+    let diff = repo.diff_index_to_workdir(None, opts)?;
+    Ok(diff.deltas().filter(|delta| !is_skipped(&skipped, delta)).count())
+}
+
+// Take out what a checkout wrote outside the sparse patterns. Failing leaves extra files around, not
+// broken ones, so it only gets a warning
+pub fn reapply(repo: &Repository) {
+    if !is_sparse(repo) {
+        return;
+    }
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir,
+        None => return,
+    };
+    debug!("{}: git sparse-checkout reapply", repo.path().display());
+    match Command::new("git").arg("sparse-checkout").arg("reapply").current_dir(workdir).output() {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => warn!("Reapplying sparse checkout in {} failed: {}", workdir.display(), String::from_utf8_lossy(&output.stderr).trim()),
+        Err(e) => warn!("Reapplying sparse checkout in {} failed: {}", workdir.display(), e),
+    }
+}
@@ -0,0 +1,91 @@
+use git2::Repository;
+use anyhow::Result;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use crate::{dedupe, prompter, watchdog};
+
+// Submodules that weren't initialized before the run, but had to be to rebase through them (e.g. ones
+// deinitialized on purpose). Once the run is done they can go back to how they were: deinitialized,
+// and without the clone if the run made it
+
+struct TempClone {
+    // Workdir of the repo the submodule is in, to run `git submodule deinit` from
+    parent: PathBuf,
+    name: String,
+    path: String,
+    // The submodule's git dir, if it was cloned just for the run. Anything fetched into git dirs that
+    // were already there stays, since the user's own objects are in those too
+    cloned: Option<PathBuf>,
+}
+
+static TEMP_CLONES: Mutex<Vec<TempClone>> = Mutex::new(vec![]);
+
+// Call before initializing a submodule that didn't open
+pub fn record(parent: &Repository, name: &str, path: &str) {
+    let git_dir = parent.path().join("modules").join(name);
+    let cloned = if git_dir.exists() { None } else { Some(git_dir) };
+    let mut clones = TEMP_CLONES.lock().expect("Temp clones lock poisoned");
+    let parent = parent.workdir().expect("Has workdir").to_path_buf();
+    if !clones.iter().any(|clone| clone.parent == parent && clone.name == name) {
+        clones.push(TempClone { parent, name: name.to_string(), path: path.to_string(), cloned });
+    }
+}
+
+// Offer to deinit what the run initialized, then to delete the clones it made. Innermost first, since
+// they were recorded on the way down
+pub fn offer_cleanup() -> Result<()> {
+    let clones = std::mem::take(&mut *TEMP_CLONES.lock().expect("Temp clones lock poisoned"));
+    if clones.is_empty() {
+        return Ok(());
+    }
+    say!("{}", tr!("Submodules that were initialized just for this run:"));
+    for clone in &clones {
+        let note = if clone.cloned.is_some() { " (cloned)" } else { "" };
+        say!("{}{}", clone.parent.join(&clone.path).display(), note);
+    }
+    say!("{}", tr!("Deinitialize them again? [y/N]"));
+    if !prompter::confirm("deinit-temp-clones", false)? {
+        return Ok(());
+    }
+
+    let mut deinited = vec![];
+    for clone in clones.iter().rev() {
+        let mut cmd = Command::new("git");
+        cmd.arg("submodule")
+            .arg("deinit")
+            .arg("--force")
+            .arg("--")
+            .arg(&clone.path)
+            .current_dir(&clone.parent);
+        match watchdog::run_command(&clone.path, "git submodule deinit", &mut cmd)? {
+            Some(output) if output.status.success() => {
+                say!("Deinitialized {}", clone.parent.join(&clone.path).display());
+                deinited.push(clone);
+            }
+            Some(output) => warn!("Deinitializing {} failed: {}", clone.path, String::from_utf8_lossy(&output.stderr).trim()),
+            None => warn!("Skipped deinitializing {}", clone.path),
+        }
+    }
+
+    let cloned = deinited.iter().filter_map(|clone| clone.cloned.as_ref()).collect::<Vec<_>>();
+    if cloned.is_empty() {
+        return Ok(());
+    }
+    say!("{}", tr!("Also delete the {} clones made for this run, with their backups and everything fetched into them? [y/N]", cloned.len()));
+    if !prompter::confirm("prune-temp-clones", false)? {
+        return Ok(());
+    }
+    for git_dir in cloned {
+        if let Some(borrower) = dedupe::borrowed_by(git_dir) {
+            warn!("Not deleting {}, {} uses its objects", git_dir.display(), borrower.display());
+            continue;
+        }
+        match fs::remove_dir_all(git_dir) {
+            Ok(()) => say!("Deleted {}", git_dir.display()),
+            Err(e) => warn!("Deleting {} failed: {}", git_dir.display(), e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use git2::{Repository, Commit, Sort};
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::fs;
+use std::process::Command;
+use crate::{Config, cache, is_excluded, output, recurse_subs, sub_path_to_string, sub_workdir_path};
+use crate::resolver::TargetResolver;
+
+// --edit-plan: write the plan to a todo file, one group per repo, and open the sequence editor on it
+// like `git rebase -i` would. Commits are only there to look at; what can be changed is which repos
+// get rebased
+
+const TODO_HELP: &str = "\
+#
+# Each repo has a line saying what happens to it:
+# rebase <path> = rebase the repo onto its target
+# exclude <path> = leave the repo (and everything inside it) on its old commits
+#
+# Deleting a repo's line excludes it. The commits under each repo are picked as
+# listed, they can't be reordered or dropped here.
+#
+# If you remove everything, the run will be aborted.
+";
+
+// The editor git would use for `rebase -i`
+fn sequence_editor(repo: &Repository) -> String {
+    let config = repo.config().ok();
+    let from_config = |key: &str| config.as_ref().and_then(|config| config.get_string(key).ok());
+    std::env::var("GIT_SEQUENCE_EDITOR").ok()
+        .or_else(|| from_config("sequence.editor"))
+        .or_else(|| std::env::var("GIT_EDITOR").ok())
+        .or_else(|| from_config("core.editor"))
+        .or_else(|| std::env::var("VISUAL").ok())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .unwrap_or_else(|| "vi".to_string())
+}
+
+// One group for each repo, in the order they'll be rebased
+fn write_todo(config: &Config, repo: &Repository, target: &Commit, resolver: &dyn TargetResolver) -> Result<(String, Vec<Vec<String>>)> {
+    let groups = recurse_subs(repo, target, resolver, &|repo: &Repository, _submodule, target: &Commit, path, child_results: HashMap<String, Vec<(Vec<String>, String)>>| -> Result<Vec<(Vec<String>, String)>> {
+        let mut children = child_results.into_iter().collect::<Vec<_>>();
+        children.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut groups = children.into_iter().flat_map(|(_, groups)| groups.into_iter()).collect::<Vec<_>>();
+
+        let named_path = sub_path_to_string(path);
+        let mut text = String::new();
+        if is_excluded(config, path) {
+            text.push_str(&format!("# {}: excluded\nexclude {}\n", named_path, named_path));
+        } else {
+            let head = repo.head()?.peel_to_commit()?.id();
+            let base = cache::merge_base(repo, head, target.id())?;
+            let mut walk = repo.revwalk()?;
+            walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+            walk.push(head)?;
+            walk.hide(base)?;
+            let commits = walk.collect::<Result<Vec<_>, _>>()?;
+            text.push_str(&format!("# {}: {} commits from {} onto {}\nrebase {}\n", named_path, commits.len(), base, target.id(), named_path));
+            for id in commits {
+                text.push_str(&format!("#   pick {} {}\n", id, cache::summary(repo, id)?));
+            }
+        }
+        groups.push((path.clone(), text));
+        Ok(groups)
+    })?;
+
+    let text = groups.iter().map(|(_, text)| text.as_str()).collect::<Vec<_>>().join("\n");
+    Ok((format!("{}{}", text, TODO_HELP), groups.into_iter().map(|(path, _)| path).collect()))
+}
+
+// Open the plan in the sequence editor and exclude the repos taken out of it
+pub fn edit(config: &mut Config, repo: &Repository, target: &Commit, resolver: &dyn TargetResolver) -> Result<()> {
+    let (text, paths) = write_todo(config, repo, target, resolver)?;
+    let todo_path = repo.path().join("sub-rebase-todo");
+    fs::write(&todo_path, text)?;
+
+    let editor = sequence_editor(repo);
+    say!("Waiting for {} to close {}...", editor, todo_path.display());
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(&todo_path)
+        .stdout(output::child_stdout())
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("Editor {} failed: {:?}", editor, status.code()));
+    }
+    let edited = fs::read_to_string(&todo_path)?;
+    let _ = fs::remove_file(&todo_path);
+
+    let by_name = paths.iter().map(|path| (sub_path_to_string(path), path)).collect::<HashMap<_, _>>();
+    let mut actions = HashMap::new();
+    for line in edited.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let mut words = line.splitn(2, char::is_whitespace);
+        let (action, name) = match (words.next(), words.next()) {
+            (Some(action), Some(name)) => (action, name.trim()),
+            _ => return Err(anyhow!("Bad plan line, expected <action> <path>: {}", line)),
+        };
+        let path = *by_name.get(name).ok_or_else(|| anyhow!("Unknown repo in plan: {}", name))?;
+        match action {
+            "rebase" | "r" => actions.insert(path.clone(), true),
+            "exclude" | "x" => actions.insert(path.clone(), false),
+            _ => return Err(anyhow!("Unknown plan action {} (expected rebase or exclude)", action)),
+        };
+    }
+    if actions.is_empty() {
+        return Err(anyhow!("Nothing left in the plan, cancelling..."));
+    }
+    if actions.get(&vec![]) != Some(&true) {
+        return Err(anyhow!("The root repo can't be excluded, cancelling..."));
+    }
+
+    for path in paths.iter().filter(|path| !path.is_empty()) {
+        let name = sub_workdir_path(path);
+        let position = config.exclude_submodules.iter().position(|e| *e == name);
+        match (actions.get(path).cloned().unwrap_or(false), position) {
+            (true, Some(position)) => { config.exclude_submodules.remove(position); }
+            (false, None) => config.exclude_submodules.push(name),
+            _ => {}
+        }
+    }
+    for path in paths.iter().filter(|path| actions.get(*path) == Some(&true) && is_excluded(config, path)) {
+        say!("[{}] Still excluded, because a repo it's inside of is", sub_path_to_string(path));
+    }
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use anyhow::{Result, anyhow};
+use std::io::Read;
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::{Failure, INTERRUPTED, prompter};
+
+// Checkouts and submodule updates sometimes hang (NFS, fuse filesystems). Long operations run under a
+// watchdog that says which repo and operation is stuck, instead of the whole nested run going quiet.
+
+static STALL_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_stall_timeout(seconds: u64) {
+    STALL_TIMEOUT.store(seconds, Ordering::SeqCst);
+}
+
+fn stall_timeout() -> Option<Duration> {
+    match STALL_TIMEOUT.load(Ordering::SeqCst) {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    }
+}
+
+// Run something in-process, complaining while it takes too long. libgit2 calls can't be cancelled, so
+// the only way out of a stall is Ctrl-C, which exits and leaves the state branches for the next run
+// to clean up
+pub fn watch<T>(named_path: &str, operation: &str, f: impl FnOnce() -> T) -> T {
+    let timeout = match stall_timeout() {
+        Some(timeout) => timeout,
+        None => return f(),
+    };
+    INTERRUPTED.store(false, Ordering::SeqCst);
+
+    let done = Arc::new((Mutex::new(false), Condvar::new()));
+    let watcher = {
+        let done = done.clone();
+        let named_path = named_path.to_string();
+        let operation = operation.to_string();
+        thread::spawn(move || {
+            let start = Instant::now();
+            let mut next_report = timeout;
+            let (lock, condvar) = &*done;
+            let mut finished = lock.lock().expect("Watchdog lock poisoned");
+            while !*finished {
+                finished = condvar.wait_timeout(finished, Duration::from_secs(1)).expect("Watchdog lock poisoned").0;
+                if *finished || start.elapsed() < timeout {
+                    continue;
+                }
+                if INTERRUPTED.load(Ordering::SeqCst) {
+                    warn!("[{}] Giving up on {}. The next run will offer to clean up", named_path, operation);
+                    std::process::exit(Failure::Interrupted.exit_code());
+                }
+                if start.elapsed() >= next_report {
+                    warn!("{}", tr!("[{}] {} has been running for {}s and may be stuck. Press Ctrl-C to abort", named_path, operation, start.elapsed().as_secs()));
+                    next_report += timeout;
+                }
+            }
+        })
+    };
+
+    let result = f();
+    *done.0.lock().expect("Watchdog lock poisoned") = true;
+    done.1.notify_all();
+    let _ = watcher.join();
+    result
+}
+
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut contents = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut contents);
+        }
+        contents
+    })
+}
+
+// Run a command to completion and collect its output. If it stalls, offer to retry, skip or abort it.
+// Skipping gives None
+pub fn run_command(named_path: &str, operation: &str, command: &mut Command) -> Result<Option<Output>> {
+    debug!("[{}] Running {:?}", named_path, command);
+    let timeout = match stall_timeout() {
+        Some(timeout) => timeout,
+        None => return Ok(Some(command.output()?)),
+    };
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    'spawn: loop {
+        let mut child = command.spawn()?;
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+        let start = Instant::now();
+        let mut deadline = start + timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(Output {
+                    status,
+                    stdout: stdout.join().unwrap_or_default(),
+                    stderr: stderr.join().unwrap_or_default(),
+                }));
+            }
+            if Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+
+            warn!("{}", tr!("[{}] {} has been running for {}s and may be stuck", named_path, operation, start.elapsed().as_secs()));
+            warn!("{}", tr!("[{}] (r)etry, (s)kip, (a)bort, or ENTER to keep waiting", named_path));
+            let choice = prompter::answer(&format!("stalled {}", named_path))?;
+            match choice.trim().chars().next().map(|c| c.to_ascii_lowercase()) {
+                Some('r') => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    warn!("[{}] Retrying {}", named_path, operation);
+                    continue 'spawn;
+                }
+                Some('s') => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    warn!("[{}] Skipped {}", named_path, operation);
+                    return Ok(None);
+                }
+                Some('a') => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(anyhow!("[{}] Aborted stalled {}", named_path, operation));
+                }
+                _ => deadline = Instant::now() + timeout,
+            }
+        }
+    }
+}